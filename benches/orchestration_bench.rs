@@ -1,5 +1,6 @@
 use criterion::{Criterion, black_box, criterion_group, criterion_main};
-use gpdb::DB;
+use gpdb::{DB, Entry, SSTable, SSTableId, ValueEntry};
+use std::sync::Arc;
 use std::thread;
 use tempfile::TempDir;
 
@@ -58,5 +59,53 @@ pub fn orchestration_bench(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, orchestration_bench);
+/// Point lookups from several threads on one uncached SSTable, so every lookup
+/// reads its block from the file.
+pub fn concurrent_sstable_reads_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent_sstable_reads");
+    group.sample_size(50);
+
+    let tmp_dir = TempDir::new().unwrap();
+    let keys: Arc<Vec<String>> = Arc::new((0..10_000).map(|i| format!("key-{:05}", i)).collect());
+    let entries = keys.iter().map(|key| {
+        Ok(Entry {
+            key: Arc::new(key.clone()),
+            value: ValueEntry {
+                value: Some(Arc::new("x".repeat(100))),
+                is_tombstone: false,
+                seq: 0,
+            },
+        })
+    });
+    let sst: SSTable<String, String> = SSTable::write_from_iter(
+        &tmp_dir.path().join("L0-1.sst"),
+        entries,
+        SSTableId(1),
+        0,
+        None,
+    )
+    .unwrap();
+
+    for threads in [1, 4, 8] {
+        group.bench_function(format!("get_{}_threads", threads), |b| {
+            b.iter(|| {
+                thread::scope(|scope| {
+                    for t in 0..threads {
+                        let sst = &sst;
+                        let keys = &keys;
+                        scope.spawn(move || {
+                            for key in keys.iter().skip(t).step_by(threads).take(1000) {
+                                black_box(sst.get(key).unwrap());
+                            }
+                        });
+                    }
+                });
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, orchestration_bench, concurrent_sstable_reads_bench);
 criterion_main!(benches);
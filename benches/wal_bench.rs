@@ -13,7 +13,7 @@ pub fn wal_bench(c: &mut Criterion) {
 
     for size in [100, 1024, 10240].iter() {
         let val = "a".repeat(*size);
-        let entry = LogEntry::Put(Arc::new("key".to_string()), Arc::new(val));
+        let entry = LogEntry::Put(Arc::new("key".to_string()), Arc::new(val), 1);
 
         let mut count = 0;
         group.bench_with_input(BenchmarkId::new("append_no_flush", size), size, |b, _| {
@@ -22,7 +22,7 @@ pub fn wal_bench(c: &mut Criterion) {
                 if count % 1000 == 0 {
                     wal.clear().unwrap();
                 }
-                wal.append_batch(&vec![black_box(entry.clone())]).unwrap();
+                wal.append_batch(&[black_box(entry.clone())]).unwrap();
             })
         });
 
@@ -33,7 +33,7 @@ pub fn wal_bench(c: &mut Criterion) {
                 if count % 1000 == 0 {
                     wal.clear().unwrap();
                 }
-                wal.append_batch(&vec![black_box(entry.clone())]).unwrap();
+                wal.append_batch(&[black_box(entry.clone())]).unwrap();
                 wal.flush().unwrap();
             })
         });
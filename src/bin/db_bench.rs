@@ -508,7 +508,6 @@ fn run_full_benchmark(cfg: &BenchConfig) -> gpdb::Result<()> {
 
     let start = Instant::now();
     while db.compaction_backlog() > 0 {
-        db.handle_compaction_results()?;
         thread::sleep(Duration::from_millis(100));
         if start.elapsed() > Duration::from_secs(60) {
             println!("{}", "Compaction timeout reached".red());
@@ -609,9 +608,9 @@ fn main() -> gpdb::Result<()> {
         );
         for cfg in &mut configs {
             cfg.num_writes = (cfg.num_writes / 10).max(1);
-            cfg.num_overwrites = (cfg.num_overwrites / 10).max(0);
+            cfg.num_overwrites /= 10;
             cfg.num_reads = (cfg.num_reads / 10).max(1);
-            cfg.num_deletes = (cfg.num_deletes / 10).max(0);
+            cfg.num_deletes /= 10;
             cfg.threads = cfg.threads.min(2);
         }
     }
@@ -4,15 +4,67 @@ use moka::sync::Cache;
 use serde::Serialize;
 use serde::de::DeserializeOwned;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters of a `BlockCache`, from `BlockCache::stats` or `DB::cache_stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Blocks pushed out to make room for others.
+    pub evictions: u64,
+    pub entries: u64,
+    /// Decoded size of the cached blocks.
+    pub used_bytes: u64,
+    pub capacity_bytes: u64,
+}
+
+impl CacheStats {
+    /// Fraction of lookups served from the cache, or 0 before the first lookup.
+    pub fn hit_ratio(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            0.0
+        } else {
+            self.hits as f64 / lookups as f64
+        }
+    }
+}
+
+/// The cache proper, shared by every handle made with `BlockCache::share`.
+struct SharedCache<K, V>
+where
+    K: DBKey + Send + Sync + 'static,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    /// Keyed by handle namespace, table id and block offset.
+    cache: Cache<(u64, SSTableId, u64), Arc<DataBlock<K, V>>>,
+    capacity_bytes: u64,
+    /// Lookups through every handle, so the cache as a whole can be reported
+    /// on from any of them.
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: Arc<AtomicU64>,
+    next_namespace: AtomicU64,
+}
 
 /// A thread-safe block cache using Moka (W-TinyLFU).
 /// Caches de-serialized DataBlocks to skip disk I/O and CPU overhead of parsing.
+/// Blocks are weighed by their decoded size, so the capacity is in bytes however
+/// large the blocks turn out.
+///
+/// Several databases can share one cache, and so one memory budget, through
+/// handles from `share`. Each handle has its own namespace, since table ids
+/// are only unique within a database.
 pub struct BlockCache<K, V>
 where
     K: DBKey + Send + Sync + 'static,
     V: Serialize + DeserializeOwned + Send + Sync + 'static,
 {
-    cache: Cache<(SSTableId, u64), Arc<DataBlock<K, V>>>,
+    shared: Arc<SharedCache<K, V>>,
+    namespace: u64,
+    hits: AtomicU64,
+    misses: AtomicU64,
 }
 
 impl<K, V> std::fmt::Debug for BlockCache<K, V>
@@ -22,7 +74,10 @@ where
 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("BlockCache")
-            .field("entry_count", &self.cache.entry_count())
+            .field("namespace", &self.namespace)
+            .field("entry_count", &self.shared.cache.entry_count())
+            .field("weighted_size", &self.shared.cache.weighted_size())
+            .field("capacity_bytes", &self.shared.capacity_bytes)
             .finish()
     }
 }
@@ -32,19 +87,133 @@ where
     K: DBKey + Send + Sync + 'static,
     V: Serialize + DeserializeOwned + Send + Sync + 'static,
 {
+    /// A cache holding up to `capacity_bytes` of decoded blocks.
     pub fn new(capacity_bytes: u64) -> Self {
-        let max_capacity = capacity_bytes / 4096;
+        let evictions = Arc::new(AtomicU64::new(0));
+        let listener_evictions = Arc::clone(&evictions);
+        let cache = Cache::builder()
+            .max_capacity(capacity_bytes)
+            .weigher(|_key, block: &Arc<DataBlock<K, V>>| {
+                u32::try_from(block_weight(block)).unwrap_or(u32::MAX)
+            })
+            .eviction_listener(move |_key, _block, cause| {
+                if cause.was_evicted() {
+                    listener_evictions.fetch_add(1, Ordering::Relaxed);
+                }
+            })
+            .support_invalidation_closures()
+            .build();
+
+        Self::with_namespace(
+            Arc::new(SharedCache {
+                cache,
+                capacity_bytes,
+                hits: AtomicU64::new(0),
+                misses: AtomicU64::new(0),
+                evictions,
+                next_namespace: AtomicU64::new(1),
+            }),
+            0,
+        )
+    }
 
-        let cache = Cache::builder().max_capacity(max_capacity).build();
+    /// A cache holding up to `capacity_bytes` of decoded blocks. Blocks are
+    /// weighed by their decoded size now, so `block_size` is ignored.
+    #[deprecated(note = "blocks are weighed by their decoded size; use `BlockCache::new`")]
+    pub fn with_block_size(capacity_bytes: u64, _block_size: usize) -> Self {
+        Self::new(capacity_bytes)
+    }
+
+    fn with_namespace(shared: Arc<SharedCache<K, V>>, namespace: u64) -> Self {
+        Self {
+            shared,
+            namespace,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
 
-        Self { cache }
+    /// Another handle on the same cache and memory budget, with a namespace of
+    /// its own. `DB::open_with_options` takes one for every database given the
+    /// cache through `DBOptions::block_cache`.
+    pub fn share(&self) -> Self {
+        let namespace = self.shared.next_namespace.fetch_add(1, Ordering::Relaxed);
+        Self::with_namespace(Arc::clone(&self.shared), namespace)
+    }
+
+    /// The memory budget, in bytes, of the whole cache.
+    pub fn capacity_bytes(&self) -> u64 {
+        self.shared.capacity_bytes
     }
 
     pub fn get(&self, sstable_id: SSTableId, offset: u64) -> Option<Arc<DataBlock<K, V>>> {
-        self.cache.get(&(sstable_id, offset))
+        let block = self.shared.cache.get(&(self.namespace, sstable_id, offset));
+        let (counter, total) = match block {
+            Some(_) => (&self.hits, &self.shared.hits),
+            None => (&self.misses, &self.shared.misses),
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        total.fetch_add(1, Ordering::Relaxed);
+        block
     }
 
     pub fn insert(&self, sstable_id: SSTableId, offset: u64, block: Arc<DataBlock<K, V>>) {
-        self.cache.insert((sstable_id, offset), block);
+        self.shared
+            .cache
+            .insert((self.namespace, sstable_id, offset), block);
+    }
+
+    /// Current counters. Hits and misses are this handle's own; the other
+    /// fields describe the whole cache. Settles pending evictions first so that
+    /// the sizes are exact.
+    pub fn stats(&self) -> CacheStats {
+        self.stats_with(&self.hits, &self.misses)
+    }
+
+    /// Like `stats`, but with the hits and misses of every handle on the cache.
+    /// A cache handed to `DBOptions::block_cache` is only looked up through the
+    /// handles the databases take, so this is how its owner sees them.
+    pub fn total_stats(&self) -> CacheStats {
+        self.stats_with(&self.shared.hits, &self.shared.misses)
+    }
+
+    fn stats_with(&self, hits: &AtomicU64, misses: &AtomicU64) -> CacheStats {
+        let cache = &self.shared.cache;
+        cache.run_pending_tasks();
+        CacheStats {
+            hits: hits.load(Ordering::Relaxed),
+            misses: misses.load(Ordering::Relaxed),
+            evictions: self.shared.evictions.load(Ordering::Relaxed),
+            entries: cache.entry_count(),
+            used_bytes: cache.weighted_size(),
+            capacity_bytes: self.shared.capacity_bytes,
+        }
+    }
+}
+
+impl<K, V> Drop for BlockCache<K, V>
+where
+    K: DBKey + Send + Sync + 'static,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    /// Frees the budget held by this handle's blocks, which nothing can look
+    /// up any more, when the cache lives on in other handles.
+    fn drop(&mut self) {
+        if Arc::strong_count(&self.shared) > 1 {
+            let namespace = self.namespace;
+            let _ = self
+                .shared
+                .cache
+                .invalidate_entries_if(move |(entry_namespace, _, _), _| {
+                    *entry_namespace == namespace
+                });
+        }
     }
 }
+
+/// Bytes a decoded block takes up in memory.
+fn block_weight<K, V>(block: &DataBlock<K, V>) -> usize {
+    std::mem::size_of::<DataBlock<K, V>>()
+        + block.data.len()
+        + block.restart_points.len() * std::mem::size_of::<u32>()
+}
@@ -1,12 +1,43 @@
 pub mod overlap;
+pub mod strategy;
 pub mod stream;
 
 use crate::db::cache::BlockCache;
 use crate::db::compaction::stream::MergeStream;
-use crate::{DBKey, Result, SSTable, SSTableId};
+use crate::{DBKey, Entry, Error, Result, SSTable, SSTableId, TableOptions};
 use serde::{Serialize, de::DeserializeOwned};
+use std::iter::Peekable;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Number of L0 tables that triggers an L0 -> L1 compaction under `LeveledCompaction`.
+pub const L0_COMPACTION_TRIGGER: usize = 4;
+/// Compaction outputs are cut into tables of roughly this many bytes.
+pub const TARGET_FILE_SIZE: u64 = 2 * 1024 * 1024;
+/// Size budget of L1; every deeper level is `LEVEL_SIZE_MULTIPLIER` times larger.
+pub const MAX_BYTES_FOR_LEVEL_BASE: u64 = 10 * 1024 * 1024;
+pub const LEVEL_SIZE_MULTIPLIER: u64 = 10;
+
+/// Hands out SSTable ids. Shared by flushes and the compaction worker, which
+/// allocates ids as it cuts outputs.
+#[derive(Debug, Clone)]
+pub struct IdAllocator(Arc<AtomicU64>);
+
+impl IdAllocator {
+    pub fn new(next: SSTableId) -> Self {
+        Self(Arc::new(AtomicU64::new(next.0)))
+    }
+
+    pub fn allocate(&self) -> SSTableId {
+        SSTableId(self.0.fetch_add(1, Ordering::SeqCst))
+    }
+
+    /// The id the next call to `allocate` will return.
+    pub fn peek(&self) -> SSTableId {
+        SSTableId(self.0.load(Ordering::SeqCst))
+    }
+}
 
 /// A request sent to the background compaction thread.
 pub enum CompactionTask<K, V>
@@ -15,10 +46,18 @@ where
     V: Serialize + DeserializeOwned + Send + Sync + 'static,
 {
     Compact {
+        /// Source-level tables plus every overlapping table of `target_level`.
         sstables: Vec<SSTable<K, V>>,
-        output_path: PathBuf,
-        next_id: SSTableId,
+        output_dir: PathBuf,
+        ids: IdAllocator,
         target_level: usize,
+        target_file_size: u64,
+        table_options: TableOptions,
+        /// Sequence numbers of live snapshots whose versions must survive the merge.
+        snapshots: Vec<u64>,
+        /// Key ranges of tables at or below `target_level` that are not inputs.
+        /// Tombstones for keys outside all of them are garbage collected.
+        deeper_ranges: Vec<(K, K)>,
         block_cache: Option<Arc<BlockCache<K, V>>>,
     },
     Shutdown,
@@ -31,11 +70,62 @@ where
     V: Serialize + DeserializeOwned + Send + Sync + 'static,
 {
     Success {
-        sstable: SSTable<K, V>,
+        /// Non-overlapping outputs in key order; empty when every input entry was
+        /// garbage collected.
+        sstables: Vec<SSTable<K, V>>,
         level: usize,
         original_sstables: Vec<SSTable<K, V>>,
+        stats: CompactionStats,
     },
-    Failure(String),
+    /// The task is handed back so that it can be retried.
+    Failure {
+        error: Error,
+        original_sstables: Vec<SSTable<K, V>>,
+        level: usize,
+        target_file_size: u64,
+    },
+}
+
+/// What a compaction read, wrote and reclaimed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactionStats {
+    pub input_entries: u64,
+    pub output_entries: u64,
+    /// Older versions dropped because a newer one shadows them for every reader.
+    pub dropped_versions: u64,
+    /// Tombstones dropped because nothing beneath them is left to hide.
+    pub dropped_tombstones: u64,
+    pub input_bytes: u64,
+    pub output_bytes: u64,
+    /// Compaction attempts that failed and were retried.
+    pub failures: u64,
+}
+
+impl CompactionStats {
+    /// Disk space freed by the compaction.
+    pub fn reclaimed_bytes(&self) -> u64 {
+        self.input_bytes.saturating_sub(self.output_bytes)
+    }
+
+    pub fn merge(&mut self, other: &CompactionStats) {
+        self.input_entries += other.input_entries;
+        self.output_entries += other.output_entries;
+        self.dropped_versions += other.dropped_versions;
+        self.dropped_tombstones += other.dropped_tombstones;
+        self.input_bytes += other.input_bytes;
+        self.output_bytes += other.output_bytes;
+        self.failures += other.failures;
+    }
+}
+
+/// The tables produced by a compaction and what it cost.
+pub struct CompactionOutput<K, V>
+where
+    K: DBKey + Send + Sync + 'static,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    pub sstables: Vec<SSTable<K, V>>,
+    pub stats: CompactionStats,
 }
 
 pub struct Compactor;
@@ -51,9 +141,139 @@ impl Compactor {
         K: DBKey + Send + Sync + 'static,
         V: Serialize + DeserializeOwned + Send + Sync + 'static,
     {
-        let stream = MergeStream::new(sstables)?;
-        let target_level = 1;
-        SSTable::write_from_iter(output_path, stream, new_id, target_level, block_cache)
+        Self::compact_to_level(sstables, output_path, new_id, 1, &[], None, block_cache)?
+            .sstables
+            .pop()
+            .ok_or_else(|| Error::Corruption("Empty SSTable".to_string()))
+    }
+
+    /// Merges `sstables` into a single table for `target_level`, keeping the
+    /// versions still visible to `snapshots`. When `deeper_ranges` is given,
+    /// tombstones for keys outside those ranges are garbage collected; see
+    /// `MergeStream::with_tombstone_gc`.
+    pub fn compact_to_level<K, V>(
+        sstables: &[SSTable<K, V>],
+        output_path: &Path,
+        new_id: SSTableId,
+        target_level: usize,
+        snapshots: &[u64],
+        deeper_ranges: Option<Vec<(K, K)>>,
+        block_cache: Option<Arc<BlockCache<K, V>>>,
+    ) -> Result<CompactionOutput<K, V>>
+    where
+        K: DBKey + Send + Sync + 'static,
+        V: Serialize + DeserializeOwned + Send + Sync + 'static,
+    {
+        let mut output = Some((output_path.to_path_buf(), new_id));
+        Self::merge_into_tables(
+            sstables,
+            target_level,
+            u64::MAX,
+            &TableOptions::default(),
+            snapshots,
+            deeper_ranges,
+            block_cache,
+            || output.take().expect("single output requested twice"),
+        )
+    }
+
+    /// Merges `sstables` into `target_level`, cutting a new table roughly every
+    /// `target_file_size` bytes. All versions of a key land in the same table, so
+    /// the outputs never overlap each other.
+    #[allow(clippy::too_many_arguments)]
+    pub fn compact_leveled<K, V>(
+        sstables: &[SSTable<K, V>],
+        output_dir: &Path,
+        ids: &IdAllocator,
+        target_level: usize,
+        target_file_size: u64,
+        table_options: &TableOptions,
+        snapshots: &[u64],
+        deeper_ranges: Option<Vec<(K, K)>>,
+        block_cache: Option<Arc<BlockCache<K, V>>>,
+    ) -> Result<CompactionOutput<K, V>>
+    where
+        K: DBKey + Send + Sync + 'static,
+        V: Serialize + DeserializeOwned + Send + Sync + 'static,
+    {
+        Self::merge_into_tables(
+            sstables,
+            target_level,
+            target_file_size,
+            table_options,
+            snapshots,
+            deeper_ranges,
+            block_cache,
+            || {
+                let id = ids.allocate();
+                (output_dir.join(format!("L{}-{}.sst", target_level, id)), id)
+            },
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn merge_into_tables<K, V, F>(
+        sstables: &[SSTable<K, V>],
+        target_level: usize,
+        target_file_size: u64,
+        table_options: &TableOptions,
+        snapshots: &[u64],
+        deeper_ranges: Option<Vec<(K, K)>>,
+        block_cache: Option<Arc<BlockCache<K, V>>>,
+        mut next_output: F,
+    ) -> Result<CompactionOutput<K, V>>
+    where
+        K: DBKey + Send + Sync + 'static,
+        V: Serialize + DeserializeOwned + Send + Sync + 'static,
+        F: FnMut() -> (PathBuf, SSTableId),
+    {
+        let mut stream = MergeStream::new(sstables)?.with_snapshots(snapshots.to_vec());
+        if let Some(ranges) = deeper_ranges {
+            stream = stream.with_tombstone_gc(ranges);
+        }
+
+        let mut outputs: Vec<SSTable<K, V>> = Vec::new();
+        {
+            let mut entries = (&mut stream).peekable();
+            while entries.peek().is_some() {
+                let (path, id) = next_output();
+                let chunk = SizeBoundedChunk {
+                    inner: &mut entries,
+                    budget: target_file_size,
+                    written: 0,
+                    last_key: None,
+                };
+                let written = SSTable::write_from_iter_with_options(
+                    &path,
+                    chunk,
+                    id,
+                    target_level,
+                    block_cache.clone(),
+                    table_options,
+                );
+                match written {
+                    Ok(sstable) => outputs.push(sstable),
+                    Err(e) => {
+                        // Nothing references the outputs yet, so a failed merge
+                        // leaves no files behind.
+                        let _ = std::fs::remove_file(&path);
+                        for output in &outputs {
+                            let _ = std::fs::remove_file(output.path());
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+        }
+
+        let mut stats = stream.stats();
+        stats.input_bytes = sstables.iter().map(|s| s.file_size()).sum();
+        stats.output_entries = outputs.iter().map(|s| s.num_entries()).sum();
+        stats.output_bytes = outputs.iter().map(|s| s.file_size()).sum();
+        Ok(CompactionOutput {
+            sstables: outputs,
+            stats,
+        })
     }
 
     pub fn compact_l0<K, V>(
@@ -80,24 +300,46 @@ impl Compactor {
             match task {
                 CompactionTask::Compact {
                     sstables,
-                    output_path,
-                    next_id,
+                    output_dir,
+                    ids,
                     target_level,
+                    target_file_size,
+                    table_options,
+                    snapshots,
+                    deeper_ranges,
                     block_cache,
                 } => {
-                    let result = Self::compact(&sstables, &output_path, next_id, block_cache);
+                    let result = Self::compact_leveled(
+                        &sstables,
+                        &output_dir,
+                        &ids,
+                        target_level,
+                        target_file_size,
+                        &table_options,
+                        &snapshots,
+                        Some(deeper_ranges),
+                        block_cache,
+                    );
                     match result {
-                        Ok(sstable) => {
+                        Ok(output) => {
                             sender
                                 .send(CompactionResult::Success {
-                                    sstable,
+                                    sstables: output.sstables,
                                     level: target_level,
                                     original_sstables: sstables,
+                                    stats: output.stats,
                                 })
                                 .ok();
                         }
                         Err(e) => {
-                            sender.send(CompactionResult::Failure(e.to_string())).ok();
+                            sender
+                                .send(CompactionResult::Failure {
+                                    error: e,
+                                    original_sstables: sstables,
+                                    level: target_level,
+                                    target_file_size,
+                                })
+                                .ok();
                         }
                     }
                 }
@@ -106,3 +348,42 @@ impl Compactor {
         }
     }
 }
+
+/// Yields entries until roughly `budget` bytes have passed, then stops at the
+/// next key boundary so that no key is split across two output tables.
+struct SizeBoundedChunk<'a, K, V, I>
+where
+    I: Iterator<Item = Result<Entry<K, V>>>,
+{
+    inner: &'a mut Peekable<I>,
+    budget: u64,
+    written: u64,
+    last_key: Option<Arc<K>>,
+}
+
+impl<K, V, I> Iterator for SizeBoundedChunk<'_, K, V, I>
+where
+    K: DBKey,
+    V: Serialize,
+    I: Iterator<Item = Result<Entry<K, V>>>,
+{
+    type Item = Result<Entry<K, V>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.written >= self.budget
+            && let Some(Ok(next)) = self.inner.peek()
+            && self.last_key.as_ref() != Some(&next.key)
+        {
+            return None;
+        }
+
+        let item = self.inner.next()?;
+        if let Ok(entry) = &item {
+            let key_size = bincode::serialized_size(&*entry.key).unwrap_or(0);
+            let val_size = bincode::serialized_size(&entry.value).unwrap_or(0);
+            self.written += key_size + val_size;
+            self.last_key = Some(Arc::clone(&entry.key));
+        }
+        Some(item)
+    }
+}
@@ -0,0 +1,397 @@
+use crate::db::compaction::{
+    Compactor, L0_COMPACTION_TRIGGER, LEVEL_SIZE_MULTIPLIER, MAX_BYTES_FOR_LEVEL_BASE,
+    TARGET_FILE_SIZE,
+};
+use crate::{DBKey, SSTable, SSTableId};
+use serde::{Serialize, de::DeserializeOwned};
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime};
+
+/// The state a strategy chooses the next compaction from.
+pub struct CompactionContext<'a, K, V>
+where
+    K: DBKey + Send + Sync + 'static,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    /// L0 in age order (oldest first), deeper levels in key order.
+    pub levels: &'a [Vec<SSTable<K, V>>],
+    /// Tables already claimed by a running compaction.
+    pub compacting: &'a HashSet<SSTableId>,
+    /// Per level, the largest key of the last table compacted out of it.
+    pub compact_pointers: &'a mut Vec<Option<K>>,
+}
+
+impl<K, V> CompactionContext<'_, K, V>
+where
+    K: DBKey + Send + Sync + 'static,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    pub fn is_compacting(&self, sstable: &SSTable<K, V>) -> bool {
+        self.compacting.contains(&sstable.id())
+    }
+
+    /// The tables of `level` whose key range intersects any of `sstables`.
+    pub fn overlapping(&self, sstables: &[SSTable<K, V>], level: usize) -> Vec<SSTable<K, V>> {
+        let Some(target) = self.levels.get(level) else {
+            return Vec::new();
+        };
+        Compactor::find_range_overlapping_sstables(sstables, target)
+            .into_iter()
+            .map(|idx| target[idx].clone())
+            .collect()
+    }
+}
+
+/// A unit of work chosen by a `CompactionStrategy`.
+pub enum CompactionPick<K, V>
+where
+    K: DBKey + Send + Sync + 'static,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    /// Merge `inputs` into `target_level`, cutting outputs at `target_file_size`.
+    Merge {
+        inputs: Vec<SSTable<K, V>>,
+        target_level: usize,
+        target_file_size: u64,
+    },
+    /// Remove whole tables without rewriting anything.
+    Drop(Vec<SSTable<K, V>>),
+}
+
+/// Decides which tables are compacted, and into which level.
+///
+/// `pick` is called repeatedly after every flush and installed compaction until
+/// it returns `None`; tables of a returned pick are marked as compacting before
+/// the next call, so a strategy must never pick a table that `is_compacting`.
+pub trait CompactionStrategy<K, V>: Send + Sync + std::fmt::Debug
+where
+    K: DBKey + Send + Sync + 'static,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    /// A stable name, recorded so a reopen can tell which strategy laid out the files.
+    fn name(&self) -> &'static str;
+
+    fn pick(&self, ctx: &mut CompactionContext<'_, K, V>) -> Option<CompactionPick<K, V>>;
+
+    /// L0 tables waiting to be merged into a deeper level, checked against the
+    /// L0 write stall triggers. Strategies that keep their data in L0 count none.
+    fn l0_backlog(&self, _levels: &[Vec<SSTable<K, V>>]) -> usize {
+        0
+    }
+
+    /// Bytes still to be compacted before every level is within its budget,
+    /// checked against the pending compaction bytes write stall triggers.
+    fn pending_compaction_bytes(&self, _levels: &[Vec<SSTable<K, V>>]) -> u64 {
+        0
+    }
+}
+
+/// Classic leveled compaction: L0 is merged into the overlapping part of L1 once
+/// it holds `l0_trigger` tables, and a level >= 1 pushes one table at a time into
+/// the next once it outgrows its size budget. Levels >= 1 never overlap.
+#[derive(Debug, Clone)]
+pub struct LeveledCompaction {
+    pub l0_trigger: usize,
+    pub max_bytes_for_level_base: u64,
+    pub level_size_multiplier: u64,
+    pub target_file_size: u64,
+}
+
+impl Default for LeveledCompaction {
+    fn default() -> Self {
+        Self {
+            l0_trigger: L0_COMPACTION_TRIGGER,
+            max_bytes_for_level_base: MAX_BYTES_FOR_LEVEL_BASE,
+            level_size_multiplier: LEVEL_SIZE_MULTIPLIER,
+            target_file_size: TARGET_FILE_SIZE,
+        }
+    }
+}
+
+impl LeveledCompaction {
+    /// Size budget of a level >= 1 before it is compacted into the next one.
+    pub fn max_bytes_for_level(&self, level: usize) -> u64 {
+        let exponent = level.saturating_sub(1).min(u32::MAX as usize) as u32;
+        self.max_bytes_for_level_base
+            .saturating_mul(self.level_size_multiplier.saturating_pow(exponent))
+    }
+
+    /// All of L0 plus every L1 table overlapping it. L0 tables overlap each other,
+    /// so only one L0 compaction may run at a time.
+    fn pick_l0<K, V>(&self, ctx: &CompactionContext<'_, K, V>) -> Option<Vec<SSTable<K, V>>>
+    where
+        K: DBKey + Send + Sync + 'static,
+        V: Serialize + DeserializeOwned + Send + Sync + 'static,
+    {
+        let l0 = ctx.levels.first()?;
+        if l0.len() < self.l0_trigger || l0.iter().any(|s| ctx.is_compacting(s)) {
+            return None;
+        }
+        let mut inputs = l0.clone();
+        inputs.extend(ctx.overlapping(l0, 1));
+        none_compacting(ctx, inputs)
+    }
+
+    /// One table of an oversized level, chosen round-robin through the key space,
+    /// plus every overlapping table of the next level.
+    fn pick_level<K, V>(
+        &self,
+        ctx: &mut CompactionContext<'_, K, V>,
+        level: usize,
+    ) -> Option<Vec<SSTable<K, V>>>
+    where
+        K: DBKey + Send + Sync + 'static,
+        V: Serialize + DeserializeOwned + Send + Sync + 'static,
+    {
+        let tables = &ctx.levels[level];
+        let level_bytes: u64 = tables.iter().map(|s| s.file_size()).sum();
+        if level_bytes <= self.max_bytes_for_level(level) {
+            return None;
+        }
+
+        let candidates: Vec<&SSTable<K, V>> =
+            tables.iter().filter(|s| !ctx.is_compacting(s)).collect();
+        let pointer = ctx.compact_pointers.get(level).cloned().flatten();
+        let next = candidates
+            .iter()
+            .find(|s| pointer.as_ref().is_none_or(|p| s.min_key() > p))
+            .or_else(|| candidates.first())?;
+        let picked = vec![(*next).clone()];
+
+        let mut inputs = picked.clone();
+        inputs.extend(ctx.overlapping(&picked, level + 1));
+        let inputs = none_compacting(ctx, inputs)?;
+
+        if ctx.compact_pointers.len() <= level {
+            ctx.compact_pointers.resize(level + 1, None);
+        }
+        ctx.compact_pointers[level] = Some(picked[0].max_key().clone());
+        Some(inputs)
+    }
+}
+
+impl<K, V> CompactionStrategy<K, V> for LeveledCompaction
+where
+    K: DBKey + Send + Sync + 'static,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    fn name(&self) -> &'static str {
+        "leveled"
+    }
+
+    fn l0_backlog(&self, levels: &[Vec<SSTable<K, V>>]) -> usize {
+        levels.first().map_or(0, Vec::len)
+    }
+
+    /// All of L0 once it reaches `l0_trigger`, plus what each deeper level
+    /// holds beyond its budget.
+    fn pending_compaction_bytes(&self, levels: &[Vec<SSTable<K, V>>]) -> u64 {
+        let level_bytes =
+            |tables: &Vec<SSTable<K, V>>| -> u64 { tables.iter().map(|s| s.file_size()).sum() };
+        let mut pending = 0;
+        if let Some(l0) = levels.first()
+            && l0.len() >= self.l0_trigger
+        {
+            pending += level_bytes(l0);
+        }
+        for (level, tables) in levels.iter().enumerate().skip(1) {
+            pending += level_bytes(tables).saturating_sub(self.max_bytes_for_level(level));
+        }
+        pending
+    }
+
+    fn pick(&self, ctx: &mut CompactionContext<'_, K, V>) -> Option<CompactionPick<K, V>> {
+        for level in 0..ctx.levels.len() {
+            let inputs = if level == 0 {
+                self.pick_l0(ctx)
+            } else {
+                self.pick_level(ctx, level)
+            };
+            if let Some(inputs) = inputs {
+                return Some(CompactionPick::Merge {
+                    inputs,
+                    target_level: level + 1,
+                    target_file_size: self.target_file_size,
+                });
+            }
+        }
+        None
+    }
+}
+
+/// Size-tiered compaction: everything stays in L0, and runs of at least
+/// `min_merge_width` consecutive tables of similar size are merged into one.
+/// Each byte is rewritten far fewer times than with leveled compaction, at the
+/// cost of more tables per lookup and more space held by shadowed versions.
+#[derive(Debug, Clone)]
+pub struct SizeTieredCompaction {
+    pub min_merge_width: usize,
+    pub max_merge_width: usize,
+    /// A table joins a run when its size is within `[bucket_low, bucket_high]`
+    /// times the run's average size.
+    pub bucket_low: f64,
+    pub bucket_high: f64,
+    /// Tables smaller than this are all considered the same size.
+    pub min_table_size: u64,
+}
+
+impl Default for SizeTieredCompaction {
+    fn default() -> Self {
+        Self {
+            min_merge_width: 4,
+            max_merge_width: 32,
+            bucket_low: 0.5,
+            bucket_high: 1.5,
+            min_table_size: 64 * 1024,
+        }
+    }
+}
+
+impl SizeTieredCompaction {
+    fn fits(&self, run_bytes: u64, run_len: usize, size: u64) -> bool {
+        let average = run_bytes / run_len as u64;
+        if average < self.min_table_size && size < self.min_table_size {
+            return true;
+        }
+        let size = size as f64;
+        size >= average as f64 * self.bucket_low && size <= average as f64 * self.bucket_high
+    }
+}
+
+impl<K, V> CompactionStrategy<K, V> for SizeTieredCompaction
+where
+    K: DBKey + Send + Sync + 'static,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    fn name(&self) -> &'static str {
+        "size-tiered"
+    }
+
+    fn pick(&self, ctx: &mut CompactionContext<'_, K, V>) -> Option<CompactionPick<K, V>> {
+        // Runs must be consecutive in age so that the merged table slots in where
+        // its inputs were and keeps shadowing older tables correctly.
+        let l0 = ctx.levels.first()?;
+        let mut run: Vec<SSTable<K, V>> = Vec::new();
+        let mut run_bytes = 0;
+        for sst in l0 {
+            let size = sst.file_size();
+            if ctx.is_compacting(sst) || (!run.is_empty() && !self.fits(run_bytes, run.len(), size))
+            {
+                if run.len() >= self.min_merge_width {
+                    break;
+                }
+                run.clear();
+                run_bytes = 0;
+                if ctx.is_compacting(sst) {
+                    continue;
+                }
+            }
+            run.push(sst.clone());
+            run_bytes += size;
+            if run.len() == self.max_merge_width {
+                break;
+            }
+        }
+
+        if run.len() < self.min_merge_width.max(2) {
+            return None;
+        }
+        Some(CompactionPick::Merge {
+            inputs: run,
+            target_level: 0,
+            target_file_size: u64::MAX,
+        })
+    }
+}
+
+/// FIFO compaction for data that is only useful while fresh: nothing is ever
+/// merged, and the oldest tables are dropped once they outlive `ttl` or the
+/// total size exceeds `max_table_files_size`. Dropped data is gone for
+/// snapshots too.
+#[derive(Debug, Clone)]
+pub struct FifoCompaction {
+    pub max_table_files_size: u64,
+    pub ttl: Option<Duration>,
+}
+
+impl Default for FifoCompaction {
+    fn default() -> Self {
+        Self {
+            max_table_files_size: 1024 * 1024 * 1024,
+            ttl: None,
+        }
+    }
+}
+
+impl FifoCompaction {
+    fn is_expired<K, V>(&self, sstable: &SSTable<K, V>, now: SystemTime) -> bool
+    where
+        K: DBKey + Send + Sync + 'static,
+        V: Serialize + DeserializeOwned + Send + Sync + 'static,
+    {
+        let Some(ttl) = self.ttl else {
+            return false;
+        };
+        std::fs::metadata(sstable.path())
+            .and_then(|meta| meta.modified())
+            .ok()
+            .and_then(|written| now.duration_since(written).ok())
+            .is_some_and(|age| age > ttl)
+    }
+}
+
+impl<K, V> CompactionStrategy<K, V> for FifoCompaction
+where
+    K: DBKey + Send + Sync + 'static,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    fn name(&self) -> &'static str {
+        "fifo"
+    }
+
+    fn pick(&self, ctx: &mut CompactionContext<'_, K, V>) -> Option<CompactionPick<K, V>> {
+        // Oldest first: deeper levels (left behind by another strategy), then L0.
+        let oldest_first: Vec<&SSTable<K, V>> = ctx
+            .levels
+            .iter()
+            .skip(1)
+            .rev()
+            .flatten()
+            .chain(ctx.levels.first().into_iter().flatten())
+            .collect();
+        let mut total: u64 = oldest_first.iter().map(|s| s.file_size()).sum();
+        let now = SystemTime::now();
+
+        // Only a prefix is dropped, so an older version can never resurface from
+        // underneath a dropped newer one.
+        let mut dropped = Vec::new();
+        for sst in oldest_first {
+            if ctx.is_compacting(sst)
+                || (total <= self.max_table_files_size && !self.is_expired(sst, now))
+            {
+                break;
+            }
+            total -= sst.file_size();
+            dropped.push(sst.clone());
+        }
+
+        if dropped.is_empty() {
+            return None;
+        }
+        Some(CompactionPick::Drop(dropped))
+    }
+}
+
+fn none_compacting<K, V>(
+    ctx: &CompactionContext<'_, K, V>,
+    inputs: Vec<SSTable<K, V>>,
+) -> Option<Vec<SSTable<K, V>>>
+where
+    K: DBKey + Send + Sync + 'static,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    if inputs.iter().any(|s| ctx.is_compacting(s)) {
+        return None;
+    }
+    Some(inputs)
+}
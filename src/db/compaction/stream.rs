@@ -1,8 +1,12 @@
-use crate::db::sstable::SSTableIterator;
-use crate::{DBKey, Entry, Result, SSTable, SSTableId};
+use crate::db::compaction::CompactionStats;
+use crate::{DBKey, Entry, ReadOptions, Result, SSTable, SSTableId};
 use serde::{Serialize, de::DeserializeOwned};
 use std::cmp::Ordering;
-use std::collections::BinaryHeap;
+use std::collections::{BinaryHeap, VecDeque};
+use std::sync::Arc;
+
+/// A sorted source of entries that can be fed into a `MergeStream`.
+pub type EntryIterator<K, V> = Box<dyn Iterator<Item = Result<Entry<K, V>>> + Send>;
 
 pub struct MergeElement<K, V> {
     pub sstable_id: SSTableId,
@@ -26,10 +30,12 @@ impl<K: DBKey, V> Eq for MergeElement<K, V> {}
 
 impl<K: DBKey, V> Ord for MergeElement<K, V> {
     fn cmp(&self, other: &Self) -> Ordering {
-        match other.entry.key.cmp(&self.entry.key) {
-            Ordering::Equal => self.sstable_id.cmp(&other.sstable_id),
-            ord => ord,
-        }
+        other
+            .entry
+            .key
+            .cmp(&self.entry.key)
+            .then_with(|| self.entry.value.seq.cmp(&other.entry.value.seq))
+            .then_with(|| self.sstable_id.cmp(&other.sstable_id))
     }
 }
 
@@ -39,7 +45,11 @@ where
     V: Serialize + DeserializeOwned + Send + Sync + 'static,
 {
     heap: BinaryHeap<MergeElement<K, V>>,
-    iters: Vec<SSTableIterator<K, V>>,
+    iters: Vec<EntryIterator<K, V>>,
+    snapshots: Vec<u64>,
+    retained: VecDeque<Entry<K, V>>,
+    gc_ranges: Option<Vec<(K, K)>>,
+    stats: CompactionStats,
 }
 
 impl<K, V> MergeStream<K, V>
@@ -48,22 +58,89 @@ where
     V: Serialize + DeserializeOwned + Send + Sync + 'static,
 {
     pub fn new(sstables: &[SSTable<K, V>]) -> Result<Self> {
-        let mut iters = Vec::with_capacity(sstables.len());
-        let mut heap = BinaryHeap::with_capacity(sstables.len());
+        let mut sources = Vec::with_capacity(sstables.len());
+        for sst in sstables {
+            // Compaction reads each input once; caching its blocks would only
+            // push out the blocks point lookups are using.
+            let iter = sst.range_opt(.., &ReadOptions { fill_cache: false })?;
+            sources.push((sst.id(), Box::new(iter) as EntryIterator<K, V>));
+        }
+        Self::from_iters(sources)
+    }
 
-        for (idx, sst) in sstables.iter().enumerate() {
-            let mut iter = sst.iter()?;
+    /// Merges arbitrary sorted sources. On duplicate keys the highest sequence
+    /// number wins, then the source with the highest id, so in-memory sources can
+    /// be given synthetic ids that rank them above every SSTable.
+    pub fn from_iters(sources: Vec<(SSTableId, EntryIterator<K, V>)>) -> Result<Self> {
+        let mut iters = Vec::with_capacity(sources.len());
+        let mut heap = BinaryHeap::with_capacity(sources.len());
+
+        for (idx, (sstable_id, mut iter)) in sources.into_iter().enumerate() {
             if let Some(entry) = iter.next() {
                 let entry = entry?;
                 heap.push(MergeElement {
-                    sstable_id: sst.id(),
+                    sstable_id,
                     entry,
                     iter_index: idx,
                 });
             }
             iters.push(iter);
         }
-        Ok(Self { heap, iters })
+        Ok(Self {
+            heap,
+            iters,
+            snapshots: Vec::new(),
+            retained: VecDeque::new(),
+            gc_ranges: None,
+            stats: CompactionStats::default(),
+        })
+    }
+
+    /// Keeps, besides the newest version of each key, the newest version visible
+    /// to each of the given snapshot sequence numbers.
+    pub fn with_snapshots(mut self, mut snapshots: Vec<u64>) -> Self {
+        snapshots.sort_unstable();
+        snapshots.dedup();
+        self.snapshots = snapshots;
+        self
+    }
+
+    /// Enables tombstone garbage collection. `deeper_ranges` are the key ranges of
+    /// every table below the output that is not part of this merge; a tombstone for a
+    /// key outside all of them shadows nothing and is dropped along with the versions
+    /// under it. Pass an empty list when the output is the bottom-most level.
+    pub fn with_tombstone_gc(mut self, deeper_ranges: Vec<(K, K)>) -> Self {
+        self.gc_ranges = Some(deeper_ranges);
+        self
+    }
+
+    /// Entry counts gathered so far. `output_entries` and byte sizes are left to the caller.
+    pub fn stats(&self) -> CompactionStats {
+        self.stats
+    }
+
+    fn tombstones_collectable(&self, key: &K) -> bool {
+        match &self.gc_ranges {
+            Some(ranges) => !ranges.iter().any(|(lo, hi)| lo <= key && key <= hi),
+            None => false,
+        }
+    }
+
+    /// True if some snapshot reads `seq` rather than the newer version at `newer_seq`.
+    fn is_visible_to_snapshot(&self, seq: u64, newer_seq: u64) -> bool {
+        let idx = self.snapshots.partition_point(|&s| s < seq);
+        self.snapshots.get(idx).is_some_and(|&s| s < newer_seq)
+    }
+
+    fn advance(&mut self, iter_index: usize, sstable_id: SSTableId) -> Result<()> {
+        if let Some(result) = self.iters[iter_index].next() {
+            self.heap.push(MergeElement {
+                sstable_id,
+                entry: result?,
+                iter_index,
+            });
+        }
+        Ok(())
     }
 }
 
@@ -75,45 +152,49 @@ where
     type Item = Result<Entry<K, V>>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let winner = self.heap.pop()?;
-
-        if let Some(result) = self.iters[winner.iter_index].next() {
-            match result {
-                Ok(entry) => {
-                    self.heap.push(MergeElement {
-                        sstable_id: winner.sstable_id,
-                        entry,
-                        iter_index: winner.iter_index,
-                    });
-                }
-                Err(e) => return Some(Err(e)),
+        loop {
+            if let Some(entry) = self.retained.pop_front() {
+                return Some(Ok(entry));
             }
-        }
 
-        loop {
-            if let Some(peeked) = self.heap.peek() {
-                if peeked.entry.key != winner.entry.key {
+            let winner = self.heap.pop()?;
+            self.stats.input_entries += 1;
+            if let Err(e) = self.advance(winner.iter_index, winner.sstable_id) {
+                return Some(Err(e));
+            }
+
+            // Collect every surviving version of this key, newest first.
+            let key = Arc::clone(&winner.entry.key);
+            let mut newer_seq = winner.entry.value.seq;
+            self.retained.push_back(winner.entry);
+            while let Some(peeked) = self.heap.peek() {
+                if peeked.entry.key != key {
                     break;
                 }
-            } else {
-                break;
+
+                let old = self.heap.pop().unwrap();
+                self.stats.input_entries += 1;
+                if let Err(e) = self.advance(old.iter_index, old.sstable_id) {
+                    return Some(Err(e));
+                }
+                let seq = old.entry.value.seq;
+                if seq < newer_seq && self.is_visible_to_snapshot(seq, newer_seq) {
+                    newer_seq = seq;
+                    self.retained.push_back(old.entry);
+                } else {
+                    self.stats.dropped_versions += 1;
+                }
             }
 
-            let old = self.heap.pop().unwrap();
-            if let Some(result) = self.iters[old.iter_index].next() {
-                match result {
-                    Ok(entry) => {
-                        self.heap.push(MergeElement {
-                            sstable_id: old.sstable_id,
-                            entry,
-                            iter_index: old.iter_index,
-                        });
-                    }
-                    Err(e) => return Some(Err(e)),
+            // A tombstone with nothing retained beneath it hides nothing once no
+            // deeper table can hold the key, so it can go. Repeat in case the
+            // version above it was a tombstone kept for a snapshot.
+            if self.tombstones_collectable(&key) {
+                while self.retained.back().is_some_and(|e| e.value.is_tombstone) {
+                    self.retained.pop_back();
+                    self.stats.dropped_tombstones += 1;
                 }
             }
         }
-
-        Some(Ok(winner.entry))
     }
 }
@@ -0,0 +1,126 @@
+use crate::db::compaction::CompactionTask;
+use crate::db::database::DB;
+use crate::{DBKey, Error, Result};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::thread::JoinHandle;
+
+/// Closes the database when the last user-facing handle goes away.
+#[derive(Debug)]
+pub(crate) struct CloseGuard<K, V>
+where
+    K: DBKey + Send + Sync + 'static + std::fmt::Debug,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static + std::fmt::Debug,
+{
+    db: DB<K, V>,
+}
+
+impl<K, V> Drop for CloseGuard<K, V>
+where
+    K: DBKey + Send + Sync + 'static + std::fmt::Debug,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static + std::fmt::Debug,
+{
+    /// Errors are lost here; `DB::close` is the way to see them.
+    fn drop(&mut self) {
+        let _ = self.db.shutdown();
+    }
+}
+
+impl<K, V> DB<K, V>
+where
+    K: DBKey + Send + Sync + 'static + std::fmt::Debug,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static + std::fmt::Debug,
+{
+    /// Flushes the MemTables already queued for flushing, waits for running
+    /// compactions and installs their results, syncs the WAL, and joins the
+    /// background threads. Writes still in the MemTable stay in the WAL and are
+    /// replayed on the next open, unless some of them were made with
+    /// `WriteOptions::disable_wal`; such a MemTable is flushed, since nothing
+    /// else holds those writes.
+    ///
+    /// Dropping the last handle does the same but has no way to report errors.
+    /// Writes through any remaining handle fail with `Error::Closed`.
+    pub fn close(self) -> Result<()> {
+        self.shutdown()
+    }
+
+    pub(crate) fn with_close_guard(mut self) -> Self {
+        self.guard = Some(Arc::new(CloseGuard {
+            db: self.detached(),
+        }));
+        self
+    }
+
+    /// A handle that does not keep the database open, for internal use.
+    pub(crate) fn detached(&self) -> Self {
+        Self {
+            guard: None,
+            ..self.clone()
+        }
+    }
+
+    pub(crate) fn ensure_open(&self) -> Result<()> {
+        if self.config.closed.load(Ordering::Acquire) {
+            return Err(Error::Closed);
+        }
+        Ok(())
+    }
+
+    /// Stops the background threads and closes the WAL. A thread that panicked
+    /// does not cut the sequence short; the first failure is returned once
+    /// every step has run.
+    pub(crate) fn shutdown(&self) -> Result<()> {
+        if self.config.closed.swap(true, Ordering::AcqRel) {
+            return Ok(());
+        }
+        let join = |handle: Option<JoinHandle<()>>, name: &str| match handle {
+            Some(handle) => handle
+                .join()
+                .map_err(|_| Error::Corruption(format!("{} panicked", name))),
+            None => Ok(()),
+        };
+
+        self.config.stall.notify();
+        // The flush thread makes one last attempt at the queued MemTables and
+        // exits; whatever it fails to flush stays in the WALs.
+        let _ = self.config.flush_tx.try_send(());
+        let flusher = join(self.config.flusher.lock().take(), "Flush thread");
+        // Let writes, a switch or a `flush` running on another thread finish first.
+        let _switch = self.switch_lock.write();
+        let _flush = self.flush_mutex.lock();
+        let unlogged = self.flush_unlogged();
+
+        // The coordinator exits once every running compaction is installed; it
+        // starts no new ones because the database is already marked closed.
+        let coordinator = join(
+            self.config.coordinator.lock().take(),
+            "Compaction coordinator",
+        );
+        let _ = self.config.compaction_tx.send(CompactionTask::Shutdown);
+        let worker = join(
+            self.config.compaction_worker.lock().take(),
+            "Compaction worker",
+        );
+        let wal = self.wal.close();
+        flusher.and(unlogged).and(coordinator).and(worker).and(wal)
+    }
+
+    /// Flushes the MemTable and the queued ones if any of them holds writes
+    /// that skipped the WAL. Must be called with the switch lock and the flush
+    /// mutex held.
+    fn flush_unlogged(&self) -> Result<()> {
+        let version = self.version.load();
+        let unlogged = self.memtable.load().has_unlogged_writes()
+            || version
+                .immutables
+                .iter()
+                .any(|imm| imm.memtable.has_unlogged_writes());
+        if !unlogged {
+            return Ok(());
+        }
+        self.switch_memtable_locked(true)?;
+        self.flush_immutables()
+    }
+}
@@ -0,0 +1,183 @@
+use crate::db::compaction::CompactionResult;
+use crate::db::database::DB;
+use crate::{CompactionStats, DBKey, SSTable, SSTableId};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Delay before the first retry of a failed compaction; doubled on every failure.
+const INITIAL_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30);
+/// How often an idle coordinator checks whether the database was closed.
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A failed compaction waiting to be resubmitted. Its inputs stay claimed in
+/// the meantime so no other compaction picks them up.
+struct PendingRetry<K, V>
+where
+    K: DBKey + Send + Sync + 'static,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    due: Instant,
+    inputs: Vec<SSTable<K, V>>,
+    level: usize,
+    target_file_size: u64,
+}
+
+/// Installs compaction results as they arrive, starts the follow-up work they
+/// make necessary and retries failures, so an idle or read-only database keeps
+/// compacting without any help from writers.
+struct Coordinator<K, V>
+where
+    K: DBKey + Send + Sync + 'static + std::fmt::Debug,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static + std::fmt::Debug,
+{
+    db: DB<K, V>,
+    results: Receiver<CompactionResult<K, V>>,
+    retries: Vec<PendingRetry<K, V>>,
+    /// Consecutive failures per set of inputs.
+    failures: HashMap<Vec<SSTableId>, u32>,
+}
+
+impl<K, V> DB<K, V>
+where
+    K: DBKey + Send + Sync + 'static + std::fmt::Debug,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static + std::fmt::Debug,
+{
+    /// Runs until the database is closed and no compaction is in flight. `self`
+    /// must be a detached handle, or the database could never close.
+    pub(crate) fn spawn_coordinator(
+        self,
+        results: Receiver<CompactionResult<K, V>>,
+    ) -> JoinHandle<()> {
+        let coordinator = Coordinator {
+            db: self,
+            results,
+            retries: Vec::new(),
+            failures: HashMap::new(),
+        };
+        std::thread::spawn(move || coordinator.run())
+    }
+}
+
+impl<K, V> Coordinator<K, V>
+where
+    K: DBKey + Send + Sync + 'static + std::fmt::Debug,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static + std::fmt::Debug,
+{
+    fn run(mut self) {
+        loop {
+            if self.db.config.closed.load(Ordering::Acquire) {
+                // Pending retries are abandoned; only running compactions are waited for.
+                for retry in std::mem::take(&mut self.retries) {
+                    self.release(&retry.inputs);
+                }
+                if self.db.compaction_state.lock().compacting_ids.is_empty() {
+                    break;
+                }
+            }
+
+            let now = Instant::now();
+            let timeout = self
+                .retries
+                .iter()
+                .map(|r| r.due.saturating_duration_since(now))
+                .min()
+                .map_or(IDLE_POLL_INTERVAL, |wait| wait.min(IDLE_POLL_INTERVAL));
+            match self.results.recv_timeout(timeout) {
+                Ok(result) => self.install(result),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            self.resubmit_due();
+        }
+    }
+
+    fn install(&mut self, result: CompactionResult<K, V>) {
+        match result {
+            CompactionResult::Success {
+                sstables,
+                level,
+                original_sstables,
+                stats,
+            } => {
+                self.failures.remove(&input_key(&original_sstables));
+                if let Err(e) = self
+                    .db
+                    .apply_compaction_success(sstables, level, original_sstables)
+                {
+                    self.db.compaction_state.lock().last_error = Some(e);
+                    return;
+                }
+                self.db.compaction_state.lock().stats.merge(&stats);
+                self.db.check_all_compactions();
+            }
+            CompactionResult::Failure {
+                error,
+                original_sstables,
+                level,
+                target_file_size,
+            } => {
+                let attempts = self
+                    .failures
+                    .entry(input_key(&original_sstables))
+                    .or_insert(0);
+                *attempts += 1;
+                let backoff = INITIAL_RETRY_BACKOFF
+                    .saturating_mul(1 << (*attempts - 1).min(16))
+                    .min(MAX_RETRY_BACKOFF);
+                {
+                    let mut state = self.db.compaction_state.lock();
+                    state.stats.merge(&CompactionStats {
+                        failures: 1,
+                        ..CompactionStats::default()
+                    });
+                    state.last_error = Some(error);
+                }
+                self.retries.push(PendingRetry {
+                    due: Instant::now() + backoff,
+                    inputs: original_sstables,
+                    level,
+                    target_file_size,
+                });
+            }
+        }
+    }
+
+    fn resubmit_due(&mut self) {
+        let now = Instant::now();
+        let (due, waiting) = std::mem::take(&mut self.retries)
+            .into_iter()
+            .partition(|r| r.due <= now);
+        self.retries = waiting;
+        for retry in due {
+            if self.db.config.closed.load(Ordering::Acquire) {
+                self.release(&retry.inputs);
+            } else {
+                self.db
+                    .trigger_compaction(retry.inputs, retry.level, retry.target_file_size);
+            }
+        }
+    }
+
+    fn release(&self, inputs: &[SSTable<K, V>]) {
+        let mut state = self.db.compaction_state.lock();
+        for sst in inputs {
+            state.compacting_ids.remove(&sst.id());
+        }
+    }
+}
+
+fn input_key<K, V>(inputs: &[SSTable<K, V>]) -> Vec<SSTableId>
+where
+    K: DBKey + Send + Sync + 'static,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    let mut ids: Vec<SSTableId> = inputs.iter().map(|s| s.id()).collect();
+    ids.sort();
+    ids
+}
@@ -1,21 +1,62 @@
+use crate::db::compaction::stream::MergeStream;
 use crate::db::database::DB;
-use crate::db::database::VersionState;
+use crate::db::database::{VersionState, sort_level};
+use crate::db::io::sync_dir;
 use crate::types::records::DBKey;
-use crate::{ManifestEntry, MemTable, Result, SSTable, SSTableId};
+use crate::{ManifestEntry, MemTable, Result, SSTable, VersionEdit};
 use serde::Serialize;
 use serde::de::DeserializeOwned;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::atomic::Ordering;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// How often an idle flush thread checks whether the database was closed.
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Delay before a failed flush is tried again.
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
 
 impl<K, V> DB<K, V>
 where
     K: DBKey + Send + Sync + 'static + std::fmt::Debug,
     V: Serialize + DeserializeOwned + Send + Sync + 'static + std::fmt::Debug,
 {
-    pub(crate) fn switch_memtable(&self) -> Result<()> {
-        let _lock = self.flush_mutex.lock();
-        if self.config.memtable_size.load(Ordering::Relaxed) < self.config.max_memtable_size {
+    /// Flushes the MemTable to an L0 table. With `wait` the call returns once it
+    /// and every MemTable queued before it are on disk, reporting any error;
+    /// otherwise the flush is left to the background flush thread. A waiting
+    /// flush also returns the last error the flush thread ran into.
+    pub fn flush(&self, wait: bool) -> Result<()> {
+        self.ensure_open()?;
+        self.switch_memtable(true)?;
+        if wait {
+            let _lock = self.flush_mutex.lock();
+            self.take_flush_error()?;
+            self.flush_immutables()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the last error of the flush thread, once.
+    pub(crate) fn take_flush_error(&self) -> Result<()> {
+        match self.config.flush_error.lock().take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Moves the MemTable to the queue of immutable MemTables, once it is full
+    /// or whenever `force` is set, and wakes the flush thread for it.
+    pub(crate) fn switch_memtable(&self, force: bool) -> Result<()> {
+        let _lock = self.switch_lock.write();
+        self.switch_memtable_locked(force)
+    }
+
+    /// `switch_memtable` for a caller already holding the switch lock.
+    pub(crate) fn switch_memtable_locked(&self, force: bool) -> Result<()> {
+        let size = self.memtable.load().size_bytes();
+        if size == 0 || (!force && size < self.config.max_memtable_size) {
             return Ok(());
         }
 
@@ -25,9 +66,9 @@ where
         // Rotate WAL before switching memtable
         let wal_id = self.wal.rotate()?;
 
-        self.memtable.store(new_memtable);
-        self.config.memtable_size.store(0, Ordering::Relaxed);
-
+        // The old MemTable joins the immutables before the new one replaces it.
+        // Readers load the MemTable before the version, so one that still gets
+        // the old MemTable may see it twice, but none can miss it.
         {
             let _manifest = self.manifest.lock();
             let old_version = self.version.load();
@@ -37,16 +78,43 @@ where
                 wal_id,
             });
 
-            self.version.store(Arc::new(VersionState {
+            self.install_version(VersionState {
                 levels: old_version.levels.clone(),
                 immutables: new_immutables,
-            }));
+            });
         }
+        self.memtable.store(new_memtable);
 
-        self.flush_immutables()?;
+        // A wake-up already pending covers this MemTable too.
+        let _ = self.config.flush_tx.try_send(());
         Ok(())
     }
 
+    /// Runs until the database is closed, flushing immutable MemTables as they
+    /// are queued and whatever is still queued at close. `self` must be a
+    /// detached handle, or the database could never close.
+    pub(crate) fn spawn_flusher(self, requests: Receiver<()>) -> JoinHandle<()> {
+        std::thread::spawn(move || {
+            let mut timeout = IDLE_POLL_INTERVAL;
+            while let Ok(()) | Err(RecvTimeoutError::Timeout) = requests.recv_timeout(timeout) {
+                let closing = self.config.closed.load(Ordering::Acquire);
+                if !self.version.load().immutables.is_empty() {
+                    let _lock = self.flush_mutex.lock();
+                    timeout = match self.flush_immutables() {
+                        Ok(()) => IDLE_POLL_INTERVAL,
+                        Err(e) => {
+                            *self.config.flush_error.lock() = Some(e);
+                            RETRY_BACKOFF
+                        }
+                    };
+                }
+                if closing {
+                    break;
+                }
+            }
+        })
+    }
+
     pub(crate) fn flush_immutables(&self) -> Result<()> {
         loop {
             let version = self.version.load();
@@ -57,43 +125,51 @@ where
             let imm_entry = version.immutables[0].clone();
             let imm = Arc::clone(&imm_entry.memtable);
 
-            let id: SSTableId;
-            let filename: String;
-            let path: PathBuf;
-
-            {
-                let mut state = self.compaction_state.lock();
-                id = state.next_id;
-                state.next_id = SSTableId(id.0 + 1);
-                filename = format!("L0-{}.sst", id);
-                path = self.config.path.join(&filename);
-            }
-
-            let new_sstable =
-                SSTable::write_from_memtable(&path, &imm, id, Some(Arc::clone(&self.block_cache)))?;
-
+            let id = self.config.ids.allocate();
+            let filename = format!("L0-{}.sst", id);
+            let path = self.config.path.join(&filename);
+
+            // Shadowed versions no snapshot can see are dropped on the way to disk.
+            let stream = MergeStream::from_iters(vec![(id, Box::new(imm.range_iter(..)))])?
+                .with_snapshots(self.live_snapshots());
+            let new_sstable = SSTable::write_from_iter_with_options(
+                &path,
+                stream,
+                id,
+                0,
+                Some(Arc::clone(&self.block_cache)),
+                &self.config.table_options,
+            )?;
+
+            // The table itself was synced when written; its directory entry
+            // must be durable too before the edit lets the WAL go.
+            sync_dir(&self.config.path)?;
             {
                 let mut manifest = self.manifest.lock();
-                let state = self.compaction_state.lock();
-                manifest.append(&ManifestEntry::AddSSTable {
-                    level: 0,
-                    path: PathBuf::from(&filename),
-                })?;
-                manifest.append(&ManifestEntry::NextID(state.next_id))?;
+                manifest.append(&ManifestEntry::Edit(VersionEdit {
+                    added: vec![(0, PathBuf::from(&filename))],
+                    next_id: Some(self.config.ids.peek()),
+                    log_number: Some(imm_entry.wal_id + 1),
+                    ..VersionEdit::default()
+                }))?;
+                self.config
+                    .log_number
+                    .store(imm_entry.wal_id + 1, Ordering::Release);
                 manifest.flush()?;
 
                 let old_version = self.version.load();
                 let mut new_levels = old_version.levels.clone();
                 new_levels[0].push(new_sstable);
-                new_levels[0].sort_by_key(|sst| sst.id());
+                sort_level(0, &mut new_levels[0]);
 
                 let mut new_immutables = old_version.immutables.clone();
                 new_immutables.remove(0);
 
-                self.version.store(Arc::new(VersionState {
+                self.install_version(VersionState {
                     levels: new_levels,
                     immutables: new_immutables,
-                }));
+                });
+                self.maybe_roll_manifest(&mut manifest)?;
             }
 
             {
@@ -1,23 +1,44 @@
+pub mod close;
+pub mod coordinator;
 pub mod flush;
+pub mod orphans;
 pub mod read;
+pub mod scan;
+pub mod sequence;
+pub mod snapshot;
+pub mod stall;
 pub mod write;
 
-use crate::db::compaction::{CompactionResult, CompactionTask, Compactor};
+pub use scan::DBIterator;
+pub use snapshot::Snapshot;
+pub use stall::StallStats;
+
+use crate::db::compaction::strategy::{CompactionContext, CompactionPick, CompactionStrategy};
+use crate::db::compaction::{CompactionStats, CompactionTask, Compactor, IdAllocator};
+use crate::db::database::close::CloseGuard;
+use crate::db::database::orphans::sweep_orphans;
+use crate::db::database::sequence::Sequencer;
+use crate::db::database::stall::StallState;
+use crate::db::io::sync_dir;
+use crate::db::manifest::manifest_number;
+use crate::db::options::StoredOptions;
 use crate::db::wal::WalManager;
+use crate::db::wal::recovery::replay_wals;
 use crate::{
-    BlockCache, DBKey, LogEntry, Manifest, ManifestEntry, MemTable, Result, SSTable, SSTableId, Wal,
+    BlockCache, CacheStats, DBKey, DBOptions, Error, Manifest, ManifestEntry, MemTable, Result,
+    SSTable, SSTableId, TableOptions, VersionEdit, WalRecoveryReport, WriteStallOptions,
 };
 use arc_swap::ArcSwap;
-use parking_lot::Mutex;
+use parking_lot::{Mutex, RwLock};
 use serde::{Serialize, de::DeserializeOwned};
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::io;
+use std::ops::Bound;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::mpsc;
-
-pub(crate) const MANIFEST_FILE_NAME: &str = "MANIFEST";
+use std::thread::JoinHandle;
 
 /// An immutable point-in-time view of the database's SSTables and Immutable MemTables.
 #[derive(Debug)]
@@ -64,7 +85,7 @@ where
 }
 
 /// The public handle to the database.
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct DB<K, V>
 where
     K: DBKey + Send + Sync + 'static + std::fmt::Debug,
@@ -75,9 +96,39 @@ where
     pub(crate) manifest: Arc<Mutex<Manifest>>,
     pub(crate) version: Arc<ArcSwap<VersionState<K, V>>>,
     pub(crate) block_cache: Arc<BlockCache<K, V>>,
-    pub(crate) compaction_state: Arc<Mutex<CompactionState<K, V>>>,
+    pub(crate) compaction_state: Arc<Mutex<CompactionState<K>>>,
+    /// Held while immutable MemTables are written out.
     pub(crate) flush_mutex: Arc<Mutex<()>>,
+    /// Held for writing while the MemTable is moved to the immutable queue, and
+    /// for reading by each write batch from taking its sequence numbers until it
+    /// is in the MemTable.
+    pub(crate) switch_lock: Arc<RwLock<()>>,
     pub(crate) config: Arc<DBConfig<K, V>>,
+    /// Shared by every user-facing handle; the database closes when the last one
+    /// is dropped. Internal handles leave it unset so they never keep it open.
+    pub(crate) guard: Option<Arc<CloseGuard<K, V>>>,
+}
+
+// Written by hand because the derive would require `K: Clone, V: Clone`.
+impl<K, V> Clone for DB<K, V>
+where
+    K: DBKey + Send + Sync + 'static + std::fmt::Debug,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static + std::fmt::Debug,
+{
+    fn clone(&self) -> Self {
+        Self {
+            memtable: Arc::clone(&self.memtable),
+            wal: Arc::clone(&self.wal),
+            manifest: Arc::clone(&self.manifest),
+            version: Arc::clone(&self.version),
+            block_cache: Arc::clone(&self.block_cache),
+            compaction_state: Arc::clone(&self.compaction_state),
+            flush_mutex: Arc::clone(&self.flush_mutex),
+            switch_lock: Arc::clone(&self.switch_lock),
+            config: Arc::clone(&self.config),
+            guard: self.guard.clone(),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -88,19 +139,49 @@ where
 {
     pub(crate) path: PathBuf,
     pub(crate) max_memtable_size: usize,
-    pub(crate) memtable_size: AtomicUsize,
+    pub(crate) max_manifest_file_size: u64,
+    pub(crate) compaction_strategy: Arc<dyn CompactionStrategy<K, V>>,
+    pub(crate) table_options: TableOptions,
+    pub(crate) write_stall: WriteStallOptions,
+    /// Write stall statistics and the signal stopped writers wait on.
+    pub(crate) stall: StallState,
     pub(crate) compaction_tx: mpsc::Sender<CompactionTask<K, V>>,
+    pub(crate) compaction_worker: Mutex<Option<JoinHandle<()>>>,
+    /// Installs compaction results in the background; see `coordinator.rs`.
+    pub(crate) coordinator: Mutex<Option<JoinHandle<()>>>,
+    /// Wakes the flush thread when a MemTable is queued for flushing.
+    pub(crate) flush_tx: mpsc::SyncSender<()>,
+    /// Flushes immutable MemTables in the background; see `flush.rs`.
+    pub(crate) flusher: Mutex<Option<JoinHandle<()>>>,
+    /// The last failure of the flush thread, until a write or a waiting flush reports it.
+    pub(crate) flush_error: Mutex<Option<Error>>,
+    /// Set once `close` has started; no new writes or compactions are accepted.
+    pub(crate) closed: AtomicBool,
+    /// SSTable ids, shared by flushes and the compaction worker.
+    pub(crate) ids: IdAllocator,
+    /// Id of the oldest WAL that has not been flushed yet.
+    pub(crate) log_number: AtomicU64,
+    /// Sequence numbers of writes, and which of them snapshots can see.
+    pub(crate) sequence: Sequencer,
+    /// Live snapshot sequence numbers and how many handles share each one.
+    pub(crate) snapshots: Mutex<BTreeMap<u64, usize>>,
+    /// What replaying the WALs on open recovered and dropped.
+    pub(crate) wal_recovery: WalRecoveryReport,
 }
 
 #[derive(Debug)]
-pub(crate) struct CompactionState<K, V>
+pub(crate) struct CompactionState<K>
 where
     K: DBKey + Send + Sync + 'static + std::fmt::Debug,
-    V: Serialize + DeserializeOwned + Send + Sync + 'static + std::fmt::Debug,
 {
-    pub(crate) next_id: SSTableId,
     pub(crate) compacting_ids: HashSet<SSTableId>,
-    pub(crate) compaction_rx: mpsc::Receiver<CompactionResult<K, V>>,
+    /// Per level, the largest key of the last table compacted out of it, so that
+    /// successive compactions rotate through the key space.
+    pub(crate) compact_pointers: Vec<Option<K>>,
+    /// Totals over every compaction installed since open.
+    pub(crate) stats: CompactionStats,
+    /// The most recent compaction that failed or could not be installed.
+    pub(crate) last_error: Option<Error>,
 }
 
 impl<K, V> DB<K, V>
@@ -109,21 +190,35 @@ where
     V: Serialize + DeserializeOwned + Send + Sync + 'static + std::fmt::Debug,
 {
     pub fn open(path: &Path, max_memtable_size: usize) -> Result<Self> {
+        Self::open_with_options(path, DBOptions::new().max_memtable_size(max_memtable_size))
+    }
+
+    pub fn open_with_options(path: &Path, options: DBOptions<K, V>) -> Result<Self> {
+        options.validate()?;
         std::fs::create_dir_all(path)?;
-        let block_cache = Arc::new(BlockCache::new(32 * 1024 * 1024));
 
-        let manifest_path = path.join(MANIFEST_FILE_NAME);
-        let manifest = if manifest_path.exists() {
-            Manifest::open(manifest_path)?
-        } else {
-            Manifest::create(manifest_path)?
-        };
+        let stored = StoredOptions::from_options(&options);
+        if let Some(previous) = StoredOptions::load(path)? {
+            previous.check_compatible(&stored, options.allow_strategy_change)?;
+        }
+
+        let block_cache = Arc::new(match &options.block_cache {
+            Some(shared) => shared.share(),
+            None => BlockCache::new(options.block_cache_size),
+        });
+
+        let manifest_path = Manifest::current(path)?;
 
         let mut levels: Vec<Vec<SSTable<K, V>>> = vec![Vec::new()];
         let mut next_id = SSTableId(0);
+        let mut log_number = 0;
         let mut active_sstables: HashSet<(usize, PathBuf)> = HashSet::new();
 
-        for record_result in manifest.iter()? {
+        let records = match &manifest_path {
+            Some(manifest_path) => Some(Manifest::read(manifest_path)?),
+            None => None,
+        };
+        for record_result in records.into_iter().flatten() {
             match record_result.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))? {
                 ManifestEntry::AddSSTable { level, path } => {
                     active_sstables.insert((level, path.clone()));
@@ -134,18 +229,76 @@ where
                 ManifestEntry::NextID(id) => {
                     next_id = id;
                 }
+                ManifestEntry::LogNumber(number) => {
+                    log_number = number;
+                }
+                ManifestEntry::Snapshot {
+                    levels,
+                    next_id: id,
+                    log_number: number,
+                } => {
+                    active_sstables = levels
+                        .into_iter()
+                        .enumerate()
+                        .flat_map(|(level, paths)| paths.into_iter().map(move |p| (level, p)))
+                        .collect();
+                    next_id = id;
+                    log_number = number;
+                }
+                ManifestEntry::Edit(edit) => {
+                    for removed in &edit.removed {
+                        active_sstables.remove(removed);
+                    }
+                    active_sstables.extend(edit.added);
+                    next_id = edit.next_id.unwrap_or(next_id);
+                    log_number = edit.log_number.unwrap_or(log_number);
+                }
             }
         }
 
+        let live_tables = active_sstables
+            .iter()
+            .filter_map(|(_, rel_path)| rel_path.file_name().map(|name| name.to_os_string()))
+            .collect();
+        let live_manifest = manifest_path.as_deref().and_then(Path::file_name);
+        sweep_orphans(
+            path,
+            &live_tables,
+            live_manifest,
+            log_number,
+            options.quarantine_orphans,
+        )?;
+
+        let mut last_sequence = 0;
         for (level, rel_path) in active_sstables {
             if level >= levels.len() {
                 levels.resize_with(level + 1, Vec::new);
             }
             let sstable = SSTable::open(&path.join(rel_path), Some(Arc::clone(&block_cache)))?;
+            last_sequence = last_sequence.max(sstable.max_seq());
+            next_id = next_id.max(SSTableId(sstable.id().0 + 1));
             levels[level].push(sstable);
         }
-        for l in &mut levels {
-            l.sort_by_key(|sst| sst.id());
+        for (level, tables) in levels.iter_mut().enumerate() {
+            sort_level(level, tables);
+            if level > 0 {
+                check_disjoint(level, tables)?;
+            }
+        }
+
+        // Every open starts a fresh manifest, so replay never covers more than
+        // one process lifetime of edits.
+        let manifest_number = manifest_path
+            .as_deref()
+            .and_then(manifest_number)
+            .unwrap_or(0);
+        let manifest = Manifest::create(
+            path,
+            manifest_number + 1,
+            &manifest_snapshot(&levels, next_id, log_number),
+        )?;
+        if let Some(old_manifest) = &manifest_path {
+            std::fs::remove_file(old_manifest)?;
         }
 
         let version = Arc::new(ArcSwap::from(Arc::new(VersionState {
@@ -158,143 +311,199 @@ where
         for entry in std::fs::read_dir(path)? {
             let entry = entry?;
             let path = entry.path();
-            if path.extension().and_then(|s| s.to_str()) == Some("wal") {
-                if let Some(name) = path.file_stem().and_then(|s| s.to_str()) {
-                    if let Ok(id) = name.parse::<u64>() {
-                        wal_files.push((id, path));
-                    }
-                }
+            if path.extension().and_then(|s| s.to_str()) == Some("wal")
+                && let Some(name) = path.file_stem().and_then(|s| s.to_str())
+                && let Ok(id) = name.parse::<u64>()
+            {
+                wal_files.push((id, path));
             }
         }
         wal_files.sort_by_key(|(id, _)| *id);
 
         let memtable = Arc::new(MemTable::new());
-        let mut last_wal_id = 0;
+        // New WALs must not reuse an id the manifest already counts as flushed.
+        let mut last_wal_id = log_number;
 
-        for (id, wal_path) in &wal_files {
+        if let Some((id, _)) = wal_files.last() {
             last_wal_id = *id;
-            let existing_wal = Wal::open(wal_path)?;
-            for entry in existing_wal.iter()? {
-                match entry.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))? {
-                    LogEntry::Put(k, v) => memtable.put(k, v),
-                    LogEntry::Delete(k) => memtable.delete(k),
+        }
+        let (wal_recovery, wal_repairs) = replay_wals(
+            &wal_files,
+            options.wal_recovery_mode,
+            last_sequence,
+            |entries, encoded_size| {
+                for entry in entries {
+                    last_sequence = last_sequence.max(entry.seq());
                 }
-            }
+                memtable.apply(entries, encoded_size);
+            },
+        )?;
+        // New writes never go to a WAL that recovery cuts back, and the WALs are
+        // only repaired once nothing else can fail the open.
+        if !wal_repairs.is_empty() {
+            last_wal_id += 1;
         }
+        let wal = WalManager::with_sync_interval(
+            path.to_path_buf(),
+            last_wal_id,
+            options.wal_sync_interval,
+        )?;
+        wal_repairs.apply()?;
+        // Recorded only now, so a failed open leaves the options it checked
+        // against in place for the next attempt.
+        stored.store(path)?;
 
         let (task_tx, task_rx) = mpsc::channel();
         let (result_tx, result_rx) = mpsc::channel();
-        std::thread::spawn(move || {
+        let (flush_tx, flush_rx) = mpsc::sync_channel(1);
+        let compaction_worker = std::thread::spawn(move || {
             Compactor::run_worker::<K, V>(task_rx, result_tx);
         });
 
-        Ok(Self {
+        let db = Self {
             memtable: Arc::new(ArcSwap::from(memtable)),
-            wal: Arc::new(WalManager::new(path.to_path_buf(), last_wal_id)?),
+            wal: Arc::new(wal),
             manifest: Arc::new(Mutex::new(manifest)),
             version,
             block_cache,
             compaction_state: Arc::new(Mutex::new(CompactionState {
-                next_id,
                 compacting_ids: HashSet::new(),
-                compaction_rx: result_rx,
+                compact_pointers: Vec::new(),
+                stats: CompactionStats::default(),
+                last_error: None,
             })),
             flush_mutex: Arc::new(Mutex::new(())),
+            switch_lock: Arc::new(RwLock::new(())),
             config: Arc::new(DBConfig {
                 path: path.to_path_buf(),
-                max_memtable_size,
-                memtable_size: AtomicUsize::new(0),
+                max_memtable_size: options.max_memtable_size,
+                max_manifest_file_size: options.max_manifest_file_size,
+                compaction_strategy: options.compaction_strategy,
+                table_options: options.table,
+                write_stall: options.write_stall,
+                stall: StallState::default(),
                 compaction_tx: task_tx,
+                compaction_worker: Mutex::new(Some(compaction_worker)),
+                coordinator: Mutex::new(None),
+                flush_tx,
+                flusher: Mutex::new(None),
+                flush_error: Mutex::new(None),
+                closed: AtomicBool::new(false),
+                ids: IdAllocator::new(next_id),
+                log_number: AtomicU64::new(log_number),
+                sequence: Sequencer::new(last_sequence),
+                snapshots: Mutex::new(BTreeMap::new()),
+                wal_recovery,
             }),
-        })
+            guard: None,
+        };
+        db.config
+            .stall
+            .record_backlog(&db.version.load(), db.config.compaction_strategy.as_ref());
+        let coordinator = db.detached().spawn_coordinator(result_rx);
+        *db.config.coordinator.lock() = Some(coordinator);
+        let flusher = db.detached().spawn_flusher(flush_rx);
+        *db.config.flusher.lock() = Some(flusher);
+        Ok(db.with_close_guard())
     }
 
-    pub fn handle_compaction_results(&self) -> Result<()> {
-        let mut results = Vec::new();
-        {
-            let state = self.compaction_state.lock();
-            while let Ok(result) = state.compaction_rx.try_recv() {
-                results.push(result);
+    /// Asks the compaction strategy for work until it has nothing left to start.
+    pub(crate) fn check_all_compactions(&self) {
+        loop {
+            if self.config.closed.load(Ordering::Acquire) {
+                return;
             }
-        }
-        if results.is_empty() {
-            return Ok(());
-        }
-
-        for result in results {
-            match result {
-                CompactionResult::Success {
-                    sstable,
-                    level,
-                    original_sstables,
-                } => {
-                    self.apply_compaction_success(sstable, level, original_sstables)?;
-                }
-                CompactionResult::Failure(e) => {
-                    eprintln!("Compaction worker failed: {}", e);
+            let pick = {
+                // Installs swap the version under this lock, so loading it here
+                // never pairs an outdated version with already released inputs.
+                let mut state = self.compaction_state.lock();
+                let version = self.version.load();
+                let state = &mut *state;
+                let mut ctx = CompactionContext {
+                    levels: &version.levels,
+                    compacting: &state.compacting_ids,
+                    compact_pointers: &mut state.compact_pointers,
+                };
+                let Some(pick) = self.config.compaction_strategy.pick(&mut ctx) else {
+                    return;
+                };
+                pick
+            };
+
+            match pick {
+                CompactionPick::Merge {
+                    inputs,
+                    target_level,
+                    target_file_size,
+                } => self.trigger_compaction(inputs, target_level, target_file_size),
+                CompactionPick::Drop(sstables) => {
+                    let stats = CompactionStats {
+                        input_entries: sstables.iter().map(|s| s.num_entries()).sum(),
+                        input_bytes: sstables.iter().map(|s| s.file_size()).sum(),
+                        ..CompactionStats::default()
+                    };
+                    if let Err(e) = self.apply_compaction_success(Vec::new(), 0, sstables) {
+                        self.compaction_state.lock().last_error = Some(e);
+                        return;
+                    }
+                    self.compaction_state.lock().stats.merge(&stats);
                 }
             }
         }
-        self.check_all_compactions();
-        Ok(())
     }
 
-    fn check_all_compactions(&self) {
-        let version = self.version.load();
-        for level in 0..version.levels.len() {
-            self.maybe_trigger_compaction(level);
-        }
-    }
-
-    fn maybe_trigger_compaction(&self, level: usize) {
-        let threshold = 4;
-        let version = self.version.load();
-        if version.levels[level].len() < threshold {
-            return;
-        }
-
-        let mut state = self.compaction_state.lock();
-        let any_compacting = version.levels[level]
-            .iter()
-            .any(|s| state.compacting_ids.contains(&s.id()));
-        if !any_compacting {
-            let sstables = version.levels[level].clone();
-            self.trigger_compaction(&mut state, sstables, level + 1);
-        }
-    }
-
-    fn trigger_compaction(
+    /// Claims `sstables` and queues their merge into `target_level`. Claiming
+    /// tables that are already claimed (for a retry) is harmless.
+    pub(crate) fn trigger_compaction(
         &self,
-        state: &mut CompactionState<K, V>,
         sstables: Vec<SSTable<K, V>>,
         target_level: usize,
+        target_file_size: u64,
     ) {
-        for sst in &sstables {
-            state.compacting_ids.insert(sst.id());
+        {
+            let mut state = self.compaction_state.lock();
+            for sst in &sstables {
+                state.compacting_ids.insert(sst.id());
+            }
         }
-        let id = state.next_id;
-        state.next_id = SSTableId(id.0 + 1);
-        let output_path = self
-            .config
-            .path
-            .join(format!("L{}-{}.sst", target_level, id));
+        let input_ids: HashSet<SSTableId> = sstables.iter().map(|s| s.id()).collect();
+        let version = self.version.load();
+        let deeper_ranges = version
+            .levels
+            .iter()
+            .skip(target_level)
+            .flatten()
+            .filter(|s| !input_ids.contains(&s.id()))
+            .map(|s| (s.min_key().clone(), s.max_key().clone()))
+            .collect();
         let _ = self.config.compaction_tx.send(CompactionTask::Compact {
             sstables,
-            output_path,
-            next_id: id,
+            output_dir: self.config.path.clone(),
+            ids: self.config.ids.clone(),
             target_level,
+            target_file_size,
+            table_options: self.config.table_options.clone(),
+            snapshots: self.live_snapshots(),
+            deeper_ranges,
             block_cache: Some(Arc::clone(&self.block_cache)),
         });
     }
 
-    fn apply_compaction_success(
+    pub(crate) fn apply_compaction_success(
         &self,
-        mut sstable: SSTable<K, V>,
+        mut sstables: Vec<SSTable<K, V>>,
         level: usize,
         original_sstables: Vec<SSTable<K, V>>,
     ) -> Result<()> {
-        sstable.set_cache(Arc::clone(&self.block_cache));
+        for sst in sstables.iter_mut() {
+            sst.set_cache(Arc::clone(&self.block_cache));
+        }
         let removed_ids: HashSet<SSTableId> = original_sstables.iter().map(|s| s.id()).collect();
+        // The outputs were synced when written; their directory entries must be
+        // durable too before the edit lets the inputs go.
+        if !sstables.is_empty() {
+            sync_dir(&self.config.path)?;
+        }
 
         {
             let mut manifest = self.manifest.lock();
@@ -303,56 +512,219 @@ where
             let old_version = self.version.load();
             let mut new_levels = old_version.levels.clone();
 
+            let mut edit = VersionEdit {
+                next_id: Some(self.config.ids.peek()),
+                ..VersionEdit::default()
+            };
             for sst in &original_sstables {
                 state.compacting_ids.remove(&sst.id());
-                let mut source_level = 0;
-                for (l_idx, level_vec) in new_levels.iter().enumerate() {
-                    if level_vec.iter().any(|s| s.id() == sst.id()) {
-                        source_level = l_idx;
-                        break;
+            }
+            for sst in &original_sstables {
+                let Some(source_level) = new_levels
+                    .iter()
+                    .position(|tables| tables.iter().any(|s| s.id() == sst.id()))
+                else {
+                    // The result no longer fits the current version, so it is
+                    // dropped without an edit and its outputs are deleted.
+                    for output in &sstables {
+                        output.mark_obsolete();
                     }
-                }
+                    return Err(Error::Corruption(format!(
+                        "Compaction input {} is missing from the current version",
+                        sst.id().0
+                    )));
+                };
                 if let Some(file_name) = sst.path().file_name() {
-                    manifest.append(&ManifestEntry::RemoveSSTable {
-                        level: source_level,
-                        path: PathBuf::from(file_name),
-                    })?;
+                    edit.removed.push((source_level, PathBuf::from(file_name)));
                 }
-                let _ = std::fs::remove_file(sst.path());
             }
-
-            if let Some(new_file_name) = sstable.path().file_name() {
-                manifest.append(&ManifestEntry::AddSSTable {
-                    level,
-                    path: PathBuf::from(new_file_name),
-                })?;
+            for new_file_name in sstables.iter().filter_map(|s| s.path().file_name()) {
+                edit.added.push((level, PathBuf::from(new_file_name)));
             }
+            manifest.append(&ManifestEntry::Edit(edit))?;
             manifest.flush()?;
+            // Scans of older versions may still open the inputs; a crash before
+            // the last of them finishes leaves the files to the orphan sweep.
+            for sst in &original_sstables {
+                sst.mark_obsolete();
+            }
 
-            for l in 0..new_levels.len() {
-                new_levels[l].retain(|s| !removed_ids.contains(&s.id()));
+            for level_vec in new_levels.iter_mut() {
+                level_vec.retain(|s| !removed_ids.contains(&s.id()));
             }
             if level >= new_levels.len() {
                 new_levels.resize_with(level + 1, Vec::new);
             }
-            new_levels[level].push(sstable);
-            new_levels[level].sort_by_key(|s| s.id());
+            new_levels[level].extend(sstables);
+            sort_level(level, &mut new_levels[level]);
 
-            self.version.store(Arc::new(VersionState {
+            self.install_version(VersionState {
                 levels: new_levels,
                 immutables: old_version.immutables.clone(),
-            }));
+            });
+            self.maybe_roll_manifest(&mut manifest)?;
         }
         Ok(())
     }
 
+    /// Makes `version` the current one. Must be called with the manifest lock
+    /// held, so that installs, and the backlogs recorded for them, are ordered.
+    pub(crate) fn install_version(&self, version: VersionState<K, V>) {
+        let version = Arc::new(version);
+        self.version.store(Arc::clone(&version));
+        self.config
+            .stall
+            .record_backlog(&version, self.config.compaction_strategy.as_ref());
+    }
+
+    /// Continues in a new manifest that starts from a snapshot of the current
+    /// version once the old one has grown past `max_manifest_file_size`. Must be
+    /// called with the manifest lock held, after the version has been installed.
+    pub(crate) fn maybe_roll_manifest(&self, manifest: &mut Manifest) -> Result<()> {
+        if manifest.size() < self.config.max_manifest_file_size {
+            return Ok(());
+        }
+        let version = self.version.load();
+        manifest.roll_over(&manifest_snapshot(
+            &version.levels,
+            self.config.ids.peek(),
+            self.config.log_number.load(Ordering::Acquire),
+        ))
+    }
+
+    /// Compaction results are installed by a background thread as they arrive,
+    /// so there is nothing left for callers to do.
+    #[deprecated(note = "compaction results are installed in the background")]
+    pub fn handle_compaction_results(&self) -> Result<()> {
+        Ok(())
+    }
+
     pub fn compaction_backlog(&self) -> usize {
         let state = self.compaction_state.lock();
         state.compacting_ids.len()
     }
 
+    /// Cumulative statistics, including space reclaimed, for every installed compaction.
+    pub fn compaction_stats(&self) -> CompactionStats {
+        self.compaction_state.lock().stats
+    }
+
+    /// The last error a background compaction ran into, if any. Failed
+    /// compactions are retried, so an error here does not mean one is still failing.
+    pub fn compaction_error(&self) -> Option<Error> {
+        self.compaction_state.lock().last_error.clone()
+    }
+
+    /// What replaying the WALs on open recovered, and what it dropped as
+    /// `DBOptions::wal_recovery_mode` allowed.
+    pub fn wal_recovery_report(&self) -> &WalRecoveryReport {
+        &self.config.wal_recovery
+    }
+
+    /// Hits, misses and memory use of the block cache, for sizing it.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.block_cache.stats()
+    }
+
     pub fn total_sst_count(&self) -> usize {
         let version = self.version.load();
         version.levels.iter().map(|l| l.len()).sum()
     }
 }
+
+/// The full-state record a manifest file starts with.
+pub(crate) fn manifest_snapshot<K, V>(
+    levels: &[Vec<SSTable<K, V>>],
+    next_id: SSTableId,
+    log_number: u64,
+) -> ManifestEntry
+where
+    K: DBKey + Send + Sync + 'static,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    ManifestEntry::Snapshot {
+        levels: levels
+            .iter()
+            .map(|tables| {
+                tables
+                    .iter()
+                    .filter_map(|s| s.path().file_name().map(PathBuf::from))
+                    .collect()
+            })
+            .collect(),
+        next_id,
+        log_number,
+    }
+}
+
+/// L0 is kept in age order so that newer tables shadow older ones; merged L0
+/// tables take the place of their inputs through their newest sequence number.
+/// Deeper levels never overlap and are kept in key order for binary search.
+pub(crate) fn sort_level<K, V>(level: usize, tables: &mut [SSTable<K, V>])
+where
+    K: DBKey + Send + Sync + 'static,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    if level == 0 {
+        tables.sort_by_key(|s| (s.max_seq(), s.id()));
+    } else {
+        tables.sort_by(|a, b| a.min_key().cmp(b.min_key()));
+    }
+}
+
+/// Fails if two tables of a deeper level overlap. Lookups binary-search those
+/// levels and would miss keys in such a level, so it is refused on open rather
+/// than read wrongly.
+fn check_disjoint<K, V>(level: usize, tables: &[SSTable<K, V>]) -> Result<()>
+where
+    K: DBKey + Send + Sync + 'static,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    for pair in tables.windows(2) {
+        if pair[0].max_key() >= pair[1].min_key() {
+            return Err(Error::Corruption(format!(
+                "L{} tables {} and {} overlap",
+                level,
+                pair[0].id().0,
+                pair[1].id().0
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// The only table of a sorted, non-overlapping level that can hold `key`.
+pub(crate) fn table_for_key<'a, K, V>(
+    tables: &'a [SSTable<K, V>],
+    key: &K,
+) -> Option<&'a SSTable<K, V>>
+where
+    K: DBKey + Send + Sync + 'static,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    let idx = tables.partition_point(|s| s.max_key() < key);
+    tables.get(idx).filter(|s| s.min_key() <= key)
+}
+
+/// The tables of a sorted, non-overlapping level that intersect the bounds.
+pub(crate) fn tables_in_range<'a, K, V>(
+    tables: &'a [SSTable<K, V>],
+    lower: Bound<&K>,
+    upper: Bound<&K>,
+) -> &'a [SSTable<K, V>]
+where
+    K: DBKey + Send + Sync + 'static,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    let start = match lower {
+        Bound::Included(lo) => tables.partition_point(|s| s.max_key() < lo),
+        Bound::Excluded(lo) => tables.partition_point(|s| s.max_key() <= lo),
+        Bound::Unbounded => 0,
+    };
+    let end = match upper {
+        Bound::Included(hi) => tables.partition_point(|s| s.min_key() <= hi),
+        Bound::Excluded(hi) => tables.partition_point(|s| s.min_key() < hi),
+        Bound::Unbounded => tables.len(),
+    };
+    &tables[start..end.max(start)]
+}
@@ -0,0 +1,81 @@
+use crate::db::manifest::{CURRENT_FILE_NAME, MANIFEST_FILE_PREFIX};
+use crate::db::options::OPTIONS_FILE_NAME;
+use crate::{Error, Result};
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
+use std::path::Path;
+
+/// Subdirectory that swept files are moved into when they are quarantined.
+pub const QUARANTINE_DIR_NAME: &str = "quarantine";
+
+/// Removes what a crash can leave behind in a database directory: tables the
+/// manifest never recorded (half-written flushes and compaction outputs), WALs
+/// whose contents already reached an SSTable, manifests `CURRENT` no longer
+/// points at and the `.tmp` files of interrupted renames of `CURRENT` and
+/// `OPTIONS`. Files of any other kind are left alone.
+///
+/// Without a `live_manifest` nothing says which tables and manifests are live.
+/// Finding any then means `CURRENT` was lost, and the open is refused rather
+/// than sweeping away the whole database.
+pub(crate) fn sweep_orphans(
+    dir: &Path,
+    live_tables: &HashSet<OsString>,
+    live_manifest: Option<&OsStr>,
+    log_number: u64,
+    quarantine: bool,
+) -> Result<()> {
+    if live_manifest.is_none() {
+        for entry in std::fs::read_dir(dir)? {
+            let name = entry?.file_name();
+            if is_table(&name) || is_manifest(&name) {
+                return Err(Error::Corruption(format!(
+                    "{:?} found without a manifest to check it against; is {} missing?",
+                    name, CURRENT_FILE_NAME
+                )));
+            }
+        }
+    }
+
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let path = entry.path();
+        let name = entry.file_name();
+        let orphan = match path.extension().and_then(|s| s.to_str()) {
+            Some("sst") => !live_tables.contains(&name),
+            Some("wal") => path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse::<u64>().ok())
+                .is_some_and(|id| id < log_number),
+            Some("tmp") => name
+                .to_str()
+                .and_then(|n| n.strip_suffix(".tmp"))
+                .is_some_and(|stem| stem == CURRENT_FILE_NAME || stem == OPTIONS_FILE_NAME),
+            _ => is_manifest(&name) && Some(name.as_os_str()) != live_manifest,
+        };
+        if !orphan {
+            continue;
+        }
+
+        if quarantine {
+            let quarantine_dir = dir.join(QUARANTINE_DIR_NAME);
+            std::fs::create_dir_all(&quarantine_dir)?;
+            std::fs::rename(&path, quarantine_dir.join(entry.file_name()))?;
+        } else {
+            std::fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+fn is_table(name: &OsStr) -> bool {
+    Path::new(name).extension().is_some_and(|ext| ext == "sst")
+}
+
+fn is_manifest(name: &OsStr) -> bool {
+    name.to_str()
+        .is_some_and(|n| n.starts_with(MANIFEST_FILE_PREFIX))
+}
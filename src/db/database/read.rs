@@ -1,6 +1,10 @@
-use crate::{DB, DBKey, Result};
+use crate::db::compaction::stream::{EntryIterator, MergeStream};
+use crate::db::database::scan::LevelIterator;
+use crate::db::database::{DBIterator, table_for_key, tables_in_range};
+use crate::{DB, DBKey, PrefixKey, ReadOptions, Result, SSTableId};
 use serde::Serialize;
 use serde::de::DeserializeOwned;
+use std::ops::{Bound, RangeBounds};
 use std::sync::Arc;
 
 impl<K, V> DB<K, V>
@@ -9,8 +13,25 @@ where
     V: Serialize + DeserializeOwned + Send + Sync + 'static + std::fmt::Debug,
 {
     pub fn get(&self, key: &K) -> Result<Option<Arc<V>>> {
+        self.get_opt(key, &ReadOptions::default())
+    }
+
+    /// Like `get`; a block read from disk is cached only if
+    /// `options.fill_cache` is set.
+    pub fn get_opt(&self, key: &K, options: &ReadOptions) -> Result<Option<Arc<V>>> {
+        self.get_at(key, u64::MAX, options)
+    }
+
+    /// Reads the newest version of `key` written at or before sequence number `seq`.
+    pub(crate) fn get_at(
+        &self,
+        key: &K,
+        seq: u64,
+        options: &ReadOptions,
+    ) -> Result<Option<Arc<V>>> {
         let key_arc = Arc::new(key.clone());
-        if let Some(entry) = self.memtable.load().get_entry(&key_arc) {
+        // The MemTable is loaded before the version; see `scan`.
+        if let Some(entry) = self.memtable.load().get_entry_at(&key_arc, seq) {
             if entry.is_tombstone {
                 return Ok(None);
             }
@@ -19,7 +40,7 @@ where
 
         let version = self.version.load();
         for imm in version.immutables.iter().rev() {
-            if let Some(entry) = imm.memtable.get_entry(&key_arc) {
+            if let Some(entry) = imm.memtable.get_entry_at(&key_arc, seq) {
                 if entry.is_tombstone {
                     return Ok(None);
                 }
@@ -27,16 +48,159 @@ where
             }
         }
 
-        for level in &version.levels {
-            for sstable in level.iter().rev() {
-                if let Some(val_entry) = sstable.get(key)? {
-                    if val_entry.is_tombstone {
-                        return Ok(None);
-                    }
-                    return Ok(val_entry.value);
+        // L0 tables overlap, so each is probed newest-first; deeper levels hold
+        // at most one candidate table.
+        let (l0, deeper) = version.levels.split_first().expect("L0 always exists");
+        let candidates = l0
+            .iter()
+            .rev()
+            .chain(deeper.iter().filter_map(|level| table_for_key(level, key)));
+        for sstable in candidates {
+            if let Some(val_entry) = sstable.get_at_opt(key, seq, options)? {
+                if val_entry.is_tombstone {
+                    return Ok(None);
                 }
+                return Ok(val_entry.value);
             }
         }
         Ok(None)
     }
+
+    /// Returns an ordered iterator over every live key in the database.
+    pub fn iter(&self) -> Result<DBIterator<K, V>> {
+        self.range(..)
+    }
+
+    /// Returns an ordered iterator over the live keys within `range`.
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Result<DBIterator<K, V>> {
+        self.range_opt(range, &ReadOptions::default())
+    }
+
+    /// Like `range`. Scans that set `fill_cache` to false leave the block cache
+    /// to point lookups.
+    pub fn range_opt<R: RangeBounds<K>>(
+        &self,
+        range: R,
+        options: &ReadOptions,
+    ) -> Result<DBIterator<K, V>> {
+        self.range_at(range, u64::MAX, options)
+    }
+
+    /// Returns an ordered iterator over the live keys starting with `prefix`.
+    pub fn prefix_iter(&self, prefix: &K) -> Result<DBIterator<K, V>>
+    where
+        K: PrefixKey,
+    {
+        self.prefix_iter_opt(prefix, &ReadOptions::default())
+    }
+
+    /// Like `prefix_iter`, with the same caching choice as `range_opt`.
+    pub fn prefix_iter_opt(&self, prefix: &K, options: &ReadOptions) -> Result<DBIterator<K, V>>
+    where
+        K: PrefixKey,
+    {
+        self.prefix_iter_at(prefix, u64::MAX, options)
+    }
+
+    pub(crate) fn range_at<R: RangeBounds<K>>(
+        &self,
+        range: R,
+        seq: u64,
+        options: &ReadOptions,
+    ) -> Result<DBIterator<K, V>> {
+        let lower = range.start_bound().cloned();
+        let upper = range.end_bound().cloned();
+        let end = upper.clone();
+        let past_end = Box::new(move |key: &K| match &end {
+            Bound::Included(hi) => key > hi,
+            Bound::Excluded(hi) => key >= hi,
+            Bound::Unbounded => false,
+        });
+        self.scan(lower, upper, past_end, seq, options)
+    }
+
+    pub(crate) fn prefix_iter_at(
+        &self,
+        prefix: &K,
+        seq: u64,
+        options: &ReadOptions,
+    ) -> Result<DBIterator<K, V>>
+    where
+        K: PrefixKey,
+    {
+        let owned_prefix = prefix.clone();
+        let past_end = Box::new(move |key: &K| !key.has_prefix(&owned_prefix));
+        self.scan(
+            Bound::Included(prefix.clone()),
+            Bound::Unbounded,
+            past_end,
+            seq,
+            options,
+        )
+    }
+
+    fn scan(
+        &self,
+        lower: Bound<K>,
+        upper: Bound<K>,
+        past_end: Box<dyn Fn(&K) -> bool + Send>,
+        seq: u64,
+        options: &ReadOptions,
+    ) -> Result<DBIterator<K, V>> {
+        // Load the MemTable before the version, mirroring `get`. A switch
+        // installs the version holding the old MemTable before it swaps in the
+        // new one, so a concurrent switch can only make data visible twice
+        // rather than not at all.
+        let memtable = self.memtable.load_full();
+        let version = self.version.load_full();
+
+        // Sources are collected newest-first; their position becomes the merge priority.
+        let mut sources: Vec<EntryIterator<K, V>> = Vec::new();
+        sources.push(Box::new(
+            memtable.range_iter((lower.clone(), upper.clone())),
+        ));
+        for imm in version.immutables.iter().rev() {
+            sources.push(Box::new(
+                imm.memtable.range_iter((lower.clone(), upper.clone())),
+            ));
+        }
+        let (l0, deeper) = version.levels.split_first().expect("L0 always exists");
+        for sstable in l0.iter().rev() {
+            if sstable.overlaps_bounds(lower.as_ref(), upper.as_ref()) {
+                sources.push(Box::new(LevelIterator::new(
+                    vec![sstable.clone()],
+                    lower.clone(),
+                    *options,
+                )));
+            }
+        }
+        // A deeper level is one sorted run; its tables are opened one at a time
+        // as the scan reaches them.
+        for level in deeper {
+            let tables = tables_in_range(level, lower.as_ref(), upper.as_ref());
+            if !tables.is_empty() {
+                sources.push(Box::new(LevelIterator::new(
+                    tables.to_vec(),
+                    lower.clone(),
+                    *options,
+                )));
+            }
+        }
+
+        // Versions written after `seq` are dropped before merging so that the
+        // newest visible version wins instead of being shadowed.
+        let num_sources = sources.len() as u64;
+        let ranked = sources
+            .into_iter()
+            .enumerate()
+            .map(|(idx, iter)| {
+                let visible: EntryIterator<K, V> = Box::new(
+                    iter.filter(move |item| !matches!(item, Ok(entry) if entry.value.seq > seq)),
+                );
+                (SSTableId(num_sources - idx as u64), visible)
+            })
+            .collect();
+        let stream = MergeStream::from_iters(ranked)?;
+        Ok(DBIterator::new(stream, past_end, version))
+    }
 }
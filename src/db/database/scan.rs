@@ -0,0 +1,132 @@
+use crate::db::compaction::stream::{EntryIterator, MergeStream};
+use crate::db::database::VersionState;
+use crate::{DBKey, Entry, ReadOptions, Result, SSTable};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::VecDeque;
+use std::ops::Bound;
+use std::sync::Arc;
+
+/// An ordered iterator over live key-value pairs, produced by `DB::iter`,
+/// `DB::range` and `DB::prefix_iter`.
+///
+/// The iterator pins the `VersionState` and MemTables it was created from, so
+/// flushes and compactions that happen while it is alive do not change what it sees
+/// on disk. Tombstones are hidden and only the newest value of each key is returned.
+pub struct DBIterator<K, V>
+where
+    K: DBKey + Send + Sync + 'static,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    stream: MergeStream<K, V>,
+    past_end: Box<dyn Fn(&K) -> bool + Send>,
+    done: bool,
+    _version: Arc<VersionState<K, V>>,
+}
+
+impl<K, V> DBIterator<K, V>
+where
+    K: DBKey + Send + Sync + 'static,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    pub(crate) fn new(
+        stream: MergeStream<K, V>,
+        past_end: Box<dyn Fn(&K) -> bool + Send>,
+        version: Arc<VersionState<K, V>>,
+    ) -> Self {
+        Self {
+            stream,
+            past_end,
+            done: false,
+            _version: version,
+        }
+    }
+}
+
+impl<K, V> Iterator for DBIterator<K, V>
+where
+    K: DBKey + Send + Sync + 'static,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    type Item = Result<(Arc<K>, Arc<V>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            let entry = match self.stream.next()? {
+                Ok(entry) => entry,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            };
+            if (self.past_end)(&entry.key) {
+                self.done = true;
+                break;
+            }
+            if entry.value.is_tombstone {
+                continue;
+            }
+            if let Some(value) = entry.value.value {
+                return Some(Ok((entry.key, value)));
+            }
+        }
+        None
+    }
+}
+
+/// Iterates a run of non-overlapping tables in key order, opening each table
+/// only once the previous one is exhausted. Each table is entered at `lower`.
+pub(crate) struct LevelIterator<K, V>
+where
+    K: DBKey + Send + Sync + 'static,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    tables: VecDeque<SSTable<K, V>>,
+    current: Option<EntryIterator<K, V>>,
+    lower: Bound<K>,
+    options: ReadOptions,
+}
+
+impl<K, V> LevelIterator<K, V>
+where
+    K: DBKey + Send + Sync + 'static,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    pub(crate) fn new(tables: Vec<SSTable<K, V>>, lower: Bound<K>, options: ReadOptions) -> Self {
+        Self {
+            tables: tables.into(),
+            current: None,
+            lower,
+            options,
+        }
+    }
+
+    fn open_next(&mut self) -> Option<Result<()>> {
+        let table = self.tables.pop_front()?;
+        Some(
+            table
+                .range_opt((self.lower.clone(), Bound::Unbounded), &self.options)
+                .map(|iter| self.current = Some(Box::new(iter))),
+        )
+    }
+}
+
+impl<K, V> Iterator for LevelIterator<K, V>
+where
+    K: DBKey + Send + Sync + 'static,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    type Item = Result<Entry<K, V>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(item) = self.current.as_mut().and_then(|iter| iter.next()) {
+                return Some(item);
+            }
+            self.current = None;
+            if let Err(e) = self.open_next()? {
+                return Some(Err(e));
+            }
+        }
+    }
+}
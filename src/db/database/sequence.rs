@@ -0,0 +1,66 @@
+use parking_lot::{Condvar, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Hands out sequence numbers to write batches and makes them visible to
+/// snapshots in order, once every earlier batch has reached the MemTable.
+#[derive(Debug)]
+pub(crate) struct Sequencer {
+    /// Highest sequence number handed out to a writer.
+    last: AtomicU64,
+    /// Highest sequence number below which every write has reached the MemTable.
+    visible: AtomicU64,
+    publish: Mutex<()>,
+    published: Condvar,
+}
+
+impl Sequencer {
+    pub(crate) fn new(last: u64) -> Self {
+        Self {
+            last: AtomicU64::new(last),
+            visible: AtomicU64::new(last),
+            publish: Mutex::new(()),
+            published: Condvar::new(),
+        }
+    }
+
+    pub(crate) fn visible(&self) -> u64 {
+        self.visible.load(Ordering::Acquire)
+    }
+
+    /// Hands out the next `count` sequence numbers. They are published when the
+    /// returned reservation is dropped, whether or not the write succeeded, so
+    /// later writers are never stuck behind a failed or panicked one.
+    pub(crate) fn reserve(&self, count: u64) -> Reservation<'_> {
+        let first = self.last.fetch_add(count, Ordering::SeqCst) + 1;
+        Reservation {
+            sequencer: self,
+            first,
+            last: first + count - 1,
+        }
+    }
+}
+
+/// Sequence numbers `first..=last`, handed out to one write batch.
+pub(crate) struct Reservation<'a> {
+    sequencer: &'a Sequencer,
+    first: u64,
+    last: u64,
+}
+
+impl Reservation<'_> {
+    pub(crate) fn first(&self) -> u64 {
+        self.first
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        let sequencer = self.sequencer;
+        let mut publish = sequencer.publish.lock();
+        while sequencer.visible.load(Ordering::Acquire) != self.first - 1 {
+            sequencer.published.wait(&mut publish);
+        }
+        sequencer.visible.store(self.last, Ordering::Release);
+        sequencer.published.notify_all();
+    }
+}
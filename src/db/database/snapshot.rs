@@ -0,0 +1,114 @@
+use crate::db::database::DBIterator;
+use crate::{DB, DBKey, PrefixKey, ReadOptions, Result};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::ops::RangeBounds;
+use std::sync::Arc;
+
+/// A consistent, read-only view of the database as of one sequence number.
+///
+/// Writes made after the snapshot was taken are invisible to it, and flushes and
+/// compactions keep every version it can see until the handle is dropped.
+pub struct Snapshot<K, V>
+where
+    K: DBKey + Send + Sync + 'static + std::fmt::Debug,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static + std::fmt::Debug,
+{
+    db: DB<K, V>,
+    seq: u64,
+}
+
+impl<K, V> Snapshot<K, V>
+where
+    K: DBKey + Send + Sync + 'static + std::fmt::Debug,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static + std::fmt::Debug,
+{
+    /// The sequence number this snapshot reads at.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<Arc<V>>> {
+        self.get_opt(key, &ReadOptions::default())
+    }
+
+    /// Like `get`, with the read options of `DB::get_opt`.
+    pub fn get_opt(&self, key: &K, options: &ReadOptions) -> Result<Option<Arc<V>>> {
+        self.db.get_at(key, self.seq, options)
+    }
+
+    pub fn iter(&self) -> Result<DBIterator<K, V>> {
+        self.range(..)
+    }
+
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Result<DBIterator<K, V>> {
+        self.range_opt(range, &ReadOptions::default())
+    }
+
+    /// Like `range`, with the read options of `DB::range_opt`.
+    pub fn range_opt<R: RangeBounds<K>>(
+        &self,
+        range: R,
+        options: &ReadOptions,
+    ) -> Result<DBIterator<K, V>> {
+        self.db.range_at(range, self.seq, options)
+    }
+
+    pub fn prefix_iter(&self, prefix: &K) -> Result<DBIterator<K, V>>
+    where
+        K: PrefixKey,
+    {
+        self.prefix_iter_opt(prefix, &ReadOptions::default())
+    }
+
+    /// Like `prefix_iter`, with the read options of `DB::prefix_iter_opt`.
+    pub fn prefix_iter_opt(&self, prefix: &K, options: &ReadOptions) -> Result<DBIterator<K, V>>
+    where
+        K: PrefixKey,
+    {
+        self.db.prefix_iter_at(prefix, self.seq, options)
+    }
+}
+
+impl<K, V> Drop for Snapshot<K, V>
+where
+    K: DBKey + Send + Sync + 'static + std::fmt::Debug,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static + std::fmt::Debug,
+{
+    fn drop(&mut self) {
+        let mut snapshots = self.db.config.snapshots.lock();
+        if let Some(count) = snapshots.get_mut(&self.seq) {
+            *count -= 1;
+            if *count == 0 {
+                snapshots.remove(&self.seq);
+            }
+        }
+    }
+}
+
+impl<K, V> DB<K, V>
+where
+    K: DBKey + Send + Sync + 'static + std::fmt::Debug,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static + std::fmt::Debug,
+{
+    /// Captures a point-in-time view of the database.
+    pub fn snapshot(&self) -> Snapshot<K, V> {
+        let mut snapshots = self.config.snapshots.lock();
+        let seq = self.config.sequence.visible();
+        *snapshots.entry(seq).or_insert(0) += 1;
+        Snapshot {
+            db: self.clone(),
+            seq,
+        }
+    }
+
+    /// Sequence numbers whose versions flushes and compactions must preserve.
+    /// The current visible sequence is included so that a snapshot taken while a
+    /// compaction is running still finds the versions it needs.
+    pub(crate) fn live_snapshots(&self) -> Vec<u64> {
+        let snapshots = self.config.snapshots.lock();
+        let mut seqs: Vec<u64> = snapshots.keys().copied().collect();
+        seqs.push(self.config.sequence.visible());
+        seqs
+    }
+}
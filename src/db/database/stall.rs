@@ -0,0 +1,148 @@
+use crate::db::compaction::strategy::CompactionStrategy;
+use crate::db::database::{DB, VersionState};
+use crate::{DBKey, Result};
+use parking_lot::{Condvar, Mutex};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// How long a stopped writer waits for a flush or compaction before it checks
+/// the backlog again.
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How much writes were held back by write stalls, from `DB::stall_stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StallStats {
+    /// Write batches delayed by a slowdown trigger.
+    pub slowed_writes: u64,
+    /// Write batches that waited at a stop trigger.
+    pub stopped_writes: u64,
+    pub slowdown_time: Duration,
+    pub stop_time: Duration,
+}
+
+impl StallStats {
+    /// Time writers spent held back, slowed down or stopped.
+    pub fn total_delay(&self) -> Duration {
+        self.slowdown_time + self.stop_time
+    }
+}
+
+/// Stall counters, the backlog of the current version, and the signal stopped
+/// writers wait on.
+#[derive(Debug, Default)]
+pub(crate) struct StallState {
+    stats: Mutex<StallStats>,
+    /// Worked out once per installed version rather than on every write.
+    immutables: AtomicUsize,
+    l0_backlog: AtomicUsize,
+    pending_compaction_bytes: AtomicU64,
+    progress: Mutex<()>,
+    changed: Condvar,
+}
+
+impl StallState {
+    /// Records the backlog of a newly installed version and wakes stopped
+    /// writers to check it.
+    pub(crate) fn record_backlog<K, V>(
+        &self,
+        version: &VersionState<K, V>,
+        strategy: &dyn CompactionStrategy<K, V>,
+    ) where
+        K: DBKey + Send + Sync + 'static,
+        V: Serialize + DeserializeOwned + Send + Sync + 'static,
+    {
+        self.immutables
+            .store(version.immutables.len(), Ordering::Release);
+        self.l0_backlog
+            .store(strategy.l0_backlog(&version.levels), Ordering::Release);
+        self.pending_compaction_bytes.store(
+            strategy.pending_compaction_bytes(&version.levels),
+            Ordering::Release,
+        );
+        self.notify();
+    }
+
+    /// Wakes stopped writers; called whenever a version is installed, and on
+    /// close.
+    pub(crate) fn notify(&self) {
+        let _progress = self.progress.lock();
+        self.changed.notify_all();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stall {
+    None,
+    Slowdown,
+    Stop,
+}
+
+impl<K, V> DB<K, V>
+where
+    K: DBKey + Send + Sync + 'static + std::fmt::Debug,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static + std::fmt::Debug,
+{
+    /// Delays or blocks a writer while flushes or compactions lag behind.
+    pub(crate) fn stall_writes(&self) -> Result<()> {
+        match self.stall_condition() {
+            Stall::None => Ok(()),
+            Stall::Slowdown => {
+                let delay = self.config.write_stall.slowdown_delay;
+                std::thread::sleep(delay);
+                let mut stats = self.config.stall.stats.lock();
+                stats.slowed_writes += 1;
+                stats.slowdown_time += delay;
+                Ok(())
+            }
+            Stall::Stop => {
+                let start = Instant::now();
+                let result = self.wait_for_backlog();
+                let mut stats = self.config.stall.stats.lock();
+                stats.stopped_writes += 1;
+                stats.stop_time += start.elapsed();
+                result
+            }
+        }
+    }
+
+    /// Cumulative write stall statistics since open.
+    pub fn stall_stats(&self) -> StallStats {
+        *self.config.stall.stats.lock()
+    }
+
+    fn wait_for_backlog(&self) -> Result<()> {
+        while self.stall_condition() == Stall::Stop {
+            self.ensure_open()?;
+            let mut progress = self.config.stall.progress.lock();
+            self.config
+                .stall
+                .changed
+                .wait_for(&mut progress, STOP_POLL_INTERVAL);
+        }
+        Ok(())
+    }
+
+    fn stall_condition(&self) -> Stall {
+        let options = &self.config.write_stall;
+        let stall = &self.config.stall;
+        let immutables = stall.immutables.load(Ordering::Acquire);
+        let l0 = stall.l0_backlog.load(Ordering::Acquire);
+        let pending_bytes = stall.pending_compaction_bytes.load(Ordering::Acquire);
+
+        if immutables >= options.immutables_stop_trigger
+            || l0 >= options.l0_stop_trigger
+            || pending_bytes >= options.pending_compaction_bytes_stop
+        {
+            Stall::Stop
+        } else if immutables >= options.immutables_slowdown_trigger
+            || l0 >= options.l0_slowdown_trigger
+            || pending_bytes >= options.pending_compaction_bytes_slowdown
+        {
+            Stall::Slowdown
+        } else {
+            Stall::None
+        }
+    }
+}
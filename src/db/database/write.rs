@@ -1,9 +1,8 @@
 use crate::db::database::DB;
-use crate::{DBKey, LogEntry, Result, WriteBatch};
+use crate::{DBKey, Error, LogEntry, Result, WriteBatch, WriteOptions};
 use serde::Serialize;
 use serde::de::DeserializeOwned;
 use std::sync::Arc;
-use std::sync::atomic::Ordering;
 
 impl<K, V> DB<K, V>
 where
@@ -11,63 +10,83 @@ where
     V: Serialize + DeserializeOwned + Send + Sync + 'static + std::fmt::Debug,
 {
     pub fn put(&self, key: K, value: V) -> Result<()> {
+        self.put_opt(key, value, &WriteOptions::default())
+    }
+
+    pub fn put_opt(&self, key: K, value: V, options: &WriteOptions) -> Result<()> {
         let mut batch = WriteBatch::new();
         batch.put(key, value);
-        self.write_batch(batch)
+        self.write_batch_opt(batch, options)
     }
 
     pub fn delete(&self, key: K) -> Result<()> {
+        self.delete_opt(key, &WriteOptions::default())
+    }
+
+    pub fn delete_opt(&self, key: K, options: &WriteOptions) -> Result<()> {
         let mut batch = WriteBatch::new();
         batch.delete(key);
-        self.write_batch(batch)
+        self.write_batch_opt(batch, options)
     }
 
     pub fn write_batch(&self, batch: WriteBatch<K, V>) -> Result<()> {
+        self.write_batch_opt(batch, &WriteOptions::default())
+    }
+
+    pub fn write_batch_opt(&self, batch: WriteBatch<K, V>, options: &WriteOptions) -> Result<()> {
         if batch.is_empty() {
             return Ok(());
         }
-        self.handle_compaction_results()?;
+        self.ensure_open()?;
+        self.take_flush_error()?;
+        self.stall_writes()?;
+
+        // No switch can happen until the batch is in the MemTable, so a batch
+        // always lands in a MemTable no older than those of earlier batches,
+        // and in the one its WAL belongs to.
+        let switch = self.switch_lock.read();
+        // Published on drop, after the batch reached the MemTable or failed.
+        let sequence = self.config.sequence.reserve(batch.entries.len() as u64);
 
         let mut log_entries = Vec::with_capacity(batch.entries.len());
-        let mut total_batch_size = 0;
-        for entry in batch.entries {
-            let key_size = std::mem::size_of_val(&*entry.key);
-            let val_size = entry
-                .value
-                .value
-                .as_ref()
-                .map_or(0, |v| std::mem::size_of_val(&**v));
-            total_batch_size += key_size + val_size;
-            let log_entry = if entry.value.is_tombstone {
-                LogEntry::Delete(entry.key)
-            } else {
-                LogEntry::Put(entry.key, entry.value.value.expect("Value missing"))
+        for (seq, entry) in (sequence.first()..).zip(batch.entries) {
+            let log_entry = match entry.value.value {
+                Some(value) if !entry.value.is_tombstone => LogEntry::Put(entry.key, value, seq),
+                _ => LogEntry::Delete(entry.key, seq),
             };
             log_entries.push(log_entry);
         }
 
         let entries_arc = Arc::new(log_entries);
+        let memtable = self.memtable.load();
 
         // Group Commit via WalManager (Zero-copy send)
-        self.wal.submit(Arc::clone(&entries_arc))?;
-
-        let memtable = self.memtable.load();
-        for entry in entries_arc.iter() {
-            match entry {
-                LogEntry::Put(k, v) => memtable.put(Arc::clone(k), Arc::clone(v)),
-                LogEntry::Delete(k) => memtable.delete(Arc::clone(k)),
+        let result = if options.disable_wal {
+            bincode::serialized_size(&*entries_arc).map_err(|e| Error::Serialization(e.to_string()))
+        } else {
+            self.wal
+                .submit_with_sync(Arc::clone(&entries_arc), options.sync)
+        };
+        if let Ok(encoded_size) = result {
+            if options.disable_wal {
+                memtable.mark_unlogged();
             }
+            memtable.apply(&entries_arc, encoded_size as usize);
         }
+        drop(sequence);
+        drop(switch);
+        result?;
 
-        if self
-            .config
-            .memtable_size
-            .fetch_add(total_batch_size, Ordering::Relaxed)
-            + total_batch_size
-            >= self.config.max_memtable_size
-        {
-            self.switch_memtable()?;
+        if memtable.size_bytes() >= self.config.max_memtable_size {
+            self.switch_memtable(false)?;
         }
         Ok(())
     }
+
+    /// Syncs every write made so far to disk, including those made without
+    /// `WriteOptions::sync`.
+    pub fn sync_wal(&self) -> Result<()> {
+        self.ensure_open()?;
+        self.wal.sync()
+    }
 }
@@ -1,23 +1,39 @@
 use crate::{Error, Result};
 use crc32fast::Hasher;
 use serde::{Serialize, de::DeserializeOwned};
+use std::fs::File;
 use std::io::{Read, Write};
+use std::path::Path;
 use std::sync::Arc;
 
+/// Largest record a reader accepts; a longer length can only be damage.
+pub(crate) const MAX_RECORD_SIZE: usize = 64 * 1024 * 1024; // 64 MB
+
+/// Syncs the entries of `dir`, so that files created or renamed in it survive
+/// a power loss.
+pub(crate) fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
 /// Writes a data-frame to the writer: [Checksum (4), Length (8), Data]
 /// Returns the total number of bytes written.
 pub fn write_record<W: Write, T: Serialize>(writer: &mut W, data: &T) -> Result<u64> {
     let serialized_data =
         bincode::serialize(data).map_err(|e| Error::Serialization(e.to_string()))?;
+    write_raw_record(writer, &serialized_data)
+}
 
-    let len = serialized_data.len() as u64;
+/// Writes already encoded bytes as a data-frame; see `write_record`.
+pub fn write_raw_record<W: Write>(writer: &mut W, data: &[u8]) -> Result<u64> {
+    let len = data.len() as u64;
     let mut hasher = Hasher::new();
-    hasher.update(&serialized_data);
+    hasher.update(data);
     let checksum = hasher.finalize();
 
     writer.write_all(&checksum.to_le_bytes())?;
     writer.write_all(&len.to_le_bytes())?;
-    writer.write_all(&serialized_data)?;
+    writer.write_all(data)?;
 
     Ok(4 + 8 + len)
 }
@@ -25,6 +41,18 @@ pub fn write_record<W: Write, T: Serialize>(writer: &mut W, data: &T) -> Result<
 /// Reads a data-frame from the reader.
 /// Returns Ok(None) on clean EOF at the start of a record.
 pub fn read_record<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<Option<T>> {
+    match read_raw_record(reader)? {
+        Some(data_bytes) => {
+            let data = bincode::deserialize(&data_bytes)
+                .map_err(|e| Error::Serialization(e.to_string()))?;
+            Ok(Some(data))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Reads the checksummed bytes of a data-frame without decoding them.
+pub fn read_raw_record<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>> {
     let mut checksum_bytes = [0u8; 4];
     if let Err(e) = reader.read_exact(&mut checksum_bytes) {
         if e.kind() == std::io::ErrorKind::UnexpectedEof {
@@ -44,7 +72,6 @@ pub fn read_record<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<Optio
     })?;
     let len = u64::from_le_bytes(len_bytes) as usize;
 
-    const MAX_RECORD_SIZE: usize = 64 * 1024 * 1024; // 64 MB
     if len > MAX_RECORD_SIZE {
         return Err(Error::Corruption(format!(
             "Record size {} exceeds maximum of {}",
@@ -67,8 +94,5 @@ pub fn read_record<R: Read, T: DeserializeOwned>(reader: &mut R) -> Result<Optio
         return Err(Error::Corruption("Record checksum mismatch".to_string()));
     }
 
-    let data: T =
-        bincode::deserialize(&data_bytes).map_err(|e| Error::Serialization(e.to_string()))?;
-
-    Ok(Some(data))
+    Ok(Some(data_bytes))
 }
@@ -1,48 +1,87 @@
-use crate::db::io::{read_record, write_record};
-use crate::{ManifestEntry, Result};
+use crate::db::io::{read_record, sync_dir, write_record};
+use crate::{Error, ManifestEntry, Result};
 use std::fs::{File, OpenOptions};
-use std::io::{BufReader, BufWriter, Write};
-use std::path::PathBuf;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
 
+/// Names the manifest file in use; replaced through a rename on every rollover.
+pub const CURRENT_FILE_NAME: &str = "CURRENT";
+/// Prefix of numbered manifest files, e.g. `MANIFEST-000003`.
+pub const MANIFEST_FILE_PREFIX: &str = "MANIFEST-";
+/// The single, never rolled over manifest written by older versions.
+pub(crate) const LEGACY_MANIFEST_FILE_NAME: &str = "MANIFEST";
+
+/// The log of changes to the set of live SSTables. Every manifest file starts
+/// with a `Snapshot` of the full state and is followed by the edits made since,
+/// so once it grows too large it is replaced by a new file holding a fresh snapshot.
 #[derive(Debug)]
 pub struct Manifest {
-    path: PathBuf,
+    dir: PathBuf,
+    number: u64,
     writer: BufWriter<File>,
+    size: u64,
 }
 
 impl Manifest {
-    pub fn create(path: PathBuf) -> Result<Self> {
+    /// Returns the manifest `CURRENT` points at, or the legacy `MANIFEST` of a
+    /// database that was never rolled over.
+    pub fn current(dir: &Path) -> Result<Option<PathBuf>> {
+        let current_path = dir.join(CURRENT_FILE_NAME);
+        if current_path.exists() {
+            let name = std::fs::read_to_string(current_path)?;
+            let name = name.trim_end();
+            if !name.starts_with(MANIFEST_FILE_PREFIX) {
+                return Err(Error::Corruption(format!(
+                    "CURRENT names {:?}, which is not a manifest",
+                    name
+                )));
+            }
+            return Ok(Some(dir.join(name)));
+        }
+        let legacy = dir.join(LEGACY_MANIFEST_FILE_NAME);
+        Ok(legacy.exists().then_some(legacy))
+    }
+
+    /// Reads the records of the manifest file at `path`.
+    pub fn read(path: &Path) -> Result<ManifestIterator> {
+        let file = OpenOptions::new().read(true).open(path)?;
+        Ok(ManifestIterator {
+            reader: BufReader::new(file),
+        })
+    }
+
+    /// Writes `snapshot` to a new manifest numbered `number` and points
+    /// `CURRENT` at it. The previous manifest stays valid until the swap.
+    pub fn create(dir: &Path, number: u64, snapshot: &ManifestEntry) -> Result<Self> {
+        let name = manifest_file_name(number);
         let file = OpenOptions::new()
             .create(true)
             .write(true)
             .truncate(true)
-            .open(&path)?;
-        Ok(Manifest {
-            path,
+            .open(dir.join(&name))?;
+        let mut manifest = Manifest {
+            dir: dir.to_path_buf(),
+            number,
             writer: BufWriter::new(file),
-        })
+            size: 0,
+        };
+        manifest.append(snapshot)?;
+        manifest.flush()?;
+        set_current(dir, &name)?;
+        Ok(manifest)
     }
 
-    pub fn open(path: PathBuf) -> Result<Self> {
-        let file = OpenOptions::new().write(true).append(true).open(&path)?;
-        Ok(Manifest {
-            path,
-            writer: BufWriter::new(file),
-        })
-    }
-
-    pub fn append(&mut self, entry: &ManifestEntry) -> Result<()> {
-        write_record(&mut self.writer, entry)?;
+    /// Continues in a new manifest that starts from `snapshot`, then deletes the
+    /// old one.
+    pub fn roll_over(&mut self, snapshot: &ManifestEntry) -> Result<()> {
+        let old_path = self.path();
+        *self = Self::create(&self.dir, self.number + 1, snapshot)?;
+        std::fs::remove_file(old_path)?;
         Ok(())
     }
 
-    pub fn clear(&mut self) -> Result<()> {
-        let file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&self.path)?;
-        self.writer = BufWriter::new(file);
+    pub fn append(&mut self, entry: &ManifestEntry) -> Result<()> {
+        self.size += write_record(&mut self.writer, entry)?;
         Ok(())
     }
 
@@ -52,22 +91,65 @@ impl Manifest {
         Ok(())
     }
 
-    pub fn iter(&self) -> Result<ManifestIterator> {
-        let file = OpenOptions::new().read(true).open(&self.path)?;
-        Ok(ManifestIterator {
-            reader: BufReader::new(file),
-        })
+    pub fn path(&self) -> PathBuf {
+        self.dir.join(manifest_file_name(self.number))
+    }
+
+    /// Bytes written to this manifest file, snapshot included.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+pub(crate) fn manifest_file_name(number: u64) -> String {
+    format!("{}{:06}", MANIFEST_FILE_PREFIX, number)
+}
+
+/// Parses the number out of a `MANIFEST-000003` style path; the legacy
+/// manifest counts as number 0.
+pub(crate) fn manifest_number(path: &Path) -> Option<u64> {
+    let name = path.file_name()?.to_str()?;
+    if name == LEGACY_MANIFEST_FILE_NAME {
+        return Some(0);
     }
+    name.strip_prefix(MANIFEST_FILE_PREFIX)?.parse().ok()
+}
+
+/// Replaces `CURRENT` through a rename so that it always names a complete manifest.
+fn set_current(dir: &Path, manifest_name: &str) -> Result<()> {
+    let tmp_path = dir.join(format!("{}.tmp", CURRENT_FILE_NAME));
+    {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        writeln!(file, "{}", manifest_name)?;
+        file.sync_all()?;
+    }
+    std::fs::rename(tmp_path, dir.join(CURRENT_FILE_NAME))?;
+    sync_dir(dir)
 }
 
 pub struct ManifestIterator {
     reader: BufReader<File>,
 }
 
+impl ManifestIterator {
+    fn at_end(&mut self) -> bool {
+        self.reader.fill_buf().is_ok_and(|buf| buf.is_empty())
+    }
+}
+
 impl Iterator for ManifestIterator {
     type Item = std::result::Result<ManifestEntry, crate::Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        read_record(&mut self.reader).transpose()
+        match read_record(&mut self.reader) {
+            // A crash while appending leaves a torn last frame; the edit it held
+            // never took effect. Damage anywhere before the end is still an error.
+            Err(Error::Corruption(_)) if self.at_end() => None,
+            result => result.transpose(),
+        }
     }
 }
@@ -1,76 +1,263 @@
-use crate::{DBKey, ValueEntry};
+use crate::{DBKey, Entry, LogEntry, Result, ValueEntry};
 use crossbeam_skiplist::SkipMap;
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::ops::{Bound, RangeBounds};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+
+/// The MemTable's internal key: user key ascending, then sequence number descending,
+/// so the newest version of a key is always the first one encountered.
+#[derive(Debug, Clone)]
+pub struct VersionedKey<K> {
+    pub key: Arc<K>,
+    pub seq: u64,
+}
+
+impl<K: Ord> PartialEq for VersionedKey<K> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<K: Ord> Eq for VersionedKey<K> {}
+
+impl<K: Ord> PartialOrd for VersionedKey<K> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<K: Ord> Ord for VersionedKey<K> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key
+            .cmp(&other.key)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
 
 /// A lock-free concurrent MemTable using a SkipList.
 /// High throughput for multi-threaded writes without mutex contention.
+/// Every version of a key is kept so that snapshots can read past newer writes.
 #[derive(Debug)]
 pub struct MemTable<K, V>
 where
     K: DBKey,
 {
-    map: SkipMap<Arc<K>, ValueEntry<V>>,
+    map: SkipMap<VersionedKey<K>, ValueEntry<V>>,
+    /// Approximate memory held by the entries; see `size_bytes`.
+    size: AtomicUsize,
+    /// Whether it holds writes that skipped the WAL, which only a flush saves.
+    unlogged: AtomicBool,
+}
+
+impl<K, V> Default for MemTable<K, V>
+where
+    K: DBKey + Send + Sync + 'static,
+    V: Serialize + Send + Sync + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<K, V> MemTable<K, V>
 where
     K: DBKey + Send + Sync + 'static,
-    V: Send + Sync + 'static,
+    V: Serialize + Send + Sync + 'static,
 {
     pub fn new() -> Self {
         Self {
             map: SkipMap::new(),
+            size: AtomicUsize::new(0),
+            unlogged: AtomicBool::new(false),
         }
     }
 
+    /// Inserts an unsequenced put. Repeated unsequenced writes replace each other.
     pub fn put(&self, key: Arc<K>, value: Arc<V>) {
-        let entry = ValueEntry {
-            value: Some(value),
-            is_tombstone: false,
-        };
-        self.map.insert(key, entry);
+        self.insert(
+            key,
+            ValueEntry {
+                value: Some(value),
+                is_tombstone: false,
+                seq: 0,
+            },
+        );
     }
 
+    /// Inserts an unsequenced tombstone.
     pub fn delete(&self, key: Arc<K>) {
-        let entry = ValueEntry {
-            value: None,
-            is_tombstone: true,
-        };
-        self.map.insert(key, entry);
+        self.insert(
+            key,
+            ValueEntry {
+                value: None,
+                is_tombstone: true,
+                seq: 0,
+            },
+        );
+    }
+
+    /// Inserts a version of `key` at the sequence number carried by `entry`.
+    pub fn insert(&self, key: Arc<K>, entry: ValueEntry<V>) {
+        let size = encoded_size(&key, &entry) + ENTRY_OVERHEAD;
+        self.insert_sized(key, entry, size);
+    }
+
+    fn insert_sized(&self, key: Arc<K>, entry: ValueEntry<V>, size: usize) {
+        let seq = entry.seq;
+        self.map.insert(VersionedKey { key, seq }, entry);
+        self.size
+            .fetch_add(size, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Records that writes about to be applied were not written to a WAL.
+    pub fn mark_unlogged(&self) {
+        self.unlogged
+            .store(true, std::sync::atomic::Ordering::Release);
+    }
+
+    /// Whether any write in the MemTable is missing from the WALs.
+    pub fn has_unlogged_writes(&self) -> bool {
+        self.unlogged.load(std::sync::atomic::Ordering::Acquire)
+    }
+
+    /// Approximate bytes held by the MemTable: the encoded size of every key and
+    /// value plus the fixed cost of an entry. Replaced unsequenced writes are
+    /// still counted, so this errs high.
+    pub fn size_bytes(&self) -> usize {
+        self.size.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Applies the sequenced entries of one write batch. `encoded_size` is the
+    /// size of the batch's WAL payload, which stands in for the encoded keys and
+    /// values so they are not encoded a second time.
+    pub fn apply(&self, entries: &[LogEntry<K, V>], encoded_size: usize) {
+        for entry in entries {
+            let (key, value) = match entry {
+                LogEntry::Put(k, v, seq) => (
+                    k,
+                    ValueEntry {
+                        value: Some(Arc::clone(v)),
+                        is_tombstone: false,
+                        seq: *seq,
+                    },
+                ),
+                LogEntry::Delete(k, seq) => (
+                    k,
+                    ValueEntry {
+                        value: None,
+                        is_tombstone: true,
+                        seq: *seq,
+                    },
+                ),
+            };
+            self.insert_sized(Arc::clone(key), value, ENTRY_OVERHEAD);
+        }
+        self.size
+            .fetch_add(encoded_size, std::sync::atomic::Ordering::Relaxed);
     }
 
     pub fn get(&self, key: &Arc<K>) -> Option<Arc<V>> {
-        self.map
-            .get(key)
-            .filter(|entry| !entry.value().is_tombstone)
-            .and_then(|entry| entry.value().value.clone())
+        self.get_entry(key)
+            .filter(|entry| !entry.is_tombstone)
+            .and_then(|entry| entry.value)
     }
 
+    /// Returns the newest version of `key`, including tombstones.
     pub fn get_entry(&self, key: &Arc<K>) -> Option<ValueEntry<V>> {
-        self.map.get(key).map(|entry| entry.value().clone())
+        self.get_entry_at(key, u64::MAX)
     }
 
-    pub fn len(&self) -> usize {
+    /// Returns the newest version of `key` whose sequence number is at most `seq`.
+    pub fn get_entry_at(&self, key: &Arc<K>, seq: u64) -> Option<ValueEntry<V>> {
+        let probe = VersionedKey {
+            key: Arc::clone(key),
+            seq,
+        };
         self.map
-            .iter()
-            .filter(|entry| !entry.value().is_tombstone)
-            .count()
+            .lower_bound(Bound::Included(&probe))
+            .filter(|entry| entry.key().key == *key)
+            .map(|entry| entry.value().clone())
+    }
+
+    /// Returns the number of keys whose newest version is not a tombstone.
+    pub fn len(&self) -> usize {
+        let mut count = 0;
+        let mut last_key: Option<Arc<K>> = None;
+        for entry in self.map.iter() {
+            if last_key.as_ref() == Some(&entry.key().key) {
+                continue;
+            }
+            last_key = Some(Arc::clone(&entry.key().key));
+            if !entry.value().is_tombstone {
+                count += 1;
+            }
+        }
+        count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
     }
 
     pub fn clear(&self) {
         self.map.clear();
+        self.size.store(0, std::sync::atomic::Ordering::Relaxed);
     }
 
-    /// Returns a lock-free sorted iterator over the MemTable.
+    /// Returns a lock-free sorted iterator over every version in the MemTable.
     pub fn iter(&self) -> SkipMapIterator<'_, K, V> {
         SkipMapIterator {
             iter: self.map.iter(),
         }
     }
+
+    /// Returns an owning sorted iterator over `range` that keeps the MemTable alive.
+    /// Each step re-seeks past the last yielded version, so concurrent inserts are tolerated.
+    pub fn range_iter<R: RangeBounds<K>>(
+        self: &Arc<Self>,
+        range: R,
+    ) -> MemTableRangeIterator<K, V> {
+        let next = match range.start_bound() {
+            Bound::Included(lo) => Bound::Included(VersionedKey {
+                key: Arc::new(lo.clone()),
+                seq: u64::MAX,
+            }),
+            Bound::Excluded(lo) => Bound::Excluded(VersionedKey {
+                key: Arc::new(lo.clone()),
+                seq: 0,
+            }),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        MemTableRangeIterator {
+            memtable: Arc::clone(self),
+            next,
+            upper: range.end_bound().cloned(),
+        }
+    }
+}
+
+/// Fixed memory an entry takes up in the MemTable besides its key and value:
+/// the skip list node holds the versioned key, the value entry and a few tower
+/// pointers.
+const ENTRY_OVERHEAD: usize = std::mem::size_of::<VersionedKey<()>>()
+    + std::mem::size_of::<ValueEntry<()>>()
+    + 4 * std::mem::size_of::<usize>();
+
+/// Bincode encoding of an entry's key and value, which unlike `size_of`
+/// includes heap payloads.
+fn encoded_size<K: Serialize, V: Serialize>(key: &K, entry: &ValueEntry<V>) -> usize {
+    let key_size = bincode::serialized_size(key).unwrap_or(0);
+    let value_size = entry
+        .value
+        .as_deref()
+        .map_or(0, |value| bincode::serialized_size(value).unwrap_or(0));
+    (key_size + value_size) as usize
 }
 
 pub struct SkipMapIterator<'a, K, V> {
-    iter: crossbeam_skiplist::map::Iter<'a, Arc<K>, ValueEntry<V>>,
+    iter: crossbeam_skiplist::map::Iter<'a, VersionedKey<K>, ValueEntry<V>>,
 }
 
 impl<'a, K, V> Iterator for SkipMapIterator<'a, K, V>
@@ -82,6 +269,42 @@ where
     fn next(&mut self) -> Option<Self::Item> {
         self.iter
             .next()
-            .map(|entry| (Arc::clone(entry.key()), entry.value().clone()))
+            .map(|entry| (Arc::clone(&entry.key().key), entry.value().clone()))
+    }
+}
+
+pub struct MemTableRangeIterator<K, V>
+where
+    K: DBKey,
+{
+    memtable: Arc<MemTable<K, V>>,
+    next: Bound<VersionedKey<K>>,
+    upper: Bound<K>,
+}
+
+impl<K, V> Iterator for MemTableRangeIterator<K, V>
+where
+    K: DBKey + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    type Item = Result<Entry<K, V>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.memtable.map.lower_bound(self.next.as_ref())?;
+        let key = &entry.key().key;
+        let in_range = match &self.upper {
+            Bound::Included(hi) => key.as_ref() <= hi,
+            Bound::Excluded(hi) => key.as_ref() < hi,
+            Bound::Unbounded => true,
+        };
+        if !in_range {
+            return None;
+        }
+
+        self.next = Bound::Excluded(entry.key().clone());
+        Some(Ok(Entry {
+            key: Arc::clone(key),
+            value: entry.value().clone(),
+        }))
     }
 }
@@ -4,15 +4,18 @@ pub mod database;
 pub mod io;
 pub mod manifest;
 pub mod memtable;
+pub mod options;
 pub mod sstable;
 pub mod wal;
 
-pub use cache::BlockCache;
+pub use cache::{BlockCache, CacheStats};
+pub use compaction::strategy::*;
 pub use compaction::stream::*;
 pub use compaction::*;
 pub use database::*;
 pub use manifest::*;
 pub use memtable::*;
+pub use options::*;
 pub use sstable::filter::FilterVariant;
 pub use sstable::*;
 pub use wal::*;
@@ -0,0 +1,431 @@
+use crate::db::io::{read_record, write_record};
+use crate::db::sstable::datablock::{BLOCK_SIZE, RESTART_INTERVAL};
+use crate::{
+    BlockCache, CompactionStrategy, Compression, DBKey, Error, FILTER_TYPE_XOR8, FILTER_TYPE_XOR16,
+    LeveledCompaction, Result, WalRecoveryMode,
+};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub(crate) const OPTIONS_FILE_NAME: &str = "OPTIONS";
+
+/// Default capacity of the block cache (32 MiB).
+pub const DEFAULT_BLOCK_CACHE_SIZE: u64 = 32 * 1024 * 1024;
+/// Default MemTable size at which it is flushed (4 MiB).
+pub const DEFAULT_MAX_MEMTABLE_SIZE: usize = 4 * 1024 * 1024;
+/// Default manifest size at which it is rolled over into a new file (4 MiB).
+pub const DEFAULT_MAX_MANIFEST_FILE_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Which XOR filter a new SSTable carries.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FilterPolicy {
+    /// Xor8 for L0, which is rewritten soon, and the more precise Xor16 deeper down.
+    #[default]
+    ByLevel,
+    Xor8,
+    Xor16,
+}
+
+impl FilterPolicy {
+    pub fn filter_type(&self, level: usize) -> u8 {
+        match self {
+            FilterPolicy::ByLevel if level > 0 => FILTER_TYPE_XOR16,
+            FilterPolicy::ByLevel | FilterPolicy::Xor8 => FILTER_TYPE_XOR8,
+            FilterPolicy::Xor16 => FILTER_TYPE_XOR16,
+        }
+    }
+}
+
+/// How SSTables are laid out when they are written. Every table records enough
+/// about itself to be read back, so these can change between opens.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TableOptions {
+    /// Target size of a data block in bytes.
+    pub block_size: usize,
+    /// Number of entries between restart points within a data block.
+    pub restart_interval: usize,
+    pub filter_policy: FilterPolicy,
+    /// Block compression of each level; levels past the end use the last entry.
+    pub compression_per_level: Vec<Compression>,
+    /// Size of the zstd dictionary trained for each zstd-compressed table, or 0
+    /// to compress without one.
+    pub zstd_dictionary_size: usize,
+}
+
+impl Default for TableOptions {
+    fn default() -> Self {
+        Self {
+            block_size: BLOCK_SIZE,
+            restart_interval: RESTART_INTERVAL,
+            filter_policy: FilterPolicy::ByLevel,
+            compression_per_level: vec![Compression::None],
+            zstd_dictionary_size: 0,
+        }
+    }
+}
+
+impl TableOptions {
+    pub fn compression(&self, level: usize) -> Compression {
+        self.compression_per_level
+            .get(level)
+            .or(self.compression_per_level.last())
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+/// When writes are held back so that flushes and compactions can catch up.
+/// Past a slowdown trigger every write batch is delayed by `slowdown_delay`;
+/// past a stop trigger writes wait until the backlog drops below it again.
+/// Each slowdown trigger must be non-zero and at most its stop trigger.
+///
+/// The L0 and pending bytes triggers only apply to `LeveledCompaction`.
+/// `SizeTieredCompaction` and `FifoCompaction` report no such backlog, so with
+/// them writes are only held back by unflushed immutable MemTables.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WriteStallOptions {
+    /// Immutable MemTables waiting to be flushed.
+    pub immutables_slowdown_trigger: usize,
+    pub immutables_stop_trigger: usize,
+    /// L0 tables waiting to be compacted, as counted by the compaction strategy.
+    pub l0_slowdown_trigger: usize,
+    pub l0_stop_trigger: usize,
+    /// Bytes the compaction strategy estimates it still has to compact.
+    pub pending_compaction_bytes_slowdown: u64,
+    pub pending_compaction_bytes_stop: u64,
+    pub slowdown_delay: Duration,
+}
+
+impl Default for WriteStallOptions {
+    fn default() -> Self {
+        Self {
+            immutables_slowdown_trigger: 3,
+            immutables_stop_trigger: 5,
+            l0_slowdown_trigger: 20,
+            l0_stop_trigger: 36,
+            pending_compaction_bytes_slowdown: 64 * 1024 * 1024 * 1024,
+            pending_compaction_bytes_stop: 256 * 1024 * 1024 * 1024,
+            slowdown_delay: Duration::from_millis(1),
+        }
+    }
+}
+
+/// Settings for a single read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadOptions {
+    /// Whether blocks read from disk are added to the block cache. Turn it off
+    /// for large one-off scans so they don't evict the blocks of hot keys.
+    pub fill_cache: bool,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        Self { fill_cache: true }
+    }
+}
+
+/// Settings for a single write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteOptions {
+    /// Whether the write returns only once the WAL is synced to disk. Without
+    /// it the write survives a crash of the process but not of the machine,
+    /// until the next sync: a synced write, `DB::sync_wal`, or the periodic
+    /// sync set up with `DBOptions::wal_sync_interval`.
+    pub sync: bool,
+    /// Skips the WAL altogether. The write is lost on a crash unless its
+    /// MemTable was flushed first.
+    pub disable_wal: bool,
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        Self {
+            sync: true,
+            disable_wal: false,
+        }
+    }
+}
+
+/// Settings for `DB::open_with_options`, built up from `DBOptions::new()`.
+#[derive(Debug, Clone)]
+pub struct DBOptions<K, V>
+where
+    K: DBKey + Send + Sync + 'static,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    pub(crate) max_memtable_size: usize,
+    pub(crate) max_manifest_file_size: u64,
+    pub(crate) block_cache_size: u64,
+    pub(crate) block_cache: Option<Arc<BlockCache<K, V>>>,
+    pub(crate) compaction_strategy: Arc<dyn CompactionStrategy<K, V>>,
+    pub(crate) table: TableOptions,
+    pub(crate) write_stall: WriteStallOptions,
+    pub(crate) wal_sync_interval: Option<Duration>,
+    pub(crate) wal_recovery_mode: WalRecoveryMode,
+    pub(crate) allow_strategy_change: bool,
+    pub(crate) quarantine_orphans: bool,
+}
+
+impl<K, V> Default for DBOptions<K, V>
+where
+    K: DBKey + Send + Sync + 'static,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    fn default() -> Self {
+        Self {
+            max_memtable_size: DEFAULT_MAX_MEMTABLE_SIZE,
+            max_manifest_file_size: DEFAULT_MAX_MANIFEST_FILE_SIZE,
+            block_cache_size: DEFAULT_BLOCK_CACHE_SIZE,
+            block_cache: None,
+            compaction_strategy: Arc::new(LeveledCompaction::default()),
+            table: TableOptions::default(),
+            write_stall: WriteStallOptions::default(),
+            wal_sync_interval: None,
+            wal_recovery_mode: WalRecoveryMode::default(),
+            allow_strategy_change: false,
+            quarantine_orphans: false,
+        }
+    }
+}
+
+impl<K, V> DBOptions<K, V>
+where
+    K: DBKey + Send + Sync + 'static,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// MemTable size in bytes at which it is frozen and flushed to L0.
+    pub fn max_memtable_size(mut self, bytes: usize) -> Self {
+        self.max_memtable_size = bytes;
+        self
+    }
+
+    /// Manifest size in bytes at which it is replaced by a new file that starts
+    /// from a snapshot of the live tables. A new manifest is also started on open.
+    pub fn max_manifest_file_size(mut self, bytes: u64) -> Self {
+        self.max_manifest_file_size = bytes;
+        self
+    }
+
+    /// Capacity of the database's own block cache. Ignored when a shared cache
+    /// is given through `block_cache`.
+    pub fn block_cache_size(mut self, bytes: u64) -> Self {
+        self.block_cache_size = bytes;
+        self
+    }
+
+    /// Reads through `cache`, which other databases may use as well, instead of
+    /// a block cache of the database's own. The cache's capacity is then the
+    /// memory budget of all of them together.
+    pub fn block_cache(mut self, cache: Arc<BlockCache<K, V>>) -> Self {
+        self.block_cache = Some(cache);
+        self
+    }
+
+    /// How flushed tables are merged over time; compaction triggers and level
+    /// sizes are fields of the strategy. Defaults to `LeveledCompaction`.
+    pub fn compaction_strategy<S>(mut self, strategy: S) -> Self
+    where
+        S: CompactionStrategy<K, V> + 'static,
+    {
+        self.compaction_strategy = Arc::new(strategy);
+        self
+    }
+
+    pub fn block_size(mut self, bytes: usize) -> Self {
+        self.table.block_size = bytes;
+        self
+    }
+
+    pub fn restart_interval(mut self, entries: usize) -> Self {
+        self.table.restart_interval = entries;
+        self
+    }
+
+    pub fn filter_policy(mut self, policy: FilterPolicy) -> Self {
+        self.table.filter_policy = policy;
+        self
+    }
+
+    /// Compresses the data blocks of every level the same way.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.table.compression_per_level = vec![compression];
+        self
+    }
+
+    /// Block compression by level, e.g. a fast codec for the short-lived upper
+    /// levels and zstd further down. Levels past the end use the last entry.
+    pub fn compression_per_level(mut self, compression: Vec<Compression>) -> Self {
+        self.table.compression_per_level = compression;
+        self
+    }
+
+    /// Trains a zstd dictionary of this many bytes on each zstd-compressed table
+    /// and stores it in the table. Helps most with small blocks of similar values.
+    pub fn zstd_dictionary_size(mut self, bytes: usize) -> Self {
+        self.table.zstd_dictionary_size = bytes;
+        self
+    }
+
+    /// When writers are slowed down or stopped while flushes and compactions
+    /// fall behind.
+    pub fn write_stall(mut self, options: WriteStallOptions) -> Self {
+        self.write_stall = options;
+        self
+    }
+
+    /// Syncs writes made without `WriteOptions::sync` in the background, at
+    /// most `interval` after they were written. A failed sync is returned by
+    /// the next write or `DB::sync_wal`.
+    pub fn wal_sync_interval(mut self, interval: Duration) -> Self {
+        self.wal_sync_interval = Some(interval);
+        self
+    }
+
+    /// How damaged WAL records are handled on open; see `WalRecoveryMode`.
+    /// What was left out is reported by `DB::wal_recovery_report`.
+    pub fn wal_recovery_mode(mut self, mode: WalRecoveryMode) -> Self {
+        self.wal_recovery_mode = mode;
+        self
+    }
+
+    /// Opens a database that was laid out by a different compaction strategy
+    /// instead of refusing to. The new strategy takes over the existing files.
+    /// The strategy is the only stored option an open refuses to change; sizes
+    /// and table settings may always differ from the last open.
+    pub fn allow_strategy_change(mut self, allow: bool) -> Self {
+        self.allow_strategy_change = allow;
+        self
+    }
+
+    /// Moves files swept up at open (unreferenced tables, flushed WALs, `.tmp`
+    /// leftovers) into a `quarantine` subdirectory instead of deleting them.
+    pub fn quarantine_orphans(mut self, quarantine: bool) -> Self {
+        self.quarantine_orphans = quarantine;
+        self
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.table.block_size == 0 || self.table.restart_interval == 0 {
+            return Err(Error::InvalidData(
+                "block_size and restart_interval must be non-zero".to_string(),
+            ));
+        }
+        let zstd_levels = zstd::compression_level_range();
+        for compression in &self.table.compression_per_level {
+            if let Compression::Zstd { level } = compression
+                && !zstd_levels.contains(level)
+            {
+                return Err(Error::InvalidData(format!(
+                    "zstd compression level {} is outside {:?}",
+                    level, zstd_levels
+                )));
+            }
+        }
+        let stall = &self.write_stall;
+        if stall.immutables_slowdown_trigger == 0
+            || stall.l0_slowdown_trigger == 0
+            || stall.pending_compaction_bytes_slowdown == 0
+        {
+            return Err(Error::InvalidData(
+                "write stall triggers must be non-zero".to_string(),
+            ));
+        }
+        if stall.immutables_slowdown_trigger > stall.immutables_stop_trigger
+            || stall.l0_slowdown_trigger > stall.l0_stop_trigger
+            || stall.pending_compaction_bytes_slowdown > stall.pending_compaction_bytes_stop
+        {
+            return Err(Error::InvalidData(
+                "write stall slowdown triggers must not exceed their stop triggers".to_string(),
+            ));
+        }
+        if self.max_memtable_size == 0 {
+            return Err(Error::InvalidData(
+                "max_memtable_size must be non-zero".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The options a database was last opened with, kept in the `OPTIONS` file.
+/// Opening checks the compaction strategy against it and nothing else.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct StoredOptions {
+    pub(crate) compaction_strategy: String,
+    pub(crate) max_memtable_size: usize,
+    pub(crate) block_cache_size: u64,
+    pub(crate) table: TableOptions,
+}
+
+impl StoredOptions {
+    pub(crate) fn from_options<K, V>(options: &DBOptions<K, V>) -> Self
+    where
+        K: DBKey + Send + Sync + 'static,
+        V: Serialize + DeserializeOwned + Send + Sync + 'static,
+    {
+        Self {
+            compaction_strategy: options.compaction_strategy.name().to_string(),
+            max_memtable_size: options.max_memtable_size,
+            block_cache_size: options
+                .block_cache
+                .as_ref()
+                .map_or(options.block_cache_size, |cache| cache.capacity_bytes()),
+            table: options.table.clone(),
+        }
+    }
+
+    pub(crate) fn load(dir: &Path) -> Result<Option<Self>> {
+        let path = dir.join(OPTIONS_FILE_NAME);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let mut reader = BufReader::new(File::open(path)?);
+        read_record(&mut reader)
+    }
+
+    /// Replaces the `OPTIONS` file through a rename, so it is never half-written.
+    pub(crate) fn store(&self, dir: &Path) -> Result<()> {
+        let tmp_path = dir.join(format!("{}.tmp", OPTIONS_FILE_NAME));
+        {
+            let file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&tmp_path)?;
+            let mut writer = BufWriter::new(file);
+            write_record(&mut writer, self)?;
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+        }
+        std::fs::rename(tmp_path, dir.join(OPTIONS_FILE_NAME))?;
+        Ok(())
+    }
+
+    /// Only the compaction strategy, by name, is checked. Sizes and table layout
+    /// may change freely between opens, since every table records its own
+    /// format, and so may the strategy's settings. The strategy itself decides
+    /// which data survives (FIFO drops whole tables), so a change has to be
+    /// asked for with `allow_strategy_change`. The other fields are recorded
+    /// for inspection only.
+    pub(crate) fn check_compatible(
+        &self,
+        new: &StoredOptions,
+        allow_strategy_change: bool,
+    ) -> Result<()> {
+        if self.compaction_strategy != new.compaction_strategy && !allow_strategy_change {
+            return Err(Error::InvalidData(format!(
+                "database was created with the {} compaction strategy, not {}",
+                self.compaction_strategy, new.compaction_strategy
+            )));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,256 @@
+use crate::db::io::MAX_RECORD_SIZE;
+use crate::db::sstable::datablock::DataBlock;
+use crate::{
+    COMPRESSION_LZ4, COMPRESSION_NONE, COMPRESSION_SNAPPY, COMPRESSION_ZSTD, Error, Result,
+};
+use parking_lot::Mutex;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use zstd::bulk::Decompressor;
+
+/// Default zstd compression level.
+pub const DEFAULT_ZSTD_LEVEL: i32 = 3;
+/// A zstd dictionary is trained on up to this many times its size in blocks.
+const DICTIONARY_SAMPLE_RATIO: usize = 100;
+
+thread_local! {
+    /// Context for zstd blocks without a dictionary, set up once per thread
+    /// rather than once per block.
+    static ZSTD_DECOMPRESSOR: RefCell<Option<Decompressor<'static>>> =
+        const { RefCell::new(None) };
+}
+
+/// How the data blocks of an SSTable are compressed. Every block is compressed
+/// on its own, so a point lookup only ever decompresses the block it reads.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd {
+        level: i32,
+    },
+    Lz4,
+    Snappy,
+}
+
+impl Compression {
+    pub fn zstd() -> Self {
+        Compression::Zstd {
+            level: DEFAULT_ZSTD_LEVEL,
+        }
+    }
+
+    /// The `TableMeta::compression_type` of tables written with this setting.
+    pub fn compression_type(&self) -> u8 {
+        match self {
+            Compression::None => COMPRESSION_NONE,
+            Compression::Zstd { .. } => COMPRESSION_ZSTD,
+            Compression::Lz4 => COMPRESSION_LZ4,
+            Compression::Snappy => COMPRESSION_SNAPPY,
+        }
+    }
+}
+
+/// Compresses the blocks of one table, optionally against a zstd dictionary
+/// trained on the table's own blocks.
+///
+/// A compressed block is stored as `[type (1), raw length (4), data]`. Blocks
+/// that do not shrink by at least an eighth are stored raw, with type
+/// `COMPRESSION_NONE`, so that reading them costs nothing extra.
+pub(crate) struct BlockCompressor {
+    compression: Compression,
+    zstd: Option<zstd::bulk::Compressor<'static>>,
+    dictionary: Option<Vec<u8>>,
+}
+
+impl BlockCompressor {
+    pub(crate) fn new(compression: Compression) -> Result<Self> {
+        let zstd = match compression {
+            Compression::Zstd { level } => Some(zstd::bulk::Compressor::new(level)?),
+            _ => None,
+        };
+        Ok(Self {
+            compression,
+            zstd,
+            dictionary: None,
+        })
+    }
+
+    /// Bytes of sample blocks to collect before training a dictionary of
+    /// `dictionary_size` bytes.
+    pub(crate) fn sample_budget(dictionary_size: usize) -> usize {
+        dictionary_size.saturating_mul(DICTIONARY_SAMPLE_RATIO)
+    }
+
+    /// Trains a zstd dictionary on `samples`. Training needs a reasonable number
+    /// of samples; when it fails the table is simply written without one.
+    pub(crate) fn train_dictionary<S: AsRef<[u8]>>(
+        &mut self,
+        samples: &[S],
+        dictionary_size: usize,
+    ) -> Result<()> {
+        let Compression::Zstd { level } = self.compression else {
+            return Ok(());
+        };
+        if dictionary_size == 0 {
+            return Ok(());
+        }
+        if let Ok(dictionary) = zstd::dict::from_samples(samples, dictionary_size) {
+            self.zstd = Some(zstd::bulk::Compressor::with_dictionary(level, &dictionary)?);
+            self.dictionary = Some(dictionary);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn dictionary(&self) -> Option<&[u8]> {
+        self.dictionary.as_deref()
+    }
+
+    pub(crate) fn compress(&mut self, raw: &[u8]) -> Result<Vec<u8>> {
+        let compressed = match self.compression {
+            Compression::None => None,
+            Compression::Zstd { .. } => match &mut self.zstd {
+                Some(zstd) => Some(zstd.compress(raw)?),
+                None => None,
+            },
+            Compression::Lz4 => Some(lz4_flex::block::compress(raw)),
+            Compression::Snappy => Some(
+                snap::raw::Encoder::new()
+                    .compress_vec(raw)
+                    .map_err(|e| Error::InvalidData(e.to_string()))?,
+            ),
+        };
+
+        let (block_type, data) = match compressed {
+            Some(data) if data.len() < raw.len() - raw.len() / 8 => {
+                (self.compression.compression_type(), data)
+            }
+            _ => (COMPRESSION_NONE, raw.to_vec()),
+        };
+        let mut block = Vec::with_capacity(5 + data.len());
+        block.push(block_type);
+        block.extend_from_slice(&(raw.len() as u32).to_le_bytes());
+        block.extend_from_slice(&data);
+        Ok(block)
+    }
+}
+
+/// The zstd dictionary stored in a table, with a pool of contexts that have
+/// it loaded, so it is digested once per context rather than once per block.
+pub(crate) struct Dictionary {
+    raw: Vec<u8>,
+    decompressors: Mutex<Vec<Decompressor<'static>>>,
+}
+
+impl Dictionary {
+    pub(crate) fn new(raw: &[u8]) -> Self {
+        Self {
+            raw: raw.to_vec(),
+            decompressors: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn decompress(&self, data: &[u8], raw_len: usize) -> Result<Vec<u8>> {
+        let pooled = self.decompressors.lock().pop();
+        let mut decompressor = match pooled {
+            Some(decompressor) => decompressor,
+            None => Decompressor::with_dictionary(&self.raw)?,
+        };
+        let raw = decompressor.decompress(data, raw_len)?;
+        self.decompressors.lock().push(decompressor);
+        Ok(raw)
+    }
+}
+
+impl std::fmt::Debug for Dictionary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Dictionary")
+            .field("len", &self.raw.len())
+            .finish()
+    }
+}
+
+fn zstd_decompress(data: &[u8], raw_len: usize) -> Result<Vec<u8>> {
+    ZSTD_DECOMPRESSOR.with_borrow_mut(|decompressor| {
+        let decompressor = match decompressor {
+            Some(decompressor) => decompressor,
+            None => decompressor.insert(Decompressor::new()?),
+        };
+        Ok(decompressor.decompress(data, raw_len)?)
+    })
+}
+
+/// Decodes a data block read from a table of format `version` whose
+/// `compression_type` is `table_compression`. Tables without compression hold
+/// plain encoded blocks.
+pub(crate) fn decode_block<K, V>(
+    block: &[u8],
+    version: u32,
+    table_compression: u8,
+    dictionary: Option<&Dictionary>,
+) -> Result<DataBlock<K, V>>
+where
+    K: DeserializeOwned,
+    V: DeserializeOwned,
+{
+    if table_compression == COMPRESSION_NONE {
+        let block: DataBlock<K, V> =
+            bincode::deserialize(block).map_err(|e| Error::Serialization(e.to_string()))?;
+        if version >= 2 {
+            return Ok(block);
+        }
+        return block.upgrade_unsequenced().ok_or_else(|| {
+            Error::Corruption("Version 1 data block has a damaged entry".to_string())
+        });
+    }
+    if block.len() < 5 {
+        return Err(Error::Corruption(
+            "Compressed block is truncated".to_string(),
+        ));
+    }
+    let raw_len = u32::from_le_bytes([block[1], block[2], block[3], block[4]]) as usize;
+    // The length is read before anything checks the data, so it is bounded
+    // the way record lengths are before a buffer that size is allocated.
+    if raw_len > MAX_RECORD_SIZE {
+        return Err(Error::Corruption(format!(
+            "Block size {} exceeds maximum of {}",
+            raw_len, MAX_RECORD_SIZE
+        )));
+    }
+    let data = &block[5..];
+    let raw = match block[0] {
+        COMPRESSION_NONE => data.to_vec(),
+        COMPRESSION_ZSTD => match dictionary {
+            Some(dictionary) => dictionary.decompress(data, raw_len)?,
+            None => zstd_decompress(data, raw_len)?,
+        },
+        COMPRESSION_LZ4 => lz4_flex::block::decompress(data, raw_len)
+            .map_err(|e| Error::Corruption(format!("Invalid lz4 block: {}", e)))?,
+        COMPRESSION_SNAPPY => {
+            let snappy_error =
+                |e: snap::Error| Error::Corruption(format!("Invalid snappy block: {}", e));
+            // Snappy sizes its output from its own header, not from `raw_len`.
+            if snap::raw::decompress_len(data).map_err(snappy_error)? != raw_len {
+                return Err(Error::Corruption(
+                    "Decompressed block has the wrong length".to_string(),
+                ));
+            }
+            snap::raw::Decoder::new()
+                .decompress_vec(data)
+                .map_err(snappy_error)?
+        }
+        other => {
+            return Err(Error::Corruption(format!(
+                "Unknown block compression type {}",
+                other
+            )));
+        }
+    };
+    if raw.len() != raw_len {
+        return Err(Error::Corruption(
+            "Decompressed block has the wrong length".to_string(),
+        ));
+    }
+    bincode::deserialize(&raw).map_err(|e| Error::Serialization(e.to_string()))
+}
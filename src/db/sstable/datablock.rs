@@ -2,6 +2,7 @@ use crate::{DBKey, ValueEntry};
 use serde::{Deserialize, Serialize};
 use std::io::{Cursor, Read};
 use std::marker::PhantomData;
+use std::ops::{Bound, Range};
 use std::sync::Arc;
 
 /// Target size for a data block (4KB)
@@ -30,14 +31,97 @@ impl<K, V> DataBlock<K, V> {
     pub fn iter(&self) -> DataBlockIterator<'_, K, V> {
         DataBlockIterator {
             block: self,
-            cursor: Cursor::new(&self.data),
-            last_key_bytes: Vec::new(),
-            _phantom: PhantomData,
+            cursor: BlockCursor::new(self),
+        }
+    }
+
+    /// Steps over the entry at `offset`, replacing `last_key_bytes`, which holds
+    /// the previous key whose prefix the entry shares, with the entry's key.
+    /// Returns the range of the entry's value.
+    fn advance(&self, offset: &mut usize, last_key_bytes: &mut Vec<u8>) -> Option<Range<usize>> {
+        let data = self.data.get(*offset..)?;
+        if data.len() < 12 {
+            return None;
         }
+        let shared = u32::from_le_bytes(data[0..4].try_into().ok()?) as usize;
+        let unshared = u32::from_le_bytes(data[4..8].try_into().ok()?) as usize;
+        let val_len = u32::from_le_bytes(data[8..12].try_into().ok()?) as usize;
+
+        let suffix = data.get(12..12 + unshared)?;
+        if shared > last_key_bytes.len() || data.len() < 12 + unshared + val_len {
+            return None;
+        }
+        last_key_bytes.truncate(shared);
+        last_key_bytes.extend_from_slice(suffix);
+
+        let value_start = *offset + 12 + unshared;
+        *offset = value_start + val_len;
+        Some(value_start..*offset)
+    }
+
+    /// Rewrites a block of a format version 1 table, whose values were written
+    /// without a sequence number, into the current layout with every value at
+    /// sequence number 0.
+    pub(crate) fn upgrade_unsequenced(&self) -> Option<Self> {
+        // The encoding of the `seq: u64` field that version 2 appended.
+        const SEQ_ZERO: [u8; 8] = [0; 8];
+
+        let mut data = Vec::with_capacity(self.data.len() + self.data.len() / 4);
+        let mut restart_points = Vec::with_capacity(self.restart_points.len());
+        let mut restarts = self.restart_points.iter().peekable();
+        let mut offset = 0;
+        let mut last_key_bytes = Vec::new();
+        while offset < self.data.len() {
+            if restarts
+                .next_if(|&&restart| restart as usize == offset)
+                .is_some()
+            {
+                restart_points.push(data.len() as u32);
+            }
+            let start = offset;
+            let value_range = self.advance(&mut offset, &mut last_key_bytes)?;
+            let val_len = u32::try_from(value_range.len() + SEQ_ZERO.len()).ok()?;
+            data.extend_from_slice(&self.data[start..start + 8]);
+            data.extend_from_slice(&val_len.to_le_bytes());
+            data.extend_from_slice(&self.data[start + 12..offset]);
+            data.extend_from_slice(&SEQ_ZERO);
+        }
+        if restarts.next().is_some() {
+            return None;
+        }
+        Some(Self::new(data, restart_points))
+    }
+
+    /// Decodes the entry at `offset` and moves `offset` past it.
+    fn decode_entry(
+        &self,
+        offset: &mut usize,
+        last_key_bytes: &mut Vec<u8>,
+    ) -> Option<crate::Entry<K, V>>
+    where
+        K: DBKey,
+        V: serde::de::DeserializeOwned,
+    {
+        let value_range = self.advance(offset, last_key_bytes)?;
+        let key: K = bincode::deserialize(last_key_bytes).ok()?;
+        let value: ValueEntry<V> = bincode::deserialize(&self.data[value_range]).ok()?;
+        Some(crate::Entry {
+            key: Arc::new(key),
+            value,
+        })
     }
 
     /// Get a value by key from the data block.
     pub fn get(&self, target: &K) -> Option<crate::ValueEntry<V>>
+    where
+        K: DBKey,
+        V: serde::de::DeserializeOwned,
+    {
+        self.get_at(target, u64::MAX)
+    }
+
+    /// Get the newest version of `target` whose sequence number is at most `seq`.
+    pub fn get_at(&self, target: &K, seq: u64) -> Option<crate::ValueEntry<V>>
     where
         K: DBKey,
         V: serde::de::DeserializeOwned,
@@ -46,64 +130,105 @@ impl<K, V> DataBlock<K, V> {
             return None;
         }
 
-        let mut left = 0;
-        let mut right = self.restart_points.len() - 1;
-        let mut start_index = 0;
-
-        while left <= right {
-            let mid = (left + right) / 2;
-            let offset = self.restart_points[mid] as usize;
-
-            // Decode full key at restart point
-            let mut cursor = Cursor::new(&self.data[offset..]);
-            let mut buf_4 = [0u8; 4];
-            cursor.read_exact(&mut buf_4).ok()?;
-            let shared = u32::from_le_bytes(buf_4);
-            cursor.read_exact(&mut buf_4).ok()?;
-            let unshared = u32::from_le_bytes(buf_4);
-            cursor.read_exact(&mut buf_4).ok()?;
-            let _val_len = u32::from_le_bytes(buf_4);
-
-            // Restart points MUST have shared == 0
-            if shared != 0 {
-                return None;
-            }
-
-            let mut key_bytes = vec![0u8; unshared as usize];
-            cursor.read_exact(&mut key_bytes).ok()?;
-            let key: K = bincode::deserialize(&key_bytes).ok()?;
-
-            match key.cmp(target) {
-                std::cmp::Ordering::Less => {
-                    start_index = mid;
-                    left = mid + 1;
-                }
-                std::cmp::Ordering::Greater => {
-                    if mid == 0 {
-                        break;
-                    }
-                    right = mid - 1;
-                }
-                std::cmp::Ordering::Equal => {
-                    start_index = mid;
-                    break;
-                }
-            }
-        }
+        let start_index = self.restart_for(target, false)?;
 
         let mut iter = self.iter();
         iter.seek_to_offset(self.restart_points[start_index] as usize);
 
-        while let Some(entry) = iter.next() {
+        for entry in iter {
             match entry.key.as_ref().cmp(target) {
-                std::cmp::Ordering::Equal => return Some(entry.value),
+                std::cmp::Ordering::Equal if entry.value.seq <= seq => return Some(entry.value),
                 std::cmp::Ordering::Greater => return None,
-                std::cmp::Ordering::Less => continue,
+                _ => continue,
             }
         }
 
         None
     }
+
+    /// Finds the restart point to scan from for the first entry whose key is at
+    /// least `target`, or greater than it when `after` is set.
+    fn restart_for(&self, target: &K, after: bool) -> Option<usize>
+    where
+        K: DBKey,
+    {
+        // Find the last restart point whose key is strictly below the target.
+        // Versions of one key can span several restart intervals, so landing on
+        // an equal key could skip its newest versions.
+        let mut left = 0;
+        let mut right = self.restart_points.len();
+        while left < right {
+            let mid = (left + right) / 2;
+            let below = match self.restart_key(mid)?.cmp(target) {
+                std::cmp::Ordering::Less => true,
+                std::cmp::Ordering::Equal => after,
+                std::cmp::Ordering::Greater => false,
+            };
+            if below {
+                left = mid + 1;
+            } else {
+                right = mid;
+            }
+        }
+        Some(left.saturating_sub(1))
+    }
+
+    /// Returns the offset of the first entry whose key is at least `target`, or
+    /// greater than it when `after` is set, along with the key bytes needed to
+    /// decode that entry. The offset is the end of the block if there is none.
+    fn position_of(&self, target: &K, after: bool) -> (usize, Vec<u8>)
+    where
+        K: DBKey,
+    {
+        let end = (self.data.len(), Vec::new());
+        let Some(restart) = self.restart_for(target, after) else {
+            return end;
+        };
+        let mut offset = self.restart_points[restart] as usize;
+        let mut last_key_bytes = Vec::new();
+        loop {
+            let start = offset;
+            let previous = last_key_bytes.clone();
+            if self.advance(&mut offset, &mut last_key_bytes).is_none() {
+                return end;
+            }
+            let Ok(key) = bincode::deserialize::<K>(&last_key_bytes) else {
+                return end;
+            };
+            let reached = match key.cmp(target) {
+                std::cmp::Ordering::Less => false,
+                std::cmp::Ordering::Equal => !after,
+                std::cmp::Ordering::Greater => true,
+            };
+            if reached {
+                return (start, previous);
+            }
+        }
+    }
+
+    /// Decodes the full key stored at restart point `idx`.
+    fn restart_key(&self, idx: usize) -> Option<K>
+    where
+        K: DBKey,
+    {
+        let offset = self.restart_points[idx] as usize;
+        let mut cursor = Cursor::new(&self.data[offset..]);
+        let mut buf_4 = [0u8; 4];
+        cursor.read_exact(&mut buf_4).ok()?;
+        let shared = u32::from_le_bytes(buf_4);
+        cursor.read_exact(&mut buf_4).ok()?;
+        let unshared = u32::from_le_bytes(buf_4);
+        cursor.read_exact(&mut buf_4).ok()?;
+
+        // Restart points MUST have shared == 0
+        if shared != 0 {
+            return None;
+        }
+
+        let mut key_bytes = vec![0u8; unshared as usize];
+        cursor.read_exact(&mut key_bytes).ok()?;
+        bincode::deserialize(&key_bytes).ok()
+    }
 }
 
 /// BlockBuilder helps group items into DataBlocks using Prefix Compression.
@@ -113,6 +238,7 @@ pub struct DeltaBlockBuilder<K, V> {
     last_key_bytes: Vec<u8>,
     count: usize,
     target_size: usize,
+    restart_interval: usize,
     _phantom: PhantomData<(K, V)>,
 }
 
@@ -122,12 +248,17 @@ where
     V: Serialize,
 {
     pub fn new(target_size: usize) -> Self {
+        Self::with_restart_interval(target_size, RESTART_INTERVAL)
+    }
+
+    pub fn with_restart_interval(target_size: usize, restart_interval: usize) -> Self {
         Self {
             data: Vec::with_capacity(target_size),
             restart_points: Vec::new(),
             last_key_bytes: Vec::new(),
             count: 0,
             target_size,
+            restart_interval: restart_interval.max(1),
             _phantom: PhantomData,
         }
     }
@@ -139,7 +270,7 @@ where
 
         let mut shared = 0;
 
-        if self.count % RESTART_INTERVAL == 0 {
+        if self.count.is_multiple_of(self.restart_interval) {
             self.restart_points.push(self.data.len() as u32);
         } else {
             // Calculate shared prefix length
@@ -181,18 +312,128 @@ where
     }
 }
 
+/// Where an iterator stands within a block. Entries are decoded in place from
+/// the front; from the back, where delta encoding cannot be undone, one restart
+/// interval at a time is decoded and handed out in reverse.
+struct BlockCursor<K, V> {
+    /// Start of the next entry from the front.
+    front: usize,
+    /// Key of the entry before `front`, which the next entry shares a prefix with.
+    last_key_bytes: Vec<u8>,
+    /// Start of the last entry returned from the back.
+    back: usize,
+    /// Decoded entries below `back`, with their offsets, in block order.
+    back_entries: Vec<(usize, crate::Entry<K, V>)>,
+}
+
+impl<K, V> BlockCursor<K, V> {
+    fn new(block: &DataBlock<K, V>) -> Self {
+        Self {
+            front: 0,
+            last_key_bytes: Vec::new(),
+            back: block.data.len(),
+            back_entries: Vec::new(),
+        }
+    }
+
+    fn seek_to_offset(&mut self, offset: usize) {
+        self.front = offset;
+        self.last_key_bytes.clear();
+    }
+}
+
+impl<K, V> BlockCursor<K, V>
+where
+    K: DBKey,
+    V: serde::de::DeserializeOwned,
+{
+    fn seek_front(&mut self, block: &DataBlock<K, V>, lower: Bound<&K>) {
+        (self.front, self.last_key_bytes) = match lower {
+            Bound::Included(key) => block.position_of(key, false),
+            Bound::Excluded(key) => block.position_of(key, true),
+            Bound::Unbounded => (0, Vec::new()),
+        };
+    }
+
+    fn seek_back(&mut self, block: &DataBlock<K, V>, upper: Bound<&K>) {
+        self.back = match upper {
+            Bound::Included(key) => block.position_of(key, true).0,
+            Bound::Excluded(key) => block.position_of(key, false).0,
+            Bound::Unbounded => block.data.len(),
+        };
+        self.back_entries.clear();
+    }
+
+    fn next(&mut self, block: &DataBlock<K, V>) -> Option<crate::Entry<K, V>> {
+        if self.front >= self.back {
+            return None;
+        }
+        block.decode_entry(&mut self.front, &mut self.last_key_bytes)
+    }
+
+    fn next_back(&mut self, block: &DataBlock<K, V>) -> Option<crate::Entry<K, V>> {
+        loop {
+            if let Some((start, entry)) = self.back_entries.pop() {
+                if start < self.front {
+                    // The front has already returned this entry.
+                    self.back_entries.clear();
+                    self.back = self.front;
+                    return None;
+                }
+                self.back = start;
+                return Some(entry);
+            }
+            if self.back <= self.front {
+                return None;
+            }
+
+            // Decode the restart interval holding the entry before `back`, or
+            // just its tail if the front is already inside it.
+            let restart = block
+                .restart_points
+                .partition_point(|&point| (point as usize) < self.back)
+                .checked_sub(1)?;
+            let restart_offset = block.restart_points[restart] as usize;
+            let (mut offset, mut last_key_bytes) = if restart_offset < self.front {
+                (self.front, self.last_key_bytes.clone())
+            } else {
+                (restart_offset, Vec::new())
+            };
+            while offset < self.back {
+                let start = offset;
+                let entry = block.decode_entry(&mut offset, &mut last_key_bytes)?;
+                self.back_entries.push((start, entry));
+            }
+        }
+    }
+}
+
 /// Iterator for reconstructing delta-encoded entries.
 pub struct DataBlockIterator<'a, K, V> {
     block: &'a DataBlock<K, V>,
-    cursor: Cursor<&'a Vec<u8>>,
-    last_key_bytes: Vec<u8>,
-    _phantom: PhantomData<(K, V)>,
+    cursor: BlockCursor<K, V>,
 }
 
 impl<'a, K, V> DataBlockIterator<'a, K, V> {
     pub fn seek_to_offset(&mut self, offset: usize) {
-        self.cursor.set_position(offset as u64);
-        self.last_key_bytes.clear();
+        self.cursor.seek_to_offset(offset);
+    }
+}
+
+impl<'a, K, V> DataBlockIterator<'a, K, V>
+where
+    K: DBKey,
+    V: serde::de::DeserializeOwned,
+{
+    /// Moves the front of the iterator to the first entry whose key is at least `target`.
+    pub fn seek(&mut self, target: &K) {
+        self.cursor.seek_front(self.block, Bound::Included(target));
+    }
+
+    /// Limits the iterator, from both ends, to the keys within the bounds.
+    pub fn set_bounds(&mut self, lower: Bound<&K>, upper: Bound<&K>) {
+        self.cursor.seek_front(self.block, lower);
+        self.cursor.seek_back(self.block, upper);
     }
 }
 
@@ -204,39 +445,82 @@ where
     type Item = crate::Entry<K, V>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.cursor.position() as usize >= self.block.data.len() {
-            return None;
-        }
+        self.cursor.next(self.block)
+    }
+}
 
-        let mut buf_4 = [0u8; 4];
+impl<'a, K, V> DoubleEndedIterator for DataBlockIterator<'a, K, V>
+where
+    K: DBKey,
+    V: serde::de::DeserializeOwned,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.cursor.next_back(self.block)
+    }
+}
 
-        self.cursor.read_exact(&mut buf_4).ok()?;
-        let shared = u32::from_le_bytes(buf_4) as usize;
+/// Owning iterator over a block, for readers that keep their place in a block
+/// between calls without re-decoding it from the start.
+pub struct DataBlockIntoIter<K, V> {
+    block: Arc<DataBlock<K, V>>,
+    cursor: BlockCursor<K, V>,
+}
 
-        self.cursor.read_exact(&mut buf_4).ok()?;
-        let unshared = u32::from_le_bytes(buf_4) as usize;
+impl<K, V> DataBlockIntoIter<K, V> {
+    pub fn new(block: Arc<DataBlock<K, V>>) -> Self {
+        let cursor = BlockCursor::new(&block);
+        Self { block, cursor }
+    }
+}
 
-        self.cursor.read_exact(&mut buf_4).ok()?;
-        let val_len = u32::from_le_bytes(buf_4) as usize;
+impl<K, V> DataBlockIntoIter<K, V>
+where
+    K: DBKey,
+    V: serde::de::DeserializeOwned,
+{
+    /// Moves the front of the iterator to the first entry whose key is at least `target`.
+    pub fn seek(&mut self, target: &K) {
+        self.cursor.seek_front(&self.block, Bound::Included(target));
+    }
 
-        let mut key_bytes = Vec::with_capacity(shared + unshared);
-        if shared > 0 {
-            key_bytes.extend_from_slice(&self.last_key_bytes[..shared]);
-        }
-        let mut suffix = vec![0u8; unshared];
-        self.cursor.read_exact(&mut suffix).ok()?;
-        key_bytes.extend_from_slice(&suffix);
+    /// Limits the iterator, from both ends, to the keys within the bounds.
+    pub fn set_bounds(&mut self, lower: Bound<&K>, upper: Bound<&K>) {
+        self.cursor.seek_front(&self.block, lower);
+        self.cursor.seek_back(&self.block, upper);
+    }
+}
 
-        let mut val_bytes = vec![0u8; val_len];
-        self.cursor.read_exact(&mut val_bytes).ok()?;
+impl<K, V> IntoIterator for DataBlock<K, V>
+where
+    K: DBKey,
+    V: serde::de::DeserializeOwned,
+{
+    type Item = crate::Entry<K, V>;
+    type IntoIter = DataBlockIntoIter<K, V>;
 
-        let key: K = bincode::deserialize(&key_bytes).ok()?;
-        let value: ValueEntry<V> = bincode::deserialize(&val_bytes).ok()?;
+    fn into_iter(self) -> Self::IntoIter {
+        DataBlockIntoIter::new(Arc::new(self))
+    }
+}
 
-        self.last_key_bytes = key_bytes;
-        Some(crate::Entry {
-            key: Arc::new(key),
-            value,
-        })
+impl<K, V> Iterator for DataBlockIntoIter<K, V>
+where
+    K: DBKey,
+    V: serde::de::DeserializeOwned,
+{
+    type Item = crate::Entry<K, V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.cursor.next(&self.block)
+    }
+}
+
+impl<K, V> DoubleEndedIterator for DataBlockIntoIter<K, V>
+where
+    K: DBKey,
+    V: serde::de::DeserializeOwned,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.cursor.next_back(&self.block)
     }
 }
@@ -1,6 +1,29 @@
+use crate::{Error, KEY_HASH_DEFAULT_HASHER, KEY_HASH_XXH3, Result};
 use serde::{Deserialize, Serialize};
+use std::hash::Hash;
 use xorf::{Filter, Xor8, Xor16};
 
+/// Hashes `key` for a filter with the `KEY_HASH_*` algorithm its table records.
+pub fn key_hash<K: Hash + Serialize>(key: &K, algorithm: u8) -> Result<u64> {
+    match algorithm {
+        KEY_HASH_XXH3 => {
+            let bytes = bincode::serialize(key).map_err(|e| Error::Serialization(e.to_string()))?;
+            Ok(xxhash_rust::xxh3::xxh3_64(&bytes))
+        }
+        KEY_HASH_DEFAULT_HASHER => {
+            use std::collections::hash_map::DefaultHasher;
+            use std::hash::Hasher;
+            let mut s = DefaultHasher::new();
+            key.hash(&mut s);
+            Ok(s.finish())
+        }
+        other => Err(Error::Corruption(format!(
+            "Unknown key hash algorithm {}",
+            other
+        ))),
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum FilterVariant {
     Xor8(Xor8),
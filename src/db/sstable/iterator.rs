@@ -1,72 +1,175 @@
-use crate::db::io::read_record;
-use crate::db::sstable::datablock::DataBlock;
-use crate::{DBKey, Entry, Result};
+use crate::db::cache::BlockCache;
+use crate::db::sstable::TableFile;
+use crate::db::sstable::compression::{Dictionary, decode_block};
+use crate::db::sstable::datablock::{DataBlock, DataBlockIntoIter};
+use crate::{DBKey, Entry, Error, Result, SSTableId};
 use serde::Serialize;
 use serde::de::DeserializeOwned;
-use std::fs::File;
-use std::io::{BufReader, Seek};
-use std::marker::PhantomData;
+use std::collections::VecDeque;
+use std::ops::Bound;
+use std::sync::Arc;
 
-pub struct SSTableIterator<K, V> {
-    pub(crate) reader: BufReader<File>,
-    pub(crate) data_end_offset: u64,
-    pub(crate) current_block: Option<DataBlock<K, V>>,
-    pub(crate) current_idx: usize,
-    pub(crate) _phantom: PhantomData<(K, V)>,
+/// Iterates the entries of one SSTable within a key range, from either end.
+///
+/// Only the blocks the sparse index places within the range are read, and the
+/// first and last of them are entered through their restart points. Blocks are
+/// taken from the table's block cache when present there, and only added to it
+/// when the read asked to fill the cache. Iterating backwards returns the
+/// entries in exactly the opposite order, so the versions of a key come oldest
+/// first.
+pub struct SSTableIterator<K, V>
+where
+    K: DBKey + Send + Sync + 'static,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    id: SSTableId,
+    file: Arc<TableFile>,
+    version: u32,
+    compression_type: u8,
+    dictionary: Option<Arc<Dictionary>>,
+    block_cache: Option<Arc<BlockCache<K, V>>>,
+    fill_cache: bool,
+    /// Offsets of the blocks in range that neither end has opened yet.
+    blocks: VecDeque<u64>,
+    first_block: Option<u64>,
+    last_block: Option<u64>,
+    lower: Bound<K>,
+    upper: Bound<K>,
+    front: Option<DataBlockIntoIter<K, V>>,
+    back: Option<DataBlockIntoIter<K, V>>,
 }
 
 impl<K, V> SSTableIterator<K, V>
 where
-    K: DBKey,
-    V: Serialize + DeserializeOwned,
+    K: DBKey + Send + Sync + 'static,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static,
 {
-    pub(crate) fn new(reader: BufReader<File>, data_end_offset: u64) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        id: SSTableId,
+        file: Arc<TableFile>,
+        version: u32,
+        compression_type: u8,
+        dictionary: Option<Arc<Dictionary>>,
+        block_cache: Option<Arc<BlockCache<K, V>>>,
+        fill_cache: bool,
+        blocks: VecDeque<u64>,
+        lower: Bound<K>,
+        upper: Bound<K>,
+    ) -> Self {
         Self {
-            reader,
-            data_end_offset,
-            current_block: None,
-            current_idx: 0,
-            _phantom: PhantomData,
+            id,
+            file,
+            version,
+            compression_type,
+            dictionary,
+            block_cache,
+            fill_cache,
+            first_block: blocks.front().copied(),
+            last_block: blocks.back().copied(),
+            blocks,
+            lower,
+            upper,
+            front: None,
+            back: None,
         }
     }
 
-    fn load_next_block(&mut self) -> Result<bool> {
-        let current_pos = self.reader.stream_position()?;
-        if current_pos >= self.data_end_offset {
-            return Ok(false);
+    fn read_block(&self, offset: u64) -> Result<Arc<DataBlock<K, V>>> {
+        if let Some(block) = self
+            .block_cache
+            .as_ref()
+            .and_then(|cache| cache.get(self.id, offset))
+        {
+            return Ok(block);
         }
+        let bytes = self
+            .file
+            .read_record_at(offset)?
+            .ok_or_else(|| Error::Corruption("Data block is missing".to_string()))?;
+        let block: Arc<DataBlock<K, V>> = Arc::new(decode_block(
+            &bytes,
+            self.version,
+            self.compression_type,
+            self.dictionary.as_deref(),
+        )?);
+        if self.fill_cache
+            && let Some(cache) = &self.block_cache
+        {
+            cache.insert(self.id, offset, Arc::clone(&block));
+        }
+        Ok(block)
+    }
 
-        match read_record(&mut self.reader)? {
-            Some(block) => {
-                self.current_block = Some(block);
-                self.current_idx = 0;
-                Ok(true)
-            }
-            None => Ok(false),
+    fn open_block(&self, offset: u64) -> Result<DataBlockIntoIter<K, V>> {
+        let mut iter = DataBlockIntoIter::new(self.read_block(offset)?);
+
+        // Blocks strictly inside the range need no seeking.
+        let lower = if Some(offset) == self.first_block {
+            self.lower.as_ref()
+        } else {
+            Bound::Unbounded
+        };
+        let upper = if Some(offset) == self.last_block {
+            self.upper.as_ref()
+        } else {
+            Bound::Unbounded
+        };
+        if !matches!((lower, upper), (Bound::Unbounded, Bound::Unbounded)) {
+            iter.set_bounds(lower, upper);
         }
+        Ok(iter)
     }
 }
 
 impl<K, V> Iterator for SSTableIterator<K, V>
 where
-    K: DBKey,
-    V: Serialize + DeserializeOwned,
+    K: DBKey + Send + Sync + 'static,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static,
 {
     type Item = Result<Entry<K, V>>;
 
     fn next(&mut self) -> Option<Self::Item> {
         loop {
-            if let Some(block) = &self.current_block {
-                if let Some(entry) = block.iter().nth(self.current_idx) {
-                    self.current_idx += 1;
-                    return Some(Ok(entry));
+            if let Some(entry) = self.front.as_mut().and_then(Iterator::next) {
+                return Some(Ok(entry));
+            }
+            let Some(offset) = self.blocks.pop_front() else {
+                // The rest of the range is in the block the back end has open.
+                return self.back.as_mut()?.next().map(Ok);
+            };
+            match self.open_block(offset) {
+                Ok(block) => self.front = Some(block),
+                Err(e) => {
+                    self.blocks.clear();
+                    self.back = None;
+                    return Some(Err(e));
                 }
             }
+        }
+    }
+}
 
-            match self.load_next_block() {
-                Ok(true) => continue,
-                Ok(false) => return None,
-                Err(e) => return Some(Err(e)),
+impl<K, V> DoubleEndedIterator for SSTableIterator<K, V>
+where
+    K: DBKey + Send + Sync + 'static,
+    V: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.back.as_mut().and_then(DoubleEndedIterator::next_back) {
+                return Some(Ok(entry));
+            }
+            let Some(offset) = self.blocks.pop_back() else {
+                return self.front.as_mut()?.next_back().map(Ok);
+            };
+            match self.open_block(offset) {
+                Ok(block) => self.back = Some(block),
+                Err(e) => {
+                    self.blocks.clear();
+                    self.front = None;
+                    return Some(Err(e));
+                }
             }
         }
     }
@@ -1,28 +1,112 @@
+pub mod compression;
 pub mod datablock;
 pub mod filter;
 pub mod iterator;
 pub mod reader;
 pub mod writer;
 
+pub use compression::{Compression, DEFAULT_ZSTD_LEVEL};
 pub use datablock::*;
-pub use filter::FilterVariant;
+pub use filter::{FilterVariant, key_hash};
 pub use iterator::*;
 
 use crate::DBKey;
+use crate::db::io::read_raw_record;
 pub use crate::types::sstable::{FILTER_TYPE_XOR8, FILTER_TYPE_XOR16};
-use crate::{Result, SSTableId, TableMeta};
+use crate::{ReadOptions, Result, SSTableId, TableMeta};
 use serde::{Serialize, de::DeserializeOwned};
-use std::collections::BTreeMap;
+use std::collections::VecDeque;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufReader, Read};
 use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-pub const FORMAT_VERSION: u32 = 1;
+pub const FORMAT_VERSION: u32 = 5;
+/// Oldest on-disk format this build can still read. Version 2 added per-entry sequence numbers,
+/// version 3 compressed blocks and the dictionary offset in the footer, version 4 the key
+/// hash algorithm in `TableMeta`, and version 5 lets the versions of one key span data
+/// blocks. Entries of version 1 tables read as sequence number 0.
+pub const MIN_FORMAT_VERSION: u32 = 1;
 pub const FOOTER_SIZE: u64 = 64;
 pub const MAGIC_NUMBER: u64 = 0xDEADC0DEBEEFCAFF;
 
+/// Read-ahead for a block read; covers the frame header and a default-sized block.
+const BLOCK_READ_AHEAD: usize = 8 * 1024;
+
+/// The open file of an SSTable, shared by every clone. Once the table has been
+/// compacted away its file is deleted with the last clone, so readers that
+/// still hold an older version can keep opening it.
+#[derive(Debug)]
+pub(crate) struct TableFile {
+    path: PathBuf,
+    file: File,
+    /// Serializes seek and read pairs where the platform has no positional reads.
+    #[cfg(not(any(unix, windows)))]
+    cursor: parking_lot::Mutex<()>,
+    obsolete: AtomicBool,
+}
+
+impl TableFile {
+    pub(crate) fn new(path: PathBuf, file: File) -> Self {
+        Self {
+            path,
+            file,
+            #[cfg(not(any(unix, windows)))]
+            cursor: parking_lot::Mutex::new(()),
+            obsolete: AtomicBool::new(false),
+        }
+    }
+
+    /// Reads the record starting at `offset`. On unix and windows, positional
+    /// reads take no lock and leave the file cursor alone, so lookups on one
+    /// table run in parallel; elsewhere they take turns seeking the file.
+    pub(crate) fn read_record_at(&self, offset: u64) -> Result<Option<Vec<u8>>> {
+        let mut reader = BufReader::with_capacity(
+            BLOCK_READ_AHEAD,
+            PositionalReader {
+                table: self,
+                offset,
+            },
+        );
+        read_raw_record(&mut reader)
+    }
+}
+
+struct PositionalReader<'a> {
+    table: &'a TableFile,
+    offset: u64,
+}
+
+impl Read for PositionalReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        #[cfg(unix)]
+        let n = std::os::unix::fs::FileExt::read_at(&self.table.file, buf, self.offset)?;
+        #[cfg(windows)]
+        let n = std::os::windows::fs::FileExt::seek_read(&self.table.file, buf, self.offset)?;
+        #[cfg(not(any(unix, windows)))]
+        let n = {
+            use std::io::{Seek, SeekFrom};
+            let _cursor = self.table.cursor.lock();
+            let mut file = &self.table.file;
+            file.seek(SeekFrom::Start(self.offset))?;
+            file.read(buf)?
+        };
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+impl Drop for TableFile {
+    fn drop(&mut self) {
+        if self.obsolete.load(Ordering::Acquire) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct SSTable<K, V>
 where
@@ -30,8 +114,10 @@ where
     V: Serialize + DeserializeOwned + Send + Sync + 'static,
 {
     pub(crate) path: PathBuf,
-    pub(crate) reader: Arc<Mutex<BufReader<File>>>,
-    pub(crate) index: BTreeMap<Arc<K>, u64>,
+    pub(crate) file: Arc<TableFile>,
+    /// First key and offset of each data block. Blocks of version 5 tables may
+    /// start with the same key when its versions span them.
+    pub(crate) index: Vec<(Arc<K>, u64)>,
     pub(crate) meta: TableMeta<K>,
     pub(crate) filter: FilterVariant,
     pub(crate) id: SSTableId,
@@ -39,6 +125,8 @@ where
     pub(crate) filter_offset: u64,
     pub(crate) index_offset: u64,
     pub(crate) file_size: u64,
+    /// zstd dictionary the data blocks were compressed against, if any.
+    pub(crate) dictionary: Option<Arc<compression::Dictionary>>,
     pub(crate) block_cache: Option<Arc<crate::db::cache::BlockCache<K, V>>>,
     pub(crate) _phantom: PhantomData<(K, V)>,
 }
@@ -51,7 +139,7 @@ where
     fn clone(&self) -> Self {
         Self {
             path: self.path.clone(),
-            reader: Arc::clone(&self.reader),
+            file: Arc::clone(&self.file),
             index: self.index.clone(),
             meta: self.meta.clone(),
             filter: self.filter.clone(),
@@ -60,6 +148,7 @@ where
             filter_offset: self.filter_offset,
             index_offset: self.index_offset,
             file_size: self.file_size,
+            dictionary: self.dictionary.clone(),
             block_cache: self.block_cache.as_ref().map(Arc::clone),
             _phantom: PhantomData,
         }
@@ -82,12 +171,21 @@ where
     pub fn path(&self) -> &Path {
         &self.path
     }
+
+    /// Deletes the file once no clone of this table is left.
+    pub(crate) fn mark_obsolete(&self) {
+        self.file.obsolete.store(true, Ordering::Release);
+    }
+
     pub fn id(&self) -> SSTableId {
         self.id
     }
     pub fn len(&self) -> usize {
         self.meta.num_entries as usize
     }
+    pub fn is_empty(&self) -> bool {
+        self.meta.num_entries == 0
+    }
     pub fn file_size(&self) -> u64 {
         self.file_size
     }
@@ -100,6 +198,18 @@ where
     pub fn num_entries(&self) -> u64 {
         self.meta.num_entries
     }
+    /// One of the `COMPRESSION_*` constants.
+    pub fn compression_type(&self) -> u8 {
+        self.meta.compression_type
+    }
+
+    pub fn has_dictionary(&self) -> bool {
+        self.dictionary.is_some()
+    }
+
+    pub fn max_seq(&self) -> u64 {
+        self.meta.max_seq
+    }
 
     pub fn overlaps(&self, other: &Self) -> bool {
         self.overlaps_range(other.min_key(), other.max_key())
@@ -109,11 +219,90 @@ where
         self.min_key() <= max && min <= self.max_key()
     }
 
+    /// Like `overlaps_range`, but for the half-open bounds used by range scans.
+    pub fn overlaps_bounds(&self, lower: Bound<&K>, upper: Bound<&K>) -> bool {
+        let above_lower = match lower {
+            Bound::Included(lo) => self.max_key() >= lo,
+            Bound::Excluded(lo) => self.max_key() > lo,
+            Bound::Unbounded => true,
+        };
+        let below_upper = match upper {
+            Bound::Included(hi) => self.min_key() <= hi,
+            Bound::Excluded(hi) => self.min_key() < hi,
+            Bound::Unbounded => true,
+        };
+        above_lower && below_upper
+    }
+
+    /// Position in the index of the first block that can hold `key`, or a key
+    /// above it when `after` is set. The versions of a key may start in the
+    /// block before the first one starting with the key.
+    pub(crate) fn first_block_for(&self, key: &K, after: bool) -> usize {
+        let straddles = self.version >= 5 && !after;
+        self.index
+            .partition_point(|(first_key, _)| {
+                let first_key = first_key.as_ref();
+                first_key < key || (first_key == key && !straddles)
+            })
+            .saturating_sub(1)
+    }
+
     pub fn iter(&self) -> Result<SSTableIterator<K, V>> {
-        let file = File::open(&self.path)?;
+        self.range(..)
+    }
+
+    /// Iterates from the first entry whose key is at least `key`.
+    pub fn iter_from(&self, key: &K) -> Result<SSTableIterator<K, V>> {
+        self.range((Bound::Included(key.clone()), Bound::Unbounded))
+    }
+
+    /// Iterates the entries whose keys fall within `range`, in either direction.
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Result<SSTableIterator<K, V>> {
+        self.range_opt(range, &ReadOptions::default())
+    }
+
+    /// Like `range`; blocks are read through the block cache, and added to it
+    /// only if `options.fill_cache` is set.
+    pub fn range_opt<R: RangeBounds<K>>(
+        &self,
+        range: R,
+        options: &ReadOptions,
+    ) -> Result<SSTableIterator<K, V>> {
+        let lower = range.start_bound().cloned();
+        let upper = range.end_bound().cloned();
+
+        // Blocks are in range from the first one that can hold the lower bound
+        // up to the last one starting within the upper bound.
+        let first = match &lower {
+            Bound::Included(key) => self.first_block_for(key, false),
+            Bound::Excluded(key) => self.first_block_for(key, true),
+            Bound::Unbounded => 0,
+        };
+        let blocks = if self.overlaps_bounds(lower.as_ref(), upper.as_ref()) {
+            self.index[first..]
+                .iter()
+                .take_while(|(first_key, _)| match &upper {
+                    Bound::Included(hi) => first_key.as_ref() <= hi,
+                    Bound::Excluded(hi) => first_key.as_ref() < hi,
+                    Bound::Unbounded => true,
+                })
+                .map(|(_, offset)| *offset)
+                .collect()
+        } else {
+            VecDeque::new()
+        };
+
         Ok(SSTableIterator::new(
-            BufReader::new(file),
-            self.filter_offset,
+            self.id,
+            Arc::clone(&self.file),
+            self.version,
+            self.meta.compression_type,
+            self.dictionary.clone(),
+            self.block_cache.clone(),
+            options.fill_cache,
+            blocks,
+            lower,
+            upper,
         ))
     }
 }
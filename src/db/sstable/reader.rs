@@ -1,17 +1,19 @@
-use crate::db::io::read_record;
+use crate::db::io::{read_raw_record, read_record};
+use crate::db::sstable::compression::{Dictionary, decode_block};
 use crate::db::sstable::datablock::DataBlock;
 use crate::db::sstable::{
-    FILTER_TYPE_XOR16, FOOTER_SIZE, FORMAT_VERSION, FilterVariant, MAGIC_NUMBER, SSTable,
+    FILTER_TYPE_XOR16, FOOTER_SIZE, FORMAT_VERSION, FilterVariant, MAGIC_NUMBER,
+    MIN_FORMAT_VERSION, SSTable, TableFile, key_hash,
 };
-use crate::{DBKey, Error, Result, SSTableId, TableMeta, ValueEntry};
+use crate::types::sstable::{LegacyTableMeta, TableMetaV1};
+use crate::{DBKey, Error, ReadOptions, Result, SSTableId, TableMeta, ValueEntry};
 use serde::Serialize;
 use serde::de::DeserializeOwned;
-use std::collections::BTreeMap;
 use std::fs::OpenOptions;
 use std::io::{BufReader, Read, Seek, SeekFrom};
 use std::marker::PhantomData;
 use std::path::Path;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use xorf::{Xor8, Xor16};
 
 impl<K, V> SSTable<K, V>
@@ -53,10 +55,14 @@ where
         reader.read_exact(&mut buf_4)?;
         let version = u32::from_le_bytes(buf_4);
 
+        // Older versions leave this part of the footer zeroed.
+        reader.read_exact(&mut buf_8)?;
+        let dictionary_offset = u64::from_le_bytes(buf_8);
+
         if magic_number != MAGIC_NUMBER {
             return Err(Error::Corruption("Invalid magic number".to_string()));
         }
-        if version > FORMAT_VERSION {
+        if !(MIN_FORMAT_VERSION..=FORMAT_VERSION).contains(&version) {
             return Err(Error::Corruption(format!(
                 "Unsupported SSTable version: {}",
                 version
@@ -64,7 +70,12 @@ where
         }
 
         reader.seek(SeekFrom::Start(meta_offset))?;
-        let meta: TableMeta<K> = read_record(&mut reader)?.ok_or_else(|| {
+        let meta: Option<TableMeta<K>> = match version {
+            1 => read_record::<_, TableMetaV1<K>>(&mut reader)?.map(TableMeta::from),
+            2 | 3 => read_record::<_, LegacyTableMeta<K>>(&mut reader)?.map(TableMeta::from),
+            _ => read_record(&mut reader)?,
+        };
+        let meta = meta.ok_or_else(|| {
             Error::Corruption("SSTable meta block is missing or empty".to_string())
         })?;
 
@@ -81,8 +92,18 @@ where
             FilterVariant::Xor8(f)
         };
 
+        let dictionary = if version >= 3 && dictionary_offset != 0 {
+            reader.seek(SeekFrom::Start(dictionary_offset))?;
+            let dictionary = read_raw_record(&mut reader)?.ok_or_else(|| {
+                Error::Corruption("SSTable compression dictionary is missing".to_string())
+            })?;
+            Some(Arc::new(Dictionary::new(&dictionary)))
+        } else {
+            None
+        };
+
         reader.seek(SeekFrom::Start(index_offset))?;
-        let index_raw: BTreeMap<K, u64> = read_record(&mut reader)?.ok_or_else(|| {
+        let index_raw: Vec<(K, u64)> = read_record(&mut reader)?.ok_or_else(|| {
             Error::Corruption("SSTable index block is missing or empty".to_string())
         })?;
         let index = index_raw
@@ -92,7 +113,7 @@ where
 
         Ok(SSTable {
             path: path.to_path_buf(),
-            reader: Arc::new(Mutex::new(reader)),
+            file: Arc::new(TableFile::new(path.to_path_buf(), reader.into_inner())),
             index,
             meta,
             filter,
@@ -101,62 +122,79 @@ where
             filter_offset,
             index_offset,
             file_size: file_len,
+            dictionary,
             block_cache,
             _phantom: PhantomData,
         })
     }
 
     pub fn get(&self, key: &K) -> Result<Option<ValueEntry<V>>> {
+        self.get_at(key, u64::MAX)
+    }
+
+    /// Returns the newest version of `key` whose sequence number is at most `seq`.
+    pub fn get_at(&self, key: &K, seq: u64) -> Result<Option<ValueEntry<V>>> {
+        self.get_at_opt(key, seq, &ReadOptions::default())
+    }
+
+    /// Like `get_at`; a block read from disk is cached only if
+    /// `options.fill_cache` is set.
+    pub fn get_at_opt(
+        &self,
+        key: &K,
+        seq: u64,
+        options: &ReadOptions,
+    ) -> Result<Option<ValueEntry<V>>> {
         if key < self.min_key() || key > self.max_key() {
             return Ok(None);
         }
 
-        let key_hash = self.hash_key(key);
+        let key_hash = self.hash_key(key)?;
         if !self.filter.contains(&key_hash) {
             return Ok(None);
         }
 
-        let block_offset = match self.index.range(..=key.clone()).next_back() {
-            Some((_, offset)) => *offset,
-            None => return Ok(None),
-        };
-
-        let block = if let Some(cache) = &self.block_cache {
-            if let Some(cached_block) = cache.get(self.id, block_offset) {
-                cached_block
-            } else {
-                let mut reader = self
-                    .reader
-                    .lock()
-                    .map_err(|_| Error::Corruption("Lock poisoned".to_string()))?;
-                reader.seek(SeekFrom::Start(block_offset))?;
-
-                let block: DataBlock<K, V> = read_record(&mut *reader)?
-                    .ok_or_else(|| Error::Corruption("Data block is missing".to_string()))?;
-                let arc_block = Arc::new(block);
-                cache.insert(self.id, block_offset, Arc::clone(&arc_block));
-                arc_block
+        // Versions at most `seq` may be in a later block than the newest ones.
+        for (first_key, block_offset) in &self.index[self.first_block_for(key, false)..] {
+            if first_key.as_ref() > key {
+                break;
             }
-        } else {
-            let mut reader = self
-                .reader
-                .lock()
-                .map_err(|_| Error::Corruption("Lock poisoned".to_string()))?;
-            reader.seek(SeekFrom::Start(block_offset))?;
-
-            let block: DataBlock<K, V> = read_record(&mut *reader)?
-                .ok_or_else(|| Error::Corruption("Data block is missing".to_string()))?;
-            Arc::new(block)
+            let block = self.cached_block(*block_offset, options)?;
+            if let Some(value) = block.get_at(key, seq) {
+                return Ok(Some(value));
+            }
+        }
+        Ok(None)
+    }
+
+    fn cached_block(&self, offset: u64, options: &ReadOptions) -> Result<Arc<DataBlock<K, V>>> {
+        let Some(cache) = &self.block_cache else {
+            return Ok(Arc::new(self.read_block(offset)?));
         };
+        if let Some(cached_block) = cache.get(self.id, offset) {
+            return Ok(cached_block);
+        }
+        let block = Arc::new(self.read_block(offset)?);
+        if options.fill_cache {
+            cache.insert(self.id, offset, Arc::clone(&block));
+        }
+        Ok(block)
+    }
 
-        block.get(key).map_or(Ok(None), |v| Ok(Some(v)))
+    fn read_block(&self, offset: u64) -> Result<DataBlock<K, V>> {
+        let bytes = self
+            .file
+            .read_record_at(offset)?
+            .ok_or_else(|| Error::Corruption("Data block is missing".to_string()))?;
+        decode_block(
+            &bytes,
+            self.version,
+            self.meta.compression_type,
+            self.dictionary.as_deref(),
+        )
     }
 
-    pub(crate) fn hash_key(&self, key: &K) -> u64 {
-        use std::collections::hash_map::DefaultHasher;
-        use std::hash::Hasher;
-        let mut s = DefaultHasher::new();
-        key.hash(&mut s);
-        s.finish()
+    pub(crate) fn hash_key(&self, key: &K) -> Result<u64> {
+        key_hash(key, self.meta.key_hash)
     }
 }
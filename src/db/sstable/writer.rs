@@ -1,13 +1,12 @@
-use crate::db::datablock::DeltaBlockBuilder;
-use crate::db::io::write_record;
-use crate::db::sstable::datablock::BLOCK_SIZE;
-use crate::db::sstable::{
-    FILTER_TYPE_XOR8, FILTER_TYPE_XOR16, FORMAT_VERSION, MAGIC_NUMBER, SSTable,
+use crate::db::datablock::{DataBlock, DeltaBlockBuilder};
+use crate::db::io::{write_raw_record, write_record};
+use crate::db::sstable::compression::{BlockCompressor, Compression};
+use crate::db::sstable::{FILTER_TYPE_XOR16, FORMAT_VERSION, MAGIC_NUMBER, SSTable, key_hash};
+use crate::{
+    DBKey, Entry, Error, KEY_HASH_XXH3, MemTable, Result, SSTableId, TableMeta, TableOptions,
 };
-use crate::{COMPRESSION_NONE, DBKey, Entry, Error, MemTable, Result, SSTableId, TableMeta};
 use serde::Serialize;
 use serde::de::DeserializeOwned;
-use std::collections::BTreeMap;
 use std::fs::OpenOptions;
 use std::io::{BufWriter, Write};
 use std::path::Path;
@@ -26,65 +25,90 @@ where
         level: usize,
         block_cache: Option<Arc<crate::db::cache::BlockCache<K, V>>>,
     ) -> Result<Self>
+    where
+        K: DBKey,
+        V: Serialize + DeserializeOwned + Send + Sync + 'static,
+        I: Iterator<Item = Result<Entry<K, V>>>,
+    {
+        Self::write_from_iter_with_options(
+            path,
+            iter,
+            id,
+            level,
+            block_cache,
+            &TableOptions::default(),
+        )
+    }
+
+    /// Writes a table laid out according to `options`.
+    pub fn write_from_iter_with_options<I>(
+        path: &Path,
+        iter: I,
+        id: SSTableId,
+        level: usize,
+        block_cache: Option<Arc<crate::db::cache::BlockCache<K, V>>>,
+        options: &TableOptions,
+    ) -> Result<Self>
     where
         K: DBKey,
         V: Serialize + DeserializeOwned + Send + Sync + 'static,
         I: Iterator<Item = Result<Entry<K, V>>>,
     {
         let file = OpenOptions::new().write(true).create_new(true).open(path)?;
-        let mut writer = BufWriter::new(file);
-        let mut sparse_index = BTreeMap::new();
+        let mut blocks = BlockSink::new(
+            BufWriter::new(file),
+            options.compression(level),
+            options.zstd_dictionary_size,
+        )?;
         let mut key_hashes = Vec::new();
 
         let mut min_key = None;
-        let mut max_key = None;
+        let mut max_key: Option<Arc<K>> = None;
         let mut num_entries = 0;
+        let mut max_seq = 0;
 
-        let mut current_offset = 0;
-        let mut builder = DeltaBlockBuilder::new(BLOCK_SIZE);
+        let mut builder =
+            DeltaBlockBuilder::with_restart_interval(options.block_size, options.restart_interval);
+        let mut block_first_key: Option<Arc<K>> = None;
 
         for item in iter {
             let entry = item?;
             if min_key.is_none() {
                 min_key = Some(Arc::clone(&entry.key));
             }
+            let is_new_key = max_key.as_ref() != Some(&entry.key);
             max_key = Some(Arc::clone(&entry.key));
             num_entries += 1;
+            max_seq = max_seq.max(entry.value.seq);
 
-            use std::collections::hash_map::DefaultHasher;
-            use std::hash::Hasher;
-            let mut s = DefaultHasher::new();
-            entry.key.hash(&mut s);
-            key_hashes.push(s.finish());
+            if is_new_key {
+                key_hashes.push(key_hash(entry.key.as_ref(), KEY_HASH_XXH3)?);
+            }
+            // The versions of one key may continue in the next block, which the
+            // index then lists with the same first key.
+            if builder.is_full()
+                && let Some(first_key) = block_first_key.take()
+            {
+                blocks.add(first_key, &builder.finish())?;
+            }
 
             if builder.is_empty() {
-                sparse_index.insert(Arc::clone(&entry.key), current_offset);
+                block_first_key = Some(Arc::clone(&entry.key));
             }
 
             builder.add(&entry.key, &entry.value);
-
-            if builder.is_full() {
-                let block = builder.finish();
-                let bytes_written = write_record(&mut writer, &block)?;
-                current_offset += bytes_written;
-            }
         }
 
-        if !builder.is_empty() {
-            let block = builder.finish();
-            let bytes_written = write_record(&mut writer, &block)?;
-            current_offset += bytes_written;
+        if let Some(first_key) = block_first_key.take() {
+            blocks.add(first_key, &builder.finish())?;
         }
+        let (mut writer, current_offset, sparse_index, dictionary) = blocks.finish()?;
 
         let min_key = min_key.ok_or_else(|| Error::Corruption("Empty SSTable".to_string()))?;
         let max_key = max_key.unwrap();
 
         let filter_offset: u64 = current_offset;
-        let filter_type = if level > 0 {
-            FILTER_TYPE_XOR16
-        } else {
-            FILTER_TYPE_XOR8
-        };
+        let filter_type = options.filter_policy.filter_type(level);
 
         let filter_size: u64 = if filter_type == FILTER_TYPE_XOR16 {
             let f = Xor16::from(&key_hashes);
@@ -103,9 +127,19 @@ where
             max_key: (*max_key).clone(),
             num_entries,
             filter_type,
-            compression_type: COMPRESSION_NONE,
+            compression_type: options.compression(level).compression_type(),
+            max_seq,
+            key_hash: KEY_HASH_XXH3,
+        };
+        let meta_size = write_record(&mut writer, &meta)?;
+
+        let dictionary_offset = match &dictionary {
+            Some(dictionary) => {
+                write_raw_record(&mut writer, dictionary)?;
+                meta_offset + meta_size
+            }
+            None => 0,
         };
-        write_record(&mut writer, &meta)?;
 
         writer.write_all(&filter_offset.to_le_bytes())?;
         writer.write_all(&index_offset.to_le_bytes())?;
@@ -113,9 +147,13 @@ where
         writer.write_all(&id.0.to_le_bytes())?;
         writer.write_all(&MAGIC_NUMBER.to_le_bytes())?;
         writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
-        writer.write_all(&[0u8; 20])?;
+        writer.write_all(&dictionary_offset.to_le_bytes())?;
+        writer.write_all(&[0u8; 12])?;
 
         writer.flush()?;
+        // A table is only ever listed in the manifest after this, so it is
+        // complete on disk before anything depends on it.
+        writer.get_ref().sync_all()?;
 
         Self::open(path, block_cache)
     }
@@ -130,3 +168,83 @@ where
         Self::write_from_iter(path, iter, id, 0, block_cache)
     }
 }
+
+/// Writes finished data blocks and records where each one starts. While a zstd
+/// dictionary is being trained, blocks are held back as its samples and written
+/// once it is ready.
+struct BlockSink<K, W> {
+    writer: W,
+    offset: u64,
+    index: Vec<(Arc<K>, u64)>,
+    compressor: Option<BlockCompressor>,
+    samples: Option<Vec<(Arc<K>, Vec<u8>)>>,
+    sample_bytes: usize,
+    dictionary_size: usize,
+}
+
+impl<K: DBKey, W: Write> BlockSink<K, W> {
+    fn new(writer: W, compression: Compression, dictionary_size: usize) -> Result<Self> {
+        let train = matches!(compression, Compression::Zstd { .. }) && dictionary_size > 0;
+        Ok(Self {
+            writer,
+            offset: 0,
+            index: Vec::new(),
+            compressor: match compression {
+                Compression::None => None,
+                _ => Some(BlockCompressor::new(compression)?),
+            },
+            samples: train.then(Vec::new),
+            sample_bytes: 0,
+            dictionary_size,
+        })
+    }
+
+    fn add<V: Serialize>(&mut self, first_key: Arc<K>, block: &DataBlock<K, V>) -> Result<()> {
+        let raw = bincode::serialize(block).map_err(|e| Error::Serialization(e.to_string()))?;
+        if let Some(samples) = &mut self.samples {
+            self.sample_bytes += raw.len();
+            samples.push((first_key, raw));
+            if self.sample_bytes >= BlockCompressor::sample_budget(self.dictionary_size) {
+                self.train()?;
+            }
+            return Ok(());
+        }
+        self.write(first_key, &raw)
+    }
+
+    fn train(&mut self) -> Result<()> {
+        let Some(samples) = self.samples.take() else {
+            return Ok(());
+        };
+        if let Some(compressor) = &mut self.compressor {
+            let raws: Vec<&[u8]> = samples.iter().map(|(_, raw)| raw.as_slice()).collect();
+            compressor.train_dictionary(&raws, self.dictionary_size)?;
+        }
+        for (first_key, raw) in samples {
+            self.write(first_key, &raw)?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, first_key: Arc<K>, raw: &[u8]) -> Result<()> {
+        let bytes_written = match &mut self.compressor {
+            Some(compressor) => write_raw_record(&mut self.writer, &compressor.compress(raw)?)?,
+            None => write_raw_record(&mut self.writer, raw)?,
+        };
+        self.index.push((first_key, self.offset));
+        self.offset += bytes_written;
+        Ok(())
+    }
+
+    /// Returns the writer, the end of the data blocks, the sparse index and the
+    /// trained dictionary.
+    #[allow(clippy::type_complexity)]
+    fn finish(mut self) -> Result<(W, u64, Vec<(Arc<K>, u64)>, Option<Vec<u8>>)> {
+        self.train()?;
+        let dictionary = self
+            .compressor
+            .as_ref()
+            .and_then(|c| c.dictionary().map(<[u8]>::to_vec));
+        Ok((self.writer, self.offset, self.index, dictionary))
+    }
+}
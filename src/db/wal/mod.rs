@@ -1,12 +1,72 @@
-use crate::db::io::{read_record, write_record};
+pub mod recovery;
+
+pub use recovery::{DroppedWalRange, WalRecoveryMode, WalRecoveryReport};
+
+use crate::db::io::{read_raw_record, write_raw_record};
+use crate::types::records::LegacyLogEntry;
 use crate::{DBKey, Error, LogEntry, Result};
-use crossbeam_channel::{Receiver, Sender, unbounded};
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender, unbounded};
+use parking_lot::Mutex;
 use serde::{Serialize, de::DeserializeOwned};
+use std::collections::VecDeque;
 use std::fs::{File, OpenOptions};
 use std::io::{BufReader, BufWriter, Write};
 use std::marker::PhantomData;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Current WAL format. Version 1 WALs have no header and their records carry no
+/// sequence numbers; version 2 starts each WAL with a header record, and
+/// version 3 writes each batch as a single record so it is recovered whole.
+pub const WAL_FORMAT_VERSION: u32 = 3;
+
+/// Start of the header record's payload, followed by the format version. No
+/// version 1 record starts this way, as those start with a small enum tag.
+const WAL_MAGIC: &[u8; 8] = b"GPDBWAL\0";
+
+/// The format version announced by a header record, or `None` if `data` is an
+/// ordinary record, as the first record of a version 1 WAL is.
+pub(crate) fn header_version(data: &[u8]) -> Option<u32> {
+    let version = data.strip_prefix(WAL_MAGIC)?;
+    Some(u32::from_le_bytes(version.try_into().ok()?))
+}
+
+/// Decodes the entries of one record of a WAL of the given format. Version 1
+/// entries are numbered on from `legacy_seq`, which is advanced past them.
+pub(crate) fn decode_record<K, V>(
+    format: u32,
+    data: &[u8],
+    legacy_seq: &mut u64,
+) -> Result<Vec<LogEntry<K, V>>>
+where
+    K: DeserializeOwned,
+    V: DeserializeOwned,
+{
+    let decode_error = |e: bincode::Error| Error::Serialization(e.to_string());
+    match format {
+        1 => {
+            let entry: LegacyLogEntry<K, V> = bincode::deserialize(data).map_err(decode_error)?;
+            *legacy_seq += 1;
+            Ok(vec![entry.with_seq(*legacy_seq)])
+        }
+        2 => Ok(vec![bincode::deserialize(data).map_err(decode_error)?]),
+        WAL_FORMAT_VERSION => bincode::deserialize(data).map_err(decode_error),
+        other => Err(Error::Corruption(format!(
+            "Unsupported WAL format version: {}",
+            other
+        ))),
+    }
+}
+
+/// Reads the format of the WAL at `path` from its first record; `None` if the
+/// WAL is still empty.
+fn read_format(path: &Path) -> Result<Option<u32>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    Ok(read_raw_record(&mut reader)?.map(|data| header_version(&data).unwrap_or(1)))
+}
 
 /// `Wal` provides a durable, write-ahead log.
 #[derive(Debug)]
@@ -17,6 +77,7 @@ where
 {
     path: PathBuf,
     writer: BufWriter<File>,
+    format: u32,
     _phantom: PhantomData<(K, V)>,
 }
 
@@ -32,30 +93,57 @@ where
             .truncate(true)
             .open(path)?;
 
-        Ok(Wal {
+        let mut wal = Wal {
             path: path.to_path_buf(),
             writer: BufWriter::new(file),
+            format: WAL_FORMAT_VERSION,
             _phantom: PhantomData,
-        })
+        };
+        wal.write_header()?;
+        Ok(wal)
     }
 
+    /// Opens an existing WAL for appending. Appends are always written in the
+    /// current format, so a WAL of an older `format_version` should be left to
+    /// replay rather than appended to.
     pub fn open(path: &Path) -> Result<Self> {
-        let file = OpenOptions::new().write(true).append(true).open(path)?;
+        let format = read_format(path)?;
+        let file = OpenOptions::new().append(true).open(path)?;
 
-        Ok(Wal {
+        let mut wal = Wal {
             path: path.to_path_buf(),
             writer: BufWriter::new(file),
+            format: format.unwrap_or(WAL_FORMAT_VERSION),
             _phantom: PhantomData,
-        })
+        };
+        if format.is_none() {
+            wal.write_header()?;
+        }
+        Ok(wal)
     }
 
-    pub fn append_batch(&mut self, entries: &[LogEntry<K, V>]) -> Result<()> {
-        for entry in entries {
-            write_record(&mut self.writer, entry)?;
-        }
+    /// The format version of the records already in this WAL.
+    pub fn format_version(&self) -> u32 {
+        self.format
+    }
+
+    fn write_header(&mut self) -> Result<()> {
+        let mut header = WAL_MAGIC.to_vec();
+        header.extend_from_slice(&WAL_FORMAT_VERSION.to_le_bytes());
+        write_raw_record(&mut self.writer, &header)?;
         Ok(())
     }
 
+    /// Appends `entries` as one record, which recovery keeps or drops whole,
+    /// and returns the bytes written.
+    /// Appends one write batch as a record. Returns the size of the encoded
+    /// batch, leaving out the frame header, as recovery sees it.
+    pub fn append_batch(&mut self, entries: &[LogEntry<K, V>]) -> Result<u64> {
+        let data = bincode::serialize(entries).map_err(|e| Error::Serialization(e.to_string()))?;
+        write_raw_record(&mut self.writer, &data)?;
+        Ok(data.len() as u64)
+    }
+
     pub fn clear(&mut self) -> Result<()> {
         let file = OpenOptions::new()
             .write(true)
@@ -64,31 +152,47 @@ where
             .open(&self.path)?;
 
         self.writer = BufWriter::new(file);
-        Ok(())
+        self.format = WAL_FORMAT_VERSION;
+        self.write_header()
     }
 
+    /// Writes out the buffered records and syncs them to disk.
     pub fn flush(&mut self) -> Result<()> {
         self.writer.flush()?;
         self.writer.get_ref().sync_all()?;
         Ok(())
     }
 
+    /// Hands the buffered records to the OS without syncing them, so they
+    /// survive a crash of the process but not of the machine.
+    pub fn flush_buffer(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
     pub fn iter(&self) -> Result<WalIterator<K, V>> {
         let file = OpenOptions::new().read(true).open(&self.path)?;
         Ok(WalIterator {
             reader: BufReader::new(file),
-            _phantom: PhantomData,
+            format: None,
+            legacy_seq: 0,
+            pending: VecDeque::new(),
         })
     }
 }
 
+/// Reads the entries of a WAL of any supported format. Entries of a version 1
+/// WAL are numbered from 1 in the order they were written.
 pub struct WalIterator<K, V>
 where
     K: Serialize + DeserializeOwned,
     V: Serialize + DeserializeOwned,
 {
     reader: BufReader<File>,
-    _phantom: PhantomData<(K, V)>,
+    /// Known once the first record is read.
+    format: Option<u32>,
+    legacy_seq: u64,
+    pending: VecDeque<LogEntry<K, V>>,
 }
 
 impl<K, V> Iterator for WalIterator<K, V>
@@ -99,13 +203,41 @@ where
     type Item = std::result::Result<LogEntry<K, V>, Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        read_record(&mut self.reader).transpose()
+        loop {
+            if let Some(entry) = self.pending.pop_front() {
+                return Some(Ok(entry));
+            }
+            let data = match read_raw_record(&mut self.reader) {
+                Ok(Some(data)) => data,
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            };
+            let format = match self.format {
+                Some(format) => format,
+                None => {
+                    let header = header_version(&data);
+                    self.format = Some(header.unwrap_or(1));
+                    if header.is_some() {
+                        continue;
+                    }
+                    1
+                }
+            };
+            match decode_record(format, &data, &mut self.legacy_seq) {
+                Ok(entries) => self.pending.extend(entries),
+                Err(e) => return Some(Err(e)),
+            }
+        }
     }
 }
 
 enum WalTask<K, V> {
     Write {
         entries: Arc<Vec<LogEntry<K, V>>>,
+        sync: bool,
+        resp_tx: Sender<Result<u64>>,
+    },
+    Sync {
         resp_tx: Sender<Result<()>>,
     },
     Rotate {
@@ -115,6 +247,10 @@ enum WalTask<K, V> {
         id: u64,
         resp_tx: Sender<Result<()>>,
     },
+    /// Syncs the current WAL and stops the worker.
+    Shutdown {
+        resp_tx: Sender<Result<()>>,
+    },
 }
 
 /// `WalManager` coordinates Group Commits and WAL rotation.
@@ -125,6 +261,8 @@ where
     V: Serialize + DeserializeOwned + Send + Sync + 'static,
 {
     task_tx: Sender<WalTask<K, V>>,
+    worker: Mutex<Option<JoinHandle<()>>>,
+    syncs: Arc<AtomicU64>,
 }
 
 impl<K, V> WalManager<K, V>
@@ -132,126 +270,305 @@ where
     K: DBKey + Send + Sync + 'static,
     V: Serialize + DeserializeOwned + Send + Sync + 'static,
 {
-    pub fn new(dir: PathBuf, mut current_id: u64) -> Result<Self> {
-        let (task_tx, task_rx): (Sender<WalTask<K, V>>, Receiver<WalTask<K, V>>) = unbounded();
+    pub fn new(dir: PathBuf, current_id: u64) -> Result<Self> {
+        Self::with_sync_interval(dir, current_id, None)
+    }
+
+    /// Like `new`, but the worker also syncs writes submitted without `sync`
+    /// once `sync_interval` has passed since they were written.
+    pub fn with_sync_interval(
+        dir: PathBuf,
+        current_id: u64,
+        sync_interval: Option<Duration>,
+    ) -> Result<Self> {
+        let (task_tx, task_rx) = unbounded::<WalTask<K, V>>();
 
+        let mut current_id = current_id;
         let wal_path = dir.join(format!("{:06}.wal", current_id));
         let mut wal = if wal_path.exists() {
             Wal::open(&wal_path)?
         } else {
             Wal::create(&wal_path)?
         };
+        // A WAL of an older format is left as it is for replay.
+        if wal.format_version() != WAL_FORMAT_VERSION {
+            current_id += 1;
+            wal = Wal::create(&dir.join(format!("{:06}.wal", current_id)))?;
+        }
 
-        std::thread::spawn(move || {
-            while let Ok(first_task) = task_rx.recv() {
-                match first_task {
-                    WalTask::Write { entries, resp_tx } => {
-                        let mut batch_resps = vec![resp_tx];
-                        let mut next_rotate = None;
-
-                        // Start batch by appending first request
-                        let mut result = wal.append_batch(&entries);
-
-                        // Group multiple writes if first succeeded
-                        if result.is_ok() {
-                            while let Ok(next_task) = task_rx.try_recv() {
-                                match next_task {
-                                    WalTask::Write {
-                                        entries: next_entries,
-                                        resp_tx: next_resp,
-                                    } => {
-                                        result = wal.append_batch(&next_entries);
-                                        batch_resps.push(next_resp);
-                                        if result.is_err() {
-                                            break;
-                                        }
-                                    }
-                                    WalTask::Rotate { resp_tx: rot_tx } => {
-                                        next_rotate = Some(rot_tx);
-                                        break;
-                                    }
-                                    _ => break,
-                                }
-                                if batch_resps.len() >= 1024 {
-                                    break;
-                                }
-                            }
-                        }
+        let syncs = Arc::new(AtomicU64::new(0));
+        let worker = WalWorker {
+            dir,
+            current_id,
+            wal,
+            sync_interval,
+            unsynced_since: None,
+            syncs: Arc::clone(&syncs),
+            sync_error: None,
+        };
+        let handle = std::thread::spawn(move || worker.run(task_rx));
 
-                        // Flush only if all appends succeeded
-                        if result.is_ok() {
-                            result = wal.flush();
-                        }
+        Ok(Self {
+            task_tx,
+            worker: Mutex::new(Some(handle)),
+            syncs,
+        })
+    }
 
-                        for r in batch_resps {
-                            let _ = r.send(result.clone());
-                        }
+    /// How many times the WAL has been synced to disk since it was opened.
+    pub fn sync_count(&self) -> u64 {
+        self.syncs.load(Ordering::Relaxed)
+    }
 
-                        if let Some(resp) = next_rotate {
-                            let old_id = current_id;
-                            current_id += 1;
-                            let new_path = dir.join(format!("{:06}.wal", current_id));
-                            match Wal::create(&new_path) {
-                                Ok(new_wal) => {
-                                    wal = new_wal;
-                                    let _ = resp.send(Ok(old_id));
-                                }
-                                Err(e) => {
-                                    let _ = resp.send(Err(e));
-                                }
-                            }
-                        }
-                    }
-                    WalTask::Rotate { resp_tx } => {
-                        let res = wal.flush().and_then(|_| {
-                            let old_id = current_id;
-                            current_id += 1;
-                            let new_path = dir.join(format!("{:06}.wal", current_id));
-                            Wal::create(&new_path).map(|new_wal| {
-                                wal = new_wal;
-                                old_id
-                            })
-                        });
-                        let _ = resp_tx.send(res);
-                    }
-                    WalTask::Delete { id, resp_tx } => {
-                        let path = dir.join(format!("{:06}.wal", id));
-                        let _ = resp_tx.send(std::fs::remove_file(path).map_err(Into::into));
-                    }
-                }
-            }
-        });
+    pub fn submit(&self, entries: Arc<Vec<LogEntry<K, V>>>) -> Result<u64> {
+        self.submit_with_sync(entries, true)
+    }
 
-        Ok(Self { task_tx })
+    /// Appends `entries` as part of a group commit. Without `sync` the call
+    /// returns once they are handed to the OS, and a later sync makes them
+    /// durable; a group is synced when any of its writes asks for it. Returns
+    /// the bytes the entries took up in the WAL.
+    pub fn submit_with_sync(&self, entries: Arc<Vec<LogEntry<K, V>>>, sync: bool) -> Result<u64> {
+        let (resp_tx, resp_rx) = unbounded();
+        self.send(WalTask::Write {
+            entries,
+            sync,
+            resp_tx,
+        })?;
+        Self::wait(resp_rx)
     }
 
-    pub fn submit(&self, entries: Arc<Vec<LogEntry<K, V>>>) -> Result<()> {
+    /// Syncs everything written to the current WAL so far.
+    pub fn sync(&self) -> Result<()> {
         let (resp_tx, resp_rx) = unbounded();
-        self.task_tx
-            .send(WalTask::Write { entries, resp_tx })
-            .map_err(|_| Error::Corruption("WAL worker crashed".into()))?;
-        resp_rx
-            .recv()
-            .map_err(|_| Error::Corruption("WAL worker dropped response".into()))?
+        self.send(WalTask::Sync { resp_tx })?;
+        Self::wait(resp_rx)
     }
 
     pub fn rotate(&self) -> Result<u64> {
         let (resp_tx, resp_rx) = unbounded();
-        self.task_tx
-            .send(WalTask::Rotate { resp_tx })
-            .map_err(|_| Error::Corruption("WAL worker crashed".into()))?;
-        resp_rx
-            .recv()
-            .map_err(|_| Error::Corruption("WAL worker dropped response".into()))?
+        self.send(WalTask::Rotate { resp_tx })?;
+        Self::wait(resp_rx)
     }
 
     pub fn delete(&self, id: u64) -> Result<()> {
         let (resp_tx, resp_rx) = unbounded();
+        self.send(WalTask::Delete { id, resp_tx })?;
+        Self::wait(resp_rx)
+    }
+
+    /// Syncs the current WAL and joins the worker thread. Later calls are no-ops.
+    pub fn close(&self) -> Result<()> {
+        let Some(handle) = self.worker.lock().take() else {
+            return Ok(());
+        };
+        let (resp_tx, resp_rx) = unbounded();
+        self.send(WalTask::Shutdown { resp_tx })?;
+        let result = Self::wait(resp_rx);
+        handle
+            .join()
+            .map_err(|_| Error::Corruption("WAL worker panicked".into()))?;
+        result
+    }
+
+    fn send(&self, task: WalTask<K, V>) -> Result<()> {
         self.task_tx
-            .send(WalTask::Delete { id, resp_tx })
-            .map_err(|_| Error::Corruption("WAL worker crashed".into()))?;
+            .send(task)
+            .map_err(|_| Error::Corruption("WAL worker crashed".into()))
+    }
+
+    fn wait<T>(resp_rx: Receiver<Result<T>>) -> Result<T> {
         resp_rx
             .recv()
             .map_err(|_| Error::Corruption("WAL worker dropped response".into()))?
     }
 }
+
+struct WalWorker<K, V>
+where
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+{
+    dir: PathBuf,
+    current_id: u64,
+    wal: Wal<K, V>,
+    sync_interval: Option<Duration>,
+    /// When the oldest write not yet synced was written.
+    unsynced_since: Option<Instant>,
+    syncs: Arc<AtomicU64>,
+    /// A failed periodic sync, held until the next write or sync reports it.
+    sync_error: Option<Error>,
+}
+
+impl<K, V> WalWorker<K, V>
+where
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+{
+    fn run(mut self, task_rx: Receiver<WalTask<K, V>>) {
+        let mut deferred = None;
+        loop {
+            let task = match deferred.take() {
+                Some(task) => task,
+                None => match self.sync_due() {
+                    Some(due) => match task_rx.recv_deadline(due) {
+                        Ok(task) => task,
+                        Err(RecvTimeoutError::Timeout) => {
+                            self.sync_if_due();
+                            continue;
+                        }
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    },
+                    None => match task_rx.recv() {
+                        Ok(task) => task,
+                        Err(_) => break,
+                    },
+                },
+            };
+            match task {
+                WalTask::Write {
+                    entries,
+                    sync,
+                    resp_tx,
+                } => {
+                    if let Err(e) = self.take_sync_error() {
+                        let _ = resp_tx.send(Err(e));
+                        continue;
+                    }
+                    deferred = self.write_group(&task_rx, &entries, sync, resp_tx);
+                    // A steady stream of writes never lets the wait above time out.
+                    self.sync_if_due();
+                }
+                WalTask::Sync { resp_tx } => {
+                    let result = self.sync();
+                    let _ = resp_tx.send(self.take_sync_error().and(result));
+                }
+                WalTask::Rotate { resp_tx } => {
+                    let _ = resp_tx.send(self.rotate());
+                }
+                WalTask::Delete { id, resp_tx } => {
+                    let path = self.dir.join(format!("{:06}.wal", id));
+                    let _ = resp_tx.send(std::fs::remove_file(path).map_err(Into::into));
+                }
+                WalTask::Shutdown { resp_tx } => {
+                    let result = self.sync();
+                    let _ = resp_tx.send(self.take_sync_error().and(result));
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Appends the first write plus every write already queued behind it, then
+    /// syncs once for the whole group if any of them asked for it. A queued
+    /// non-write task ends the group and is handed back to run next.
+    fn write_group(
+        &mut self,
+        task_rx: &Receiver<WalTask<K, V>>,
+        entries: &[LogEntry<K, V>],
+        sync: bool,
+        resp_tx: Sender<Result<u64>>,
+    ) -> Option<WalTask<K, V>> {
+        let mut sync = sync;
+        let mut batch_resps = Vec::new();
+        let mut deferred = None;
+
+        // Start batch by appending first request
+        let mut result = match self.wal.append_batch(entries) {
+            Ok(bytes) => {
+                batch_resps.push((resp_tx, bytes));
+                Ok(())
+            }
+            Err(e) => {
+                let _ = resp_tx.send(Err(e.clone()));
+                Err(e)
+            }
+        };
+
+        // Group multiple writes if first succeeded
+        if result.is_ok() {
+            while let Ok(next_task) = task_rx.try_recv() {
+                match next_task {
+                    WalTask::Write {
+                        entries: next_entries,
+                        sync: next_sync,
+                        resp_tx: next_resp,
+                    } => {
+                        sync |= next_sync;
+                        match self.wal.append_batch(&next_entries) {
+                            Ok(bytes) => batch_resps.push((next_resp, bytes)),
+                            Err(e) => {
+                                let _ = next_resp.send(Err(e.clone()));
+                                result = Err(e);
+                                break;
+                            }
+                        }
+                    }
+                    other => {
+                        deferred = Some(other);
+                        break;
+                    }
+                }
+                if batch_resps.len() >= 1024 {
+                    break;
+                }
+            }
+        }
+
+        // Flush only if all appends succeeded
+        if result.is_ok() {
+            result = if sync {
+                self.sync()
+            } else {
+                self.unsynced_since.get_or_insert_with(Instant::now);
+                self.wal.flush_buffer()
+            };
+        }
+
+        for (r, bytes) in batch_resps {
+            let _ = r.send(result.clone().map(|()| bytes));
+        }
+        deferred
+    }
+
+    /// Syncs the current WAL, which covers every write not yet synced.
+    fn sync(&mut self) -> Result<()> {
+        self.wal.flush()?;
+        self.unsynced_since = None;
+        self.syncs.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// When the periodic sync, if enabled, has to sync the pending writes.
+    fn sync_due(&self) -> Option<Instant> {
+        Some(self.unsynced_since? + self.sync_interval?)
+    }
+
+    /// Runs the periodic sync if it is due. A failure is kept for the next write
+    /// or sync to return, and the sync is tried again one interval later.
+    fn sync_if_due(&mut self) {
+        if self.sync_due().is_some_and(|due| due <= Instant::now())
+            && let Err(e) = self.sync()
+        {
+            self.unsynced_since = Some(Instant::now());
+            self.sync_error = Some(e);
+        }
+    }
+
+    /// Returns the error of a failed periodic sync, once.
+    fn take_sync_error(&mut self) -> Result<()> {
+        match self.sync_error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn rotate(&mut self) -> Result<u64> {
+        self.sync()?;
+        let old_id = self.current_id;
+        let new_path = self.dir.join(format!("{:06}.wal", old_id + 1));
+        self.wal = Wal::create(&new_path)?;
+        self.current_id = old_id + 1;
+        Ok(old_id)
+    }
+}
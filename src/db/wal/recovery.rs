@@ -0,0 +1,223 @@
+use crate::db::io::read_raw_record;
+use crate::db::wal::{decode_record, header_version};
+use crate::{Error, LogEntry, Result};
+use serde::{Serialize, de::DeserializeOwned};
+use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
+
+/// Checksum (4) and length (8) in front of every WAL record.
+const RECORD_HEADER_SIZE: usize = 12;
+
+/// How `DB::open` treats damaged records while replaying the WALs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WalRecoveryMode {
+    /// Any damaged record fails the open.
+    #[default]
+    AbsoluteConsistency,
+    /// Drops a damaged record at the very end of a WAL, as left by a write torn
+    /// by a crash, and fails on damage anywhere else.
+    TolerateCorruptedTailRecords,
+    /// Replays up to the first damaged record and drops everything after it,
+    /// later WALs included, so the database is as of one point in time.
+    PointInTime,
+    /// Steps over every damaged record whose length is intact and replays the
+    /// rest. Each batch is one record, so it is kept or dropped as a whole, but
+    /// a batch may survive while earlier ones do not.
+    SkipAnyCorruptedRecords,
+}
+
+/// A stretch of a WAL left out by recovery.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DroppedWalRange {
+    pub wal_id: u64,
+    /// Offset of the first byte left out.
+    pub offset: u64,
+    pub bytes: u64,
+    /// The damage found at `offset`, or why an intact WAL was left out.
+    pub reason: String,
+}
+
+/// What replaying the WALs on open recovered and dropped, from
+/// `DB::wal_recovery_report`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WalRecoveryReport {
+    pub wals_replayed: usize,
+    pub records_replayed: u64,
+    pub dropped: Vec<DroppedWalRange>,
+}
+
+impl WalRecoveryReport {
+    /// Whether every record of every WAL was replayed.
+    pub fn is_clean(&self) -> bool {
+        self.dropped.is_empty()
+    }
+
+    pub fn dropped_bytes(&self) -> u64 {
+        self.dropped.iter().map(|range| range.bytes).sum()
+    }
+}
+
+/// What recovery does to the WAL files it dropped records from. Nothing is
+/// changed until `apply`, which the open calls once everything else succeeded,
+/// so a failed open leaves the WALs as it found them.
+#[derive(Debug, Default)]
+pub(crate) struct WalRepairs {
+    /// WALs to cut back to the end of their last record kept, so that nothing
+    /// is ever replayed from behind the damage.
+    truncate: Vec<(PathBuf, u64)>,
+    /// WALs that point-in-time recovery left out entirely.
+    remove: Vec<PathBuf>,
+}
+
+impl WalRepairs {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.truncate.is_empty() && self.remove.is_empty()
+    }
+
+    pub(crate) fn apply(self) -> Result<()> {
+        for (path, len) in self.truncate {
+            truncate(&path, len)?;
+        }
+        for path in self.remove {
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+/// One step through the bytes of a WAL.
+enum Frame {
+    /// The payload of an intact record and the offset of the next one.
+    Record(Vec<u8>, usize),
+    /// A damaged record whose length is intact, so reading can go on at `next`.
+    Damaged { error: Error, next: usize },
+    /// A damaged record that nothing after can be told apart from.
+    Torn(Error),
+}
+
+/// Replays the WALs, sorted by id, into `apply` the way `mode` asks for, and
+/// returns the repairs that make the WALs match what was replayed. `apply` is
+/// handed the entries of each record along with its encoded size. Entries of
+/// version 1 WALs, which carry no sequence numbers, are numbered on from
+/// `last_sequence`.
+pub(crate) fn replay_wals<K, V>(
+    wals: &[(u64, PathBuf)],
+    mode: WalRecoveryMode,
+    last_sequence: u64,
+    mut apply: impl FnMut(&[LogEntry<K, V>], usize),
+) -> Result<(WalRecoveryReport, WalRepairs)>
+where
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+{
+    let mut report = WalRecoveryReport::default();
+    let mut repairs = WalRepairs::default();
+    let mut stopped_at = None;
+    let mut legacy_seq = last_sequence;
+
+    for (id, path) in wals {
+        if let Some(stopped_at) = stopped_at {
+            let bytes = std::fs::metadata(path)?.len();
+            repairs.remove.push(path.clone());
+            report.dropped.push(DroppedWalRange {
+                wal_id: *id,
+                offset: 0,
+                bytes,
+                reason: format!("Recovery stopped at damage in WAL {}", stopped_at),
+            });
+            continue;
+        }
+
+        let bytes = std::fs::read(path)?;
+        let mut offset = 0;
+        let mut format = None;
+        while offset < bytes.len() {
+            let (error, next) = match read_frame(&bytes, offset) {
+                Frame::Record(data, next) => {
+                    let record_format = match format {
+                        Some(format) => format,
+                        None => {
+                            let header = header_version(&data);
+                            format = Some(header.unwrap_or(1));
+                            if header.is_some() {
+                                offset = next;
+                                continue;
+                            }
+                            1
+                        }
+                    };
+                    match decode_record(record_format, &data, &mut legacy_seq) {
+                        Ok(entries) => {
+                            apply(&entries, data.len());
+                            report.records_replayed += 1;
+                            offset = next;
+                            continue;
+                        }
+                        Err(e @ Error::Serialization(_)) => (e, Some(next)),
+                        Err(e) => return Err(e),
+                    }
+                }
+                Frame::Damaged { error, next } => (error, Some(next)),
+                Frame::Torn(error) => (error, None),
+            };
+            let at_tail = next.is_none_or(|next| next == bytes.len());
+
+            match (mode, next) {
+                (WalRecoveryMode::AbsoluteConsistency, _) => return Err(error),
+                (WalRecoveryMode::TolerateCorruptedTailRecords, _) if !at_tail => {
+                    return Err(error);
+                }
+                (WalRecoveryMode::SkipAnyCorruptedRecords, Some(next)) if !at_tail => {
+                    report.dropped.push(DroppedWalRange {
+                        wal_id: *id,
+                        offset: offset as u64,
+                        bytes: (next - offset) as u64,
+                        reason: error.to_string(),
+                    });
+                    offset = next;
+                    continue;
+                }
+                (WalRecoveryMode::PointInTime, _) => stopped_at = Some(*id),
+                _ => {}
+            }
+
+            repairs.truncate.push((path.clone(), offset as u64));
+            report.dropped.push(DroppedWalRange {
+                wal_id: *id,
+                offset: offset as u64,
+                bytes: (bytes.len() - offset) as u64,
+                reason: error.to_string(),
+            });
+            break;
+        }
+        report.wals_replayed += 1;
+    }
+    Ok((report, repairs))
+}
+
+fn read_frame(bytes: &[u8], offset: usize) -> Frame {
+    let mut rest = &bytes[offset..];
+    let error = match read_raw_record(&mut rest) {
+        Ok(Some(data)) => return Frame::Record(data, bytes.len() - rest.len()),
+        Ok(None) => Error::Corruption("Unexpected EOF while reading record checksum".to_string()),
+        Err(e) => e,
+    };
+
+    // A record can be stepped over when its length still fits in the file.
+    let next = bytes
+        .get(offset + 4..offset + RECORD_HEADER_SIZE)
+        .and_then(|len| usize::try_from(u64::from_le_bytes(len.try_into().ok()?)).ok())
+        .and_then(|len| (offset + RECORD_HEADER_SIZE).checked_add(len))
+        .filter(|&next| next <= bytes.len());
+    match next {
+        Some(next) => Frame::Damaged { error, next },
+        None => Frame::Torn(error),
+    }
+}
+
+fn truncate(path: &Path, len: u64) -> Result<()> {
+    let file = OpenOptions::new().write(true).open(path)?;
+    file.set_len(len)?;
+    file.sync_all()?;
+    Ok(())
+}
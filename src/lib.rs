@@ -1,11 +1,14 @@
 pub mod db;
 pub mod types;
 
+pub use db::compaction::strategy::*;
 pub use db::compaction::stream::*;
 pub use db::compaction::*;
 pub use db::sstable::datablock::*;
 pub use db::*;
 pub use types::{
-    batch::*, records::*, result::*, sstable::COMPRESSION_NONE, sstable::COMPRESSION_ZSTD,
-    sstable::FILTER_TYPE_XOR8, sstable::FILTER_TYPE_XOR16, sstable::SSTableId, sstable::TableMeta,
+    batch::*, records::*, result::*, sstable::COMPRESSION_LZ4, sstable::COMPRESSION_NONE,
+    sstable::COMPRESSION_SNAPPY, sstable::COMPRESSION_ZSTD, sstable::FILTER_TYPE_XOR8,
+    sstable::FILTER_TYPE_XOR16, sstable::KEY_HASH_DEFAULT_HASHER, sstable::KEY_HASH_XXH3,
+    sstable::SSTableId, sstable::TableMeta,
 };
@@ -9,6 +9,24 @@ pub trait DBKey: Eq + Hash + Ord + Clone + Serialize + DeserializeOwned + std::f
 impl<T> DBKey for T where T: Eq + Hash + Ord + Clone + Serialize + DeserializeOwned + std::fmt::Debug
 {}
 
+/// Keys whose ordering places every key sharing a prefix in one contiguous run,
+/// which is what lets `DB::prefix_iter` stop at the first non-matching key.
+pub trait PrefixKey: DBKey {
+    fn has_prefix(&self, prefix: &Self) -> bool;
+}
+
+impl PrefixKey for String {
+    fn has_prefix(&self, prefix: &Self) -> bool {
+        self.starts_with(prefix.as_str())
+    }
+}
+
+impl PrefixKey for Vec<u8> {
+    fn has_prefix(&self, prefix: &Self) -> bool {
+        self.starts_with(prefix)
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 /// A full key-value pair as stored in an SSTable data block or returned by iterators.
 pub struct Entry<K, V> {
@@ -26,32 +44,89 @@ impl<K: Clone, V> Clone for Entry<K, V> {
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
+/// A single WAL record. The trailing `u64` is the operation's sequence number.
 pub enum LogEntry<K, V> {
+    Put(Arc<K>, Arc<V>, u64),
+    Delete(Arc<K>, u64),
+}
+
+impl<K, V> LogEntry<K, V> {
+    pub fn seq(&self) -> u64 {
+        match self {
+            Self::Put(_, _, seq) | Self::Delete(_, seq) => *seq,
+        }
+    }
+}
+
+/// A WAL record of format version 1, written before writes had sequence numbers.
+#[derive(Deserialize)]
+pub(crate) enum LegacyLogEntry<K, V> {
     Put(Arc<K>, Arc<V>),
     Delete(Arc<K>),
 }
 
+impl<K, V> LegacyLogEntry<K, V> {
+    pub(crate) fn with_seq(self, seq: u64) -> LogEntry<K, V> {
+        match self {
+            Self::Put(key, value) => LogEntry::Put(key, value, seq),
+            Self::Delete(key) => LogEntry::Delete(key, seq),
+        }
+    }
+}
+
 impl<K: Clone, V> Clone for LogEntry<K, V> {
     fn clone(&self) -> Self {
         match self {
-            Self::Put(k, v) => Self::Put(Arc::clone(k), Arc::clone(v)),
-            Self::Delete(k) => Self::Delete(Arc::clone(k)),
+            Self::Put(k, v, seq) => Self::Put(Arc::clone(k), Arc::clone(v), *seq),
+            Self::Delete(k, seq) => Self::Delete(Arc::clone(k), *seq),
         }
     }
 }
 
+/// One change to the set of live SSTables. It is written as a single checksummed
+/// manifest frame, so replay applies all of it or, if the frame was torn, none.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct VersionEdit {
+    /// `(level, file name)` of each table that is no longer live.
+    pub removed: Vec<(usize, PathBuf)>,
+    /// `(level, file name)` of each new table.
+    pub added: Vec<(usize, PathBuf)>,
+    pub next_id: Option<SSTableId>,
+    pub log_number: Option<u64>,
+}
+
+/// A manifest record. Edits are written as `Edit`; the single-table records are
+/// what older versions wrote and are only replayed.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum ManifestEntry {
-    AddSSTable { level: usize, path: PathBuf },
-    RemoveSSTable { level: usize, path: PathBuf },
+    AddSSTable {
+        level: usize,
+        path: PathBuf,
+    },
+    RemoveSSTable {
+        level: usize,
+        path: PathBuf,
+    },
     NextID(SSTableId),
+    /// Every WAL with a lower id has been flushed to an SSTable.
+    LogNumber(u64),
+    /// The complete state at the start of a manifest file: the live tables of
+    /// each level, plus the values of `NextID` and `LogNumber`.
+    Snapshot {
+        levels: Vec<Vec<PathBuf>>,
+        next_id: SSTableId,
+        log_number: u64,
+    },
+    Edit(VersionEdit),
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 /// The value part of a database entry, including tombstone information.
+/// `seq` is the monotonic sequence number of the write that produced it.
 pub struct ValueEntry<V> {
     pub value: Option<Arc<V>>,
     pub is_tombstone: bool,
+    pub seq: u64,
 }
 
 impl<V> Clone for ValueEntry<V> {
@@ -59,6 +134,7 @@ impl<V> Clone for ValueEntry<V> {
         Self {
             value: self.value.as_ref().map(Arc::clone),
             is_tombstone: self.is_tombstone,
+            seq: self.seq,
         }
     }
 }
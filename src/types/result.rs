@@ -14,6 +14,9 @@ pub enum Error {
 
     #[error("Invalid data: {0}")]
     InvalidData(String),
+
+    #[error("Database is closed")]
+    Closed,
 }
 
 impl From<std::io::Error> for Error {
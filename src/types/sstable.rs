@@ -15,6 +15,14 @@ pub const FILTER_TYPE_XOR16: u8 = 1;
 
 pub const COMPRESSION_NONE: u8 = 0;
 pub const COMPRESSION_ZSTD: u8 = 1;
+pub const COMPRESSION_LZ4: u8 = 2;
+pub const COMPRESSION_SNAPPY: u8 = 3;
+
+/// `std`'s `DefaultHasher` over the key's `Hash` impl. Its output may change
+/// between Rust releases; only tables written before format version 4 use it.
+pub const KEY_HASH_DEFAULT_HASHER: u8 = 0;
+/// xxh3-64 over the bincode encoding of the key, which is fixed by its specification.
+pub const KEY_HASH_XXH3: u8 = 1;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 /// Metadata for an SSTable, stored in the file.
@@ -24,4 +32,57 @@ pub struct TableMeta<K> {
     pub num_entries: u64,
     pub filter_type: u8,
     pub compression_type: u8,
+    pub max_seq: u64,
+    /// One of the `KEY_HASH_*` constants: how the filter's keys were hashed.
+    pub key_hash: u8,
+}
+
+/// `TableMeta` as written by format versions 2 and 3, before `key_hash`.
+#[derive(Deserialize)]
+pub(crate) struct LegacyTableMeta<K> {
+    pub min_key: K,
+    pub max_key: K,
+    pub num_entries: u64,
+    pub filter_type: u8,
+    pub compression_type: u8,
+    pub max_seq: u64,
+}
+
+impl<K> From<LegacyTableMeta<K>> for TableMeta<K> {
+    fn from(meta: LegacyTableMeta<K>) -> Self {
+        Self {
+            min_key: meta.min_key,
+            max_key: meta.max_key,
+            num_entries: meta.num_entries,
+            filter_type: meta.filter_type,
+            compression_type: meta.compression_type,
+            max_seq: meta.max_seq,
+            key_hash: KEY_HASH_DEFAULT_HASHER,
+        }
+    }
+}
+
+/// `TableMeta` as written by format version 1, before sequence numbers. Its
+/// entries all count as written at sequence number 0.
+#[derive(Deserialize)]
+pub(crate) struct TableMetaV1<K> {
+    pub min_key: K,
+    pub max_key: K,
+    pub num_entries: u64,
+    pub filter_type: u8,
+    pub compression_type: u8,
+}
+
+impl<K> From<TableMetaV1<K>> for TableMeta<K> {
+    fn from(meta: TableMetaV1<K>) -> Self {
+        Self {
+            min_key: meta.min_key,
+            max_key: meta.max_key,
+            num_entries: meta.num_entries,
+            filter_type: meta.filter_type,
+            compression_type: meta.compression_type,
+            max_seq: 0,
+            key_hash: KEY_HASH_DEFAULT_HASHER,
+        }
+    }
 }
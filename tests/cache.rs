@@ -1,7 +1,8 @@
-use gpdb::SSTableId;
 use gpdb::db::cache::BlockCache;
 use gpdb::db::sstable::datablock::DataBlock;
+use gpdb::{Entry, ReadOptions, SSTable, SSTableId, ValueEntry};
 use std::sync::Arc;
+use tempfile::TempDir;
 
 #[test]
 fn test_block_cache_hit_miss() {
@@ -25,3 +26,79 @@ fn test_block_cache_hit_miss() {
     // Different offset -> Miss
     assert!(cache.get(sst_id, 200).is_none());
 }
+
+#[test]
+fn iterators_fill_the_cache_only_when_asked() {
+    let tmp_dir = TempDir::new().unwrap();
+    let entries = (0..2000u64).map(|i| {
+        Ok(Entry {
+            key: Arc::new(format!("key:{:05}", i)),
+            value: ValueEntry {
+                value: Some(Arc::new("x".repeat(50))),
+                is_tombstone: false,
+                seq: i,
+            },
+        })
+    });
+    let mut sst: SSTable<String, String> = SSTable::write_from_iter(
+        &tmp_dir.path().join("L1-1.sst"),
+        entries,
+        SSTableId(1),
+        1,
+        None,
+    )
+    .unwrap();
+    let cache = Arc::new(BlockCache::new(1024 * 1024));
+    sst.set_cache(Arc::clone(&cache));
+
+    let scan = sst
+        .range_opt(.., &ReadOptions { fill_cache: false })
+        .unwrap();
+    assert_eq!(scan.count(), 2000);
+    assert!(cache.get(SSTableId(1), 0).is_none());
+
+    let key = "key:00001".to_string();
+    let found = sst
+        .get_at_opt(&key, u64::MAX, &ReadOptions { fill_cache: false })
+        .unwrap();
+    assert!(found.is_some());
+    assert!(cache.get(SSTableId(1), 0).is_none());
+
+    assert_eq!(sst.iter().unwrap().count(), 2000);
+    assert!(cache.get(SSTableId(1), 0).is_some());
+}
+
+#[test]
+fn cache_weighs_blocks_by_size() {
+    let cache: BlockCache<String, String> = BlockCache::new(64 * 1024);
+
+    cache.insert(
+        SSTableId(1),
+        0,
+        Arc::new(DataBlock::new(vec![0; 100], vec![0])),
+    );
+    assert!(cache.get(SSTableId(1), 0).is_some());
+    assert!(cache.get(SSTableId(2), 0).is_none());
+    let stats = cache.stats();
+    assert_eq!((stats.hits, stats.misses), (1, 1));
+    assert_eq!(stats.entries, 1);
+    assert!(stats.used_bytes >= 100);
+
+    // Blocks four times the default block size: only a handful fit.
+    for offset in 1..=32 {
+        let block = Arc::new(DataBlock::new(vec![0; 16 * 1024], vec![0]));
+        cache.insert(SSTableId(1), offset, block);
+    }
+    let stats = cache.stats();
+    assert_eq!(stats.capacity_bytes, 64 * 1024);
+    assert!(stats.used_bytes <= stats.capacity_bytes);
+    assert!(stats.entries <= 4, "{:?}", stats);
+    assert!(stats.evictions >= 28, "{:?}", stats);
+}
+
+#[test]
+#[allow(deprecated)]
+fn block_cache_with_block_size_still_sizes_by_bytes() {
+    let cache: BlockCache<String, String> = BlockCache::with_block_size(1024 * 1024, 4096);
+    assert_eq!(cache.capacity_bytes(), 1024 * 1024);
+}
@@ -1,4 +1,7 @@
-use gpdb::{Compactor, Entry, MemTable, MergeElement, MergeStream, SSTable, SSTableId, ValueEntry};
+use gpdb::{
+    Compactor, Entry, IdAllocator, MemTable, MergeElement, MergeStream, SSTable, SSTableId,
+    TableOptions, ValueEntry,
+};
 use std::sync::Arc;
 use tempfile::TempDir;
 
@@ -10,6 +13,7 @@ fn make_el(key: &str, id: u64, iter_idx: usize) -> MergeElement<String, String>
             value: ValueEntry {
                 value: Some(Arc::new("val".to_string())),
                 is_tombstone: false,
+                seq: 0,
             },
         },
         iter_index: iter_idx,
@@ -132,3 +136,170 @@ fn compactor_l0_to_disk() {
     let val_c = sst_l1.get(&"C".to_string()).unwrap().unwrap();
     assert!(val_c.value.is_some());
 }
+
+#[test]
+fn merge_stream_retains_snapshot_versions() {
+    let tmp_dir = TempDir::new().unwrap();
+
+    let mem = MemTable::new();
+    for (seq, val) in [(3, "v3"), (5, "v5"), (8, "v8")] {
+        mem.insert(
+            Arc::new("A".to_string()),
+            ValueEntry {
+                value: Some(Arc::new(val.to_string())),
+                is_tombstone: false,
+                seq,
+            },
+        );
+    }
+    let path = tmp_dir.path().join("L0-1.sst");
+    let sst = SSTable::write_from_memtable(&path, &mem, SSTableId(1), None).unwrap();
+    assert_eq!(sst.len(), 3);
+    assert_eq!(sst.max_seq(), 8);
+
+    let newest_only: Vec<_> = MergeStream::new(std::slice::from_ref(&sst))
+        .unwrap()
+        .map(|r| r.unwrap().value.seq)
+        .collect();
+    assert_eq!(newest_only, vec![8]);
+
+    // A snapshot at 6 needs v5; nothing needs v3.
+    let retained: Vec<_> = MergeStream::new(std::slice::from_ref(&sst))
+        .unwrap()
+        .with_snapshots(vec![6])
+        .map(|r| r.unwrap().value.seq)
+        .collect();
+    assert_eq!(retained, vec![8, 5]);
+
+    let at_4 = sst.get_at(&"A".to_string(), 4).unwrap().unwrap();
+    assert_eq!(at_4.value.unwrap().as_str(), "v3");
+    assert!(sst.get_at(&"A".to_string(), 2).unwrap().is_none());
+}
+
+#[test]
+fn compaction_collects_tombstones() {
+    let tmp_dir = TempDir::new().unwrap();
+
+    let path1 = tmp_dir.path().join("L0-1.sst");
+    let mem1 = MemTable::new();
+    mem1.put(Arc::new("A".to_string()), Arc::new("val".to_string()));
+    mem1.put(Arc::new("B".to_string()), Arc::new("val".to_string()));
+    let sst1 = SSTable::write_from_memtable(&path1, &mem1, SSTableId(1), None).unwrap();
+
+    let path2 = tmp_dir.path().join("L0-2.sst");
+    let mem2 = MemTable::new();
+    mem2.delete(Arc::new("A".to_string()));
+    let sst2 = SSTable::write_from_memtable(&path2, &mem2, SSTableId(2), None).unwrap();
+    let inputs = [sst1, sst2];
+
+    // "A" is covered by a deeper table, so its tombstone must survive.
+    let kept = Compactor::compact_to_level(
+        &inputs,
+        &tmp_dir.path().join("L1-3.sst"),
+        SSTableId(3),
+        1,
+        &[],
+        Some(vec![("A".to_string(), "A".to_string())]),
+        None,
+    )
+    .unwrap();
+    let kept_sst = &kept.sstables[0];
+    assert_eq!(kept_sst.len(), 2);
+    assert!(
+        kept_sst
+            .get(&"A".to_string())
+            .unwrap()
+            .unwrap()
+            .is_tombstone
+    );
+    assert_eq!(kept.stats.dropped_versions, 1);
+    assert_eq!(kept.stats.dropped_tombstones, 0);
+
+    // Bottom-most output: the tombstone and the value under it both go.
+    let collected = Compactor::compact_to_level(
+        &inputs,
+        &tmp_dir.path().join("L1-4.sst"),
+        SSTableId(4),
+        1,
+        &[],
+        Some(Vec::new()),
+        None,
+    )
+    .unwrap();
+    let collected_sst = &collected.sstables[0];
+    assert_eq!(collected_sst.len(), 1);
+    assert!(collected_sst.get(&"A".to_string()).unwrap().is_none());
+    assert_eq!(collected.stats.input_entries, 3);
+    assert_eq!(collected.stats.output_entries, 1);
+    assert_eq!(collected.stats.dropped_tombstones, 1);
+    assert!(collected.stats.reclaimed_bytes() > 0);
+}
+
+#[test]
+fn compaction_of_only_deletes_produces_no_table() {
+    let tmp_dir = TempDir::new().unwrap();
+
+    let path = tmp_dir.path().join("L0-1.sst");
+    let mem = MemTable::<String, String>::new();
+    mem.delete(Arc::new("A".to_string()));
+    mem.delete(Arc::new("B".to_string()));
+    let sst = SSTable::write_from_memtable(&path, &mem, SSTableId(1), None).unwrap();
+
+    let output_path = tmp_dir.path().join("L1-2.sst");
+    let output = Compactor::compact_to_level(
+        &[sst],
+        &output_path,
+        SSTableId(2),
+        1,
+        &[],
+        Some(Vec::new()),
+        None,
+    )
+    .unwrap();
+    assert!(output.sstables.is_empty());
+    assert_eq!(output.stats.dropped_tombstones, 2);
+    assert!(!output_path.exists());
+}
+
+#[test]
+fn leveled_compaction_splits_non_overlapping_outputs() {
+    let tmp_dir = TempDir::new().unwrap();
+
+    let mut inputs = Vec::new();
+    for t in 0..3u64 {
+        let mem = MemTable::new();
+        for i in 0..200 {
+            mem.put(
+                Arc::new(format!("key-{:04}", i * 3 + t)),
+                Arc::new("x".repeat(32)),
+            );
+        }
+        let path = tmp_dir.path().join(format!("L0-{}.sst", t));
+        inputs.push(SSTable::write_from_memtable(&path, &mem, SSTableId(t), None).unwrap());
+    }
+
+    let ids = IdAllocator::new(SSTableId(10));
+    let output = Compactor::compact_leveled(
+        &inputs,
+        tmp_dir.path(),
+        &ids,
+        1,
+        4096,
+        &TableOptions::default(),
+        &[],
+        None,
+        None,
+    )
+    .unwrap();
+
+    assert!(output.sstables.len() > 1);
+    assert_eq!(ids.peek(), SSTableId(10 + output.sstables.len() as u64));
+    assert_eq!(output.stats.output_entries, 600);
+    for pair in output.sstables.windows(2) {
+        assert!(pair[0].max_key() < pair[1].min_key());
+    }
+    for sst in &output.sstables {
+        let name = sst.path().file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("L1-"));
+    }
+}
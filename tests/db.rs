@@ -1,4 +1,8 @@
-use gpdb::DB;
+use gpdb::{
+    BlockCache, Compression, DB, DBOptions, Error, FifoCompaction, FilterPolicy, ReadOptions,
+    SizeTieredCompaction, WriteOptions, WriteStallOptions,
+};
+use std::sync::Arc;
 use tempfile::TempDir;
 
 #[test]
@@ -132,6 +136,39 @@ fn db_manifest_complex_reconciliation() {
     );
 }
 
+#[test]
+fn db_manifest_rolls_over() {
+    let tmp_dir = TempDir::new().unwrap();
+    let path = tmp_dir.path();
+    let manifests = || {
+        std::fs::read_dir(path)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.starts_with("MANIFEST"))
+            .collect::<Vec<_>>()
+    };
+
+    {
+        let options = DBOptions::new()
+            .max_memtable_size(50)
+            .max_manifest_file_size(512);
+        let db: DB<String, String> = DB::open_with_options(path, options).unwrap();
+        for i in 0..100 {
+            db.put(format!("key-{:03}", i), "val".to_string()).unwrap();
+        }
+        db.close().unwrap();
+    }
+
+    // Opening starts MANIFEST-000001; every rollover since replaced the previous file.
+    let current = std::fs::read_to_string(path.join("CURRENT")).unwrap();
+    assert_eq!(manifests(), vec![current.trim_end().to_string()]);
+    assert!(current.trim_end() > "MANIFEST-000002");
+
+    let db: DB<String, String> = DB::open(path, 1024).unwrap();
+    assert_eq!(db.iter().unwrap().count(), 100);
+    assert_eq!(manifests().len(), 1);
+}
+
 #[test]
 fn db_level_n_compaction() {
     let tmp_dir = TempDir::new().unwrap();
@@ -146,3 +183,675 @@ fn db_level_n_compaction() {
     let val = db.get(&"key-0".to_string()).unwrap().unwrap();
     assert_eq!(val.as_str(), "val");
 }
+
+#[test]
+fn db_range_scans() {
+    let tmp_dir = TempDir::new().unwrap();
+    let db: DB<String, String> = DB::open(tmp_dir.path(), 50).unwrap();
+
+    for i in 0..10 {
+        db.put(format!("key-{}", i), format!("v{}", i)).unwrap();
+    }
+    db.put("key-3".to_string(), "v3-new".to_string()).unwrap();
+    db.delete("key-5".to_string()).unwrap();
+    db.put("other".to_string(), "x".to_string()).unwrap();
+
+    let all: Vec<_> = db.iter().unwrap().map(|r| r.unwrap()).collect();
+    let keys: Vec<&str> = all.iter().map(|(k, _)| k.as_str()).collect();
+    assert_eq!(
+        keys,
+        vec![
+            "key-0", "key-1", "key-2", "key-3", "key-4", "key-6", "key-7", "key-8", "key-9",
+            "other"
+        ]
+    );
+    assert_eq!(all[3].1.as_str(), "v3-new");
+
+    let ranged: Vec<String> = db
+        .range("key-2".to_string().."key-7".to_string())
+        .unwrap()
+        .map(|r| r.unwrap().0.as_ref().clone())
+        .collect();
+    assert_eq!(ranged, vec!["key-2", "key-3", "key-4", "key-6"]);
+
+    let inclusive = db
+        .range("key-8".to_string()..="key-9".to_string())
+        .unwrap()
+        .count();
+    assert_eq!(inclusive, 2);
+
+    let prefixed = db.prefix_iter(&"key-".to_string()).unwrap().count();
+    assert_eq!(prefixed, 9);
+    assert_eq!(db.prefix_iter(&"missing".to_string()).unwrap().count(), 0);
+}
+
+#[test]
+fn db_snapshot_isolation() {
+    let tmp_dir = TempDir::new().unwrap();
+    let db: DB<String, String> = DB::open(tmp_dir.path(), 50).unwrap();
+
+    db.put("a".to_string(), "a1".to_string()).unwrap();
+    db.put("b".to_string(), "b1".to_string()).unwrap();
+    let snap = db.snapshot();
+
+    db.put("a".to_string(), "a2".to_string()).unwrap();
+    db.delete("b".to_string()).unwrap();
+    db.put("c".to_string(), "c1".to_string()).unwrap();
+
+    // Push enough writes through to force flushes and compactions.
+    for i in 0..40 {
+        db.put(format!("filler-{}", i), "x".to_string()).unwrap();
+        db.put("a".to_string(), format!("a-{}", i)).unwrap();
+    }
+
+    assert_eq!(snap.get(&"a".to_string()).unwrap().unwrap().as_str(), "a1");
+    assert_eq!(snap.get(&"b".to_string()).unwrap().unwrap().as_str(), "b1");
+    assert!(snap.get(&"c".to_string()).unwrap().is_none());
+
+    let seen: Vec<(String, String)> = snap
+        .iter()
+        .unwrap()
+        .map(|r| {
+            let (k, v) = r.unwrap();
+            (k.as_ref().clone(), v.as_ref().clone())
+        })
+        .collect();
+    assert_eq!(
+        seen,
+        vec![
+            ("a".to_string(), "a1".to_string()),
+            ("b".to_string(), "b1".to_string())
+        ]
+    );
+
+    assert_eq!(db.get(&"a".to_string()).unwrap().unwrap().as_str(), "a-39");
+    assert!(db.get(&"b".to_string()).unwrap().is_none());
+    assert!(snap.seq() < db.snapshot().seq());
+}
+
+#[test]
+fn db_compaction_reclaims_deleted_keys() {
+    let tmp_dir = TempDir::new().unwrap();
+    let db: DB<String, String> = DB::open(tmp_dir.path(), 50).unwrap();
+
+    for i in 0..20 {
+        db.put(format!("key-{:02}", i), "val".to_string()).unwrap();
+    }
+    for i in 0..20 {
+        db.delete(format!("key-{:02}", i)).unwrap();
+    }
+    for _ in 0..200 {
+        if db.compaction_stats().dropped_tombstones > 0 {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        db.put("tick".to_string(), "x".to_string()).unwrap();
+    }
+
+    let stats = db.compaction_stats();
+    assert!(stats.dropped_tombstones > 0);
+    assert!(stats.dropped_versions > 0);
+    assert_eq!(db.prefix_iter(&"key-".to_string()).unwrap().count(), 0);
+}
+
+#[test]
+fn db_reads_across_levels() {
+    let tmp_dir = TempDir::new().unwrap();
+    let db: DB<String, String> = DB::open(tmp_dir.path(), 200).unwrap();
+
+    // Interleave the key space so every flush overlaps what is already on disk.
+    for round in 0..5 {
+        for i in (round..300).step_by(5) {
+            db.put(format!("key-{:03}", i), format!("v{}", i)).unwrap();
+        }
+    }
+    assert!(db.total_sst_count() > 1);
+
+    for i in 0..300 {
+        let value = db.get(&format!("key-{:03}", i)).unwrap().unwrap();
+        assert_eq!(value.as_str(), format!("v{}", i));
+    }
+    assert!(db.get(&"key-300".to_string()).unwrap().is_none());
+
+    let ranged: Vec<String> = db
+        .range("key-100".to_string().."key-110".to_string())
+        .unwrap()
+        .map(|r| r.unwrap().0.as_ref().clone())
+        .collect();
+    assert_eq!(ranged.len(), 10);
+    assert_eq!(ranged[0], "key-100");
+    assert_eq!(db.iter().unwrap().count(), 300);
+}
+
+#[test]
+fn db_size_tiered_compaction() {
+    let tmp_dir = TempDir::new().unwrap();
+    let options = DBOptions::new()
+        .max_memtable_size(50)
+        .compaction_strategy(SizeTieredCompaction::default());
+    let db: DB<String, String> = DB::open_with_options(tmp_dir.path(), options).unwrap();
+
+    for round in 0..4 {
+        for i in 0..10 {
+            db.put(format!("key-{}", i), format!("v{}", round)).unwrap();
+        }
+    }
+    // The first runs merged may hold no two versions of one key.
+    for _ in 0..200 {
+        if db.compaction_stats().dropped_versions > 0 {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        db.put("tick".to_string(), "x".to_string()).unwrap();
+    }
+
+    assert!(db.compaction_stats().dropped_versions > 0);
+    for i in 0..10 {
+        assert_eq!(
+            db.get(&format!("key-{}", i)).unwrap().unwrap().as_str(),
+            "v3"
+        );
+    }
+}
+
+#[test]
+fn db_fifo_compaction_drops_oldest_tables() {
+    let tmp_dir = TempDir::new().unwrap();
+    let options = DBOptions::new()
+        .max_memtable_size(50)
+        .compaction_strategy(FifoCompaction {
+            max_table_files_size: 0,
+            ttl: None,
+        });
+    let db: DB<String, String> = DB::open_with_options(tmp_dir.path(), options).unwrap();
+
+    for i in 0..20 {
+        db.put(format!("key-{:02}", i), "val".to_string()).unwrap();
+    }
+    // Waits out the background flushes, and the drops that follow them.
+    db.flush(true).unwrap();
+
+    assert_eq!(db.total_sst_count(), 0);
+    assert!(db.get(&"key-00".to_string()).unwrap().is_none());
+    assert!(db.compaction_stats().input_bytes > 0);
+    assert_eq!(db.compaction_stats().output_bytes, 0);
+}
+
+#[test]
+fn db_compression_can_change_between_opens() {
+    let tmp_dir = TempDir::new().unwrap();
+    let path = tmp_dir.path();
+
+    {
+        let options = DBOptions::new()
+            .max_memtable_size(512)
+            .compression_per_level(vec![Compression::Lz4, Compression::zstd()])
+            .zstd_dictionary_size(1024);
+        let db: DB<String, String> = DB::open_with_options(path, options).unwrap();
+        for i in 0..200 {
+            db.put(format!("key-{:03}", i), format!(r#"{{"n":{}}}"#, i))
+                .unwrap();
+        }
+    }
+
+    let db: DB<String, String> = DB::open_with_options(
+        path,
+        DBOptions::new()
+            .max_memtable_size(512)
+            .compression(Compression::Snappy),
+    )
+    .unwrap();
+    for i in (0..200).step_by(7) {
+        let value = db.get(&format!("key-{:03}", i)).unwrap().unwrap();
+        assert_eq!(value.as_str(), format!(r#"{{"n":{}}}"#, i));
+    }
+    assert_eq!(db.iter().unwrap().count(), 200);
+
+    let invalid = DBOptions::new().compression(Compression::Zstd { level: 99 });
+    assert!(matches!(
+        DB::<String, String>::open_with_options(path, invalid),
+        Err(Error::InvalidData(_))
+    ));
+}
+
+#[test]
+fn db_options_are_persisted_and_checked() {
+    let tmp_dir = TempDir::new().unwrap();
+    let path = tmp_dir.path();
+
+    {
+        let options = DBOptions::new()
+            .max_memtable_size(64)
+            .block_size(256)
+            .restart_interval(4)
+            .filter_policy(FilterPolicy::Xor16)
+            .block_cache_size(1024 * 1024);
+        let db: DB<String, String> = DB::open_with_options(path, options).unwrap();
+        for i in 0..50 {
+            db.put(format!("key-{:02}", i), "val".to_string()).unwrap();
+        }
+    }
+
+    // Table layout may change between opens.
+    let db: DB<String, String> =
+        DB::open_with_options(path, DBOptions::new().block_size(8192)).unwrap();
+    assert_eq!(
+        db.get(&"key-07".to_string()).unwrap().unwrap().as_str(),
+        "val"
+    );
+    drop(db);
+
+    // A different compaction strategy must be asked for explicitly.
+    let fifo = || FifoCompaction {
+        max_table_files_size: u64::MAX,
+        ttl: None,
+    };
+    let err =
+        DB::<String, String>::open_with_options(path, DBOptions::new().compaction_strategy(fifo()))
+            .unwrap_err();
+    assert!(matches!(err, Error::InvalidData(_)));
+
+    let db: DB<String, String> = DB::open_with_options(
+        path,
+        DBOptions::new()
+            .compaction_strategy(fifo())
+            .allow_strategy_change(true),
+    )
+    .unwrap();
+    assert_eq!(
+        db.get(&"key-49".to_string()).unwrap().unwrap().as_str(),
+        "val"
+    );
+
+    assert!(DB::<String, String>::open_with_options(path, DBOptions::new().block_size(0)).is_err());
+}
+
+#[test]
+fn db_failed_open_keeps_the_stored_options() {
+    let tmp_dir = TempDir::new().unwrap();
+    let path = tmp_dir.path();
+    DB::<String, String>::open(path, 1024)
+        .unwrap()
+        .close()
+        .unwrap();
+
+    // A WAL that cannot be read fails the open after the options were checked.
+    std::fs::create_dir(path.join("000009.wal")).unwrap();
+    let fifo = FifoCompaction {
+        max_table_files_size: u64::MAX,
+        ttl: None,
+    };
+    let options = DBOptions::new()
+        .compaction_strategy(fifo)
+        .allow_strategy_change(true);
+    assert!(DB::<String, String>::open_with_options(path, options).is_err());
+
+    std::fs::remove_dir(path.join("000009.wal")).unwrap();
+    DB::<String, String>::open(path, 1024)
+        .unwrap()
+        .close()
+        .unwrap();
+}
+
+#[test]
+fn db_close_drains_compactions() {
+    let tmp_dir = TempDir::new().unwrap();
+    let path = tmp_dir.path();
+
+    let db: DB<String, String> = DB::open(path, 50).unwrap();
+    for i in 0..100 {
+        db.put(format!("key-{:03}", i), "val".to_string()).unwrap();
+    }
+    let other = db.clone();
+    db.close().unwrap();
+
+    assert!(matches!(
+        other.put("late".to_string(), "x".to_string()),
+        Err(Error::Closed)
+    ));
+    drop(other);
+
+    // Every table on disk is referenced by the manifest: nothing was left half-done.
+    let on_disk = std::fs::read_dir(path)
+        .unwrap()
+        .filter(|e| {
+            e.as_ref()
+                .unwrap()
+                .path()
+                .extension()
+                .is_some_and(|x| x == "sst")
+        })
+        .count();
+    let db: DB<String, String> = DB::open(path, 50).unwrap();
+    assert_eq!(db.total_sst_count(), on_disk);
+    assert_eq!(db.iter().unwrap().count(), 100);
+}
+
+#[test]
+fn db_installs_compactions_without_writes() {
+    let tmp_dir = TempDir::new().unwrap();
+    let db: DB<String, String> = DB::open(tmp_dir.path(), 50).unwrap();
+
+    let mut i = 0;
+    while db.total_sst_count() < 4 {
+        db.put(format!("key-{:03}", i), "val".to_string()).unwrap();
+        db.flush(true).unwrap();
+        i += 1;
+    }
+
+    // No further writes: the compaction must still be installed on its own.
+    for _ in 0..200 {
+        if db.compaction_stats().input_entries > 0 && db.compaction_backlog() == 0 {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    assert!(db.compaction_stats().input_entries > 0);
+    assert_eq!(db.compaction_backlog(), 0);
+    assert_eq!(db.total_sst_count(), 1);
+    assert_eq!(db.iter().unwrap().count(), i);
+}
+
+#[test]
+fn db_cache_stats_track_lookups() {
+    let tmp_dir = TempDir::new().unwrap();
+    let db: DB<String, String> = DB::open(tmp_dir.path(), 1024).unwrap();
+    for i in 0..200 {
+        db.put(format!("key{:03}", i), format!("value{}", i))
+            .unwrap();
+    }
+    assert!(db.total_sst_count() > 0);
+
+    let before = db.cache_stats();
+    assert_eq!(before.entries, 0);
+    let scanned = db
+        .range_opt(.., &ReadOptions { fill_cache: false })
+        .unwrap();
+    assert_eq!(scanned.count(), 200);
+    let snapshot = db.snapshot();
+    let no_fill = ReadOptions { fill_cache: false };
+    assert_eq!(snapshot.range_opt(.., &no_fill).unwrap().count(), 200);
+    let prefixed = db.prefix_iter_opt(&"key1".to_string(), &no_fill).unwrap();
+    assert_eq!(prefixed.count(), 100);
+    assert!(
+        snapshot
+            .get_opt(&"key000".to_string(), &no_fill)
+            .unwrap()
+            .is_some()
+    );
+    assert_eq!(db.cache_stats().entries, 0);
+
+    for _ in 0..2 {
+        for i in 0..50 {
+            assert!(db.get(&format!("key{:03}", i)).unwrap().is_some());
+        }
+    }
+    let stats = db.cache_stats();
+    assert!(stats.hits > 0, "{:?}", stats);
+    assert!(stats.misses > before.misses, "{:?}", stats);
+    assert!(stats.entries > 0 && stats.used_bytes > 0, "{:?}", stats);
+}
+
+#[test]
+fn db_instances_share_one_block_cache() {
+    let tmp_dir = TempDir::new().unwrap();
+    let cache = Arc::new(BlockCache::new(1024 * 1024));
+    let open = |name: &str| -> DB<String, String> {
+        let options = DBOptions::new()
+            .max_memtable_size(1024)
+            .block_cache(Arc::clone(&cache));
+        DB::open_with_options(&tmp_dir.path().join(name), options).unwrap()
+    };
+    let tenant_a = open("a");
+    let tenant_b = open("b");
+
+    // Same keys and the same table ids in both databases, different values.
+    for i in 0..100 {
+        tenant_a
+            .put(format!("key{:03}", i), format!("a{}", i))
+            .unwrap();
+        tenant_b
+            .put(format!("key{:03}", i), format!("b{}", i))
+            .unwrap();
+    }
+    for i in 0..100 {
+        let key = format!("key{:03}", i);
+        assert_eq!(*tenant_a.get(&key).unwrap().unwrap(), format!("a{}", i));
+        assert_eq!(*tenant_b.get(&key).unwrap().unwrap(), format!("b{}", i));
+    }
+
+    let stats = tenant_a.cache_stats();
+    assert_eq!(stats.capacity_bytes, 1024 * 1024);
+    assert_eq!(stats.entries, tenant_b.cache_stats().entries);
+    assert!(stats.misses > 0);
+    assert_eq!(cache.stats().misses, 0);
+    let total = cache.total_stats();
+    assert_eq!(total.misses, stats.misses + tenant_b.cache_stats().misses);
+    assert_eq!(total.hits, stats.hits + tenant_b.cache_stats().hits);
+
+    // Closing one database frees its share of the budget.
+    let shared_entries = stats.entries;
+    tenant_a.close().unwrap();
+    let remaining = tenant_b.cache_stats().entries;
+    assert!(
+        remaining > 0 && remaining < shared_entries,
+        "{} of {}",
+        remaining,
+        shared_entries
+    );
+}
+
+#[test]
+fn db_flushes_by_payload_size() {
+    let tmp_dir = TempDir::new().unwrap();
+    let db: DB<String, String> = DB::open(tmp_dir.path(), 64 * 1024).unwrap();
+
+    // 200 values of 1 KiB are three times the MemTable limit.
+    for i in 0..200 {
+        db.put(format!("key{:03}", i), "x".repeat(1024)).unwrap();
+    }
+    assert!(db.total_sst_count() >= 2, "{}", db.total_sst_count());
+    for i in 0..200 {
+        assert!(db.get(&format!("key{:03}", i)).unwrap().is_some());
+    }
+}
+
+#[test]
+fn db_slows_writes_when_l0_backs_up() {
+    let tmp_dir = TempDir::new().unwrap();
+    let options = DBOptions::new()
+        .max_memtable_size(50)
+        .write_stall(WriteStallOptions {
+            l0_slowdown_trigger: 1,
+            ..WriteStallOptions::default()
+        });
+    let db: DB<String, String> = DB::open_with_options(tmp_dir.path(), options).unwrap();
+    assert_eq!(db.stall_stats(), Default::default());
+
+    for i in 0..20 {
+        db.put(format!("key{:02}", i), "value".to_string()).unwrap();
+    }
+
+    // Every write after the first flush finds a table in L0.
+    let stats = db.stall_stats();
+    assert!(stats.slowed_writes > 0, "{:?}", stats);
+    assert_eq!(stats.stopped_writes, 0);
+    assert!(stats.total_delay() >= stats.slowdown_time);
+    for i in 0..20 {
+        assert!(db.get(&format!("key{:02}", i)).unwrap().is_some());
+    }
+}
+
+#[test]
+fn write_stall_stop_triggers_must_be_positive() {
+    let tmp_dir = TempDir::new().unwrap();
+    let options = DBOptions::new().write_stall(WriteStallOptions {
+        l0_stop_trigger: 0,
+        ..WriteStallOptions::default()
+    });
+    let result = DB::<String, String>::open_with_options(tmp_dir.path(), options);
+    assert!(matches!(result, Err(Error::InvalidData(_))));
+}
+
+#[test]
+fn write_stall_slowdown_triggers_must_be_positive_and_below_stop() {
+    let tmp_dir = TempDir::new().unwrap();
+    for stall in [
+        WriteStallOptions {
+            immutables_slowdown_trigger: 0,
+            ..WriteStallOptions::default()
+        },
+        WriteStallOptions {
+            l0_slowdown_trigger: 40,
+            l0_stop_trigger: 36,
+            ..WriteStallOptions::default()
+        },
+        WriteStallOptions {
+            pending_compaction_bytes_slowdown: 2,
+            pending_compaction_bytes_stop: 1,
+            ..WriteStallOptions::default()
+        },
+    ] {
+        let options = DBOptions::new().write_stall(stall.clone());
+        let result = DB::<String, String>::open_with_options(tmp_dir.path(), options);
+        assert!(matches!(result, Err(Error::InvalidData(_))), "{:?}", stall);
+    }
+
+    // A slowdown trigger may equal its stop trigger.
+    let options = DBOptions::new().write_stall(WriteStallOptions {
+        l0_slowdown_trigger: 36,
+        ..WriteStallOptions::default()
+    });
+    assert!(DB::<String, String>::open_with_options(tmp_dir.path(), options).is_ok());
+}
+
+#[test]
+fn db_flush_on_request() {
+    let tmp_dir = TempDir::new().unwrap();
+    let db: DB<String, String> = DB::open(tmp_dir.path(), 1024 * 1024).unwrap();
+
+    // Nothing to flush yet.
+    db.flush(true).unwrap();
+    assert_eq!(db.total_sst_count(), 0);
+
+    db.put("k1".to_string(), "v1".to_string()).unwrap();
+    db.flush(true).unwrap();
+    assert_eq!(db.total_sst_count(), 1);
+
+    // Without waiting, the background flush thread writes the table.
+    db.put("k2".to_string(), "v2".to_string()).unwrap();
+    db.flush(false).unwrap();
+    for _ in 0..200 {
+        if db.total_sst_count() == 2 {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    assert_eq!(db.total_sst_count(), 2);
+    assert_eq!(db.get(&"k1".to_string()).unwrap().unwrap().as_str(), "v1");
+    assert_eq!(db.get(&"k2".to_string()).unwrap().unwrap().as_str(), "v2");
+
+    db.close().unwrap();
+    let db: DB<String, String> = DB::open(tmp_dir.path(), 1024 * 1024).unwrap();
+    assert_eq!(db.total_sst_count(), 2);
+    assert!(matches!(db.clone().close(), Ok(())));
+    assert!(matches!(db.flush(true), Err(Error::Closed)));
+}
+
+#[test]
+fn db_reads_see_every_key_while_memtables_switch() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let tmp_dir = TempDir::new().unwrap();
+    let db: DB<String, String> = DB::open(tmp_dir.path(), 1024 * 1024).unwrap();
+    let key = |round: usize| format!("key-{:04}", round);
+    // Rounds whose key has been written, plus one once writing is over.
+    let written = Arc::new(AtomicUsize::new(0));
+    const ROUNDS: usize = 100;
+
+    let readers: Vec<_> = (0..2)
+        .map(|_| {
+            let db = db.clone();
+            let written = Arc::clone(&written);
+            std::thread::spawn(move || {
+                loop {
+                    let rounds = written.load(Ordering::Acquire);
+                    if rounds > ROUNDS {
+                        break;
+                    }
+                    if rounds > 0 {
+                        assert!(db.get(&key(rounds - 1)).unwrap().is_some());
+                        assert!(db.iter().unwrap().count() >= rounds);
+                    }
+                }
+            })
+        })
+        .collect();
+
+    // Every write goes to a fresh MemTable, which is switched out right after.
+    for round in 0..ROUNDS {
+        db.put(key(round), "val".to_string()).unwrap();
+        written.store(round + 1, Ordering::Release);
+        db.flush(false).unwrap();
+    }
+    written.store(ROUNDS + 1, Ordering::Release);
+    for reader in readers {
+        reader.join().unwrap();
+    }
+}
+
+#[test]
+fn db_write_options_trade_durability() {
+    let tmp_dir = TempDir::new().unwrap();
+    let path = tmp_dir.path();
+    let no_sync = WriteOptions {
+        sync: false,
+        ..WriteOptions::default()
+    };
+    let no_wal = WriteOptions {
+        disable_wal: true,
+        ..WriteOptions::default()
+    };
+
+    {
+        let options = DBOptions::new().wal_sync_interval(std::time::Duration::from_millis(10));
+        let db: DB<String, String> = DB::open_with_options(path, options).unwrap();
+        db.put_opt("unsynced".to_string(), "v".to_string(), &no_sync)
+            .unwrap();
+        db.delete_opt("unsynced".to_string(), &no_sync).unwrap();
+        db.put_opt("unsynced".to_string(), "v2".to_string(), &no_sync)
+            .unwrap();
+        db.sync_wal().unwrap();
+        db.put_opt("unlogged".to_string(), "v".to_string(), &no_wal)
+            .unwrap();
+        assert_eq!(
+            db.get(&"unlogged".to_string()).unwrap().unwrap().as_str(),
+            "v"
+        );
+    }
+
+    // Dropping the last handle flushes the writes that skipped the WAL.
+    {
+        let db: DB<String, String> = DB::open(path, 1024 * 1024).unwrap();
+        assert_eq!(
+            db.get(&"unsynced".to_string()).unwrap().unwrap().as_str(),
+            "v2"
+        );
+        assert_eq!(
+            db.get(&"unlogged".to_string()).unwrap().unwrap().as_str(),
+            "v"
+        );
+        assert_eq!(db.total_sst_count(), 1);
+
+        db.put_opt("closed".to_string(), "v".to_string(), &no_wal)
+            .unwrap();
+        db.close().unwrap();
+    }
+
+    let db: DB<String, String> = DB::open(path, 1024 * 1024).unwrap();
+    assert_eq!(
+        db.get(&"closed".to_string()).unwrap().unwrap().as_str(),
+        "v"
+    );
+    db.close().unwrap();
+}
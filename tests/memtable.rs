@@ -1,4 +1,4 @@
-use gpdb::MemTable;
+use gpdb::{MemTable, ValueEntry};
 use std::sync::Arc;
 
 #[test]
@@ -135,3 +135,54 @@ fn iter_tombstone() {
 
     assert!(iter.next().is_none());
 }
+
+#[test]
+fn versioned_reads() {
+    let memtable: MemTable<String, String> = MemTable::new();
+    let key = Arc::new("key".to_string());
+
+    for (seq, val) in [(1, "v1"), (4, "v4")] {
+        memtable.insert(
+            Arc::clone(&key),
+            ValueEntry {
+                value: Some(Arc::new(val.to_string())),
+                is_tombstone: false,
+                seq,
+            },
+        );
+    }
+    memtable.insert(
+        Arc::clone(&key),
+        ValueEntry {
+            value: None,
+            is_tombstone: true,
+            seq: 6,
+        },
+    );
+
+    assert!(memtable.get(&key).is_none());
+    assert_eq!(memtable.len(), 0);
+    assert!(memtable.get_entry_at(&key, 0).is_none());
+    let at_5 = memtable.get_entry_at(&key, 5).unwrap();
+    assert_eq!(at_5.value.unwrap().as_str(), "v4");
+    assert_eq!(memtable.iter().count(), 3);
+}
+
+#[test]
+fn size_counts_heap_payloads() {
+    let memtable: MemTable<String, String> = MemTable::new();
+    assert_eq!(memtable.size_bytes(), 0);
+
+    for i in 0..10 {
+        memtable.put(Arc::new(format!("key{}", i)), Arc::new("x".repeat(1024)));
+    }
+    let size = memtable.size_bytes();
+    assert!(size >= 10 * 1024, "{}", size);
+    assert!(size < 20 * 1024, "{}", size);
+
+    memtable.delete(Arc::new("key0".to_string()));
+    assert!(memtable.size_bytes() > size);
+
+    memtable.clear();
+    assert_eq!(memtable.size_bytes(), 0);
+}
@@ -20,6 +20,7 @@ proptest! {
             let entry = ValueEntry {
                 value: Some(Arc::new(val)),
                 is_tombstone: false,
+                seq: 0,
             };
             builder.add(key, &entry);
             entries.push(Entry {
@@ -54,6 +55,7 @@ proptest! {
             let entry = ValueEntry {
                 value: Some(Arc::new(val)),
                 is_tombstone: false,
+                seq: 0,
             };
             builder.add(key, &entry);
             entries_written += 1;
@@ -67,4 +69,33 @@ proptest! {
             }
         }
     }
+
+    #[test]
+    fn seek_and_reverse(keys in prop::collection::vec("[a-d]{0,6}", 1..100), target in "[a-d]{0,6}") {
+        let mut sorted_keys = keys.clone();
+        sorted_keys.sort();
+        sorted_keys.dedup();
+
+        let mut builder = DeltaBlockBuilder::with_restart_interval(1024 * 1024, 4);
+        for key in &sorted_keys {
+            let entry = ValueEntry {
+                value: Some(Arc::new(key.clone())),
+                is_tombstone: false,
+                seq: 0,
+            };
+            builder.add(key, &entry);
+        }
+        let block = builder.finish();
+
+        let expected: Vec<String> = sorted_keys.iter().filter(|k| **k >= target).cloned().collect();
+        let mut iter = block.iter();
+        iter.seek(&target);
+        let sought: Vec<String> = iter.map(|e| e.key.as_ref().clone()).collect();
+        prop_assert_eq!(&sought, &expected);
+
+        let mut reversed: Vec<String> = sorted_keys.clone();
+        reversed.reverse();
+        let backwards: Vec<String> = block.iter().rev().map(|e| e.key.as_ref().clone()).collect();
+        prop_assert_eq!(backwards, reversed);
+    }
 }
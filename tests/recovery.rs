@@ -1,6 +1,11 @@
-use gpdb::DB;
+use gpdb::{
+    DB, DBOptions, Entry, Manifest, ManifestEntry, SSTable, SSTableId, ValueEntry, WalRecoveryMode,
+    WriteBatch,
+};
 use std::fs::OpenOptions;
 use std::io::{Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
 use tempfile::TempDir;
 
 #[test]
@@ -64,8 +69,441 @@ fn recovery_with_orphan_sstables() {
     let orphan_path = path.join("L0-99999.sst");
     std::fs::File::create(&orphan_path).unwrap();
 
-    // Reopening should succeed and ignore the orphan file
+    // Reopening should succeed and sweep the orphan file
     let db: DB<String, String> = DB::open(path, 1024).unwrap();
     assert_eq!(db.get(&"k1".to_string()).unwrap().unwrap().as_str(), "v1");
     assert!(db.total_sst_count() == 1);
+    assert!(!orphan_path.exists());
+}
+
+#[test]
+fn recovery_quarantines_orphans() {
+    let tmp_dir = TempDir::new().unwrap();
+    let path = tmp_dir.path();
+
+    {
+        let db: DB<String, String> = DB::open(path, 10).unwrap();
+        db.put("k1".to_string(), "v1".to_string()).unwrap(); // Flushes WAL 0
+    }
+
+    // A WAL the manifest counts as flushed must not be replayed, even if it is garbage
+    std::fs::write(path.join("000000.wal"), b"not a wal").unwrap();
+    std::fs::write(path.join("L1-99999.sst"), b"half-written").unwrap();
+    std::fs::write(path.join("OPTIONS.tmp"), b"").unwrap();
+    std::fs::write(path.join("notes.tmp"), b"not ours").unwrap();
+
+    let options = DBOptions::new()
+        .max_memtable_size(1024)
+        .quarantine_orphans(true);
+    let db: DB<String, String> = DB::open_with_options(path, options).unwrap();
+    assert_eq!(db.get(&"k1".to_string()).unwrap().unwrap().as_str(), "v1");
+    assert_eq!(db.total_sst_count(), 1);
+
+    let quarantine = path.join("quarantine");
+    for name in ["000000.wal", "L1-99999.sst", "OPTIONS.tmp"] {
+        assert!(!path.join(name).exists(), "{} was not swept", name);
+        assert!(
+            quarantine.join(name).exists(),
+            "{} was not quarantined",
+            name
+        );
+    }
+    assert!(path.join("notes.tmp").exists());
+}
+
+#[test]
+fn recovery_from_legacy_manifest() {
+    let tmp_dir = copy_fixture("v1_db");
+    let path = tmp_dir.path();
+
+    // Older versions kept every change as its own record in a single MANIFEST
+    // file without CURRENT.
+    assert!(!path.join("CURRENT").exists());
+    let entries: Vec<ManifestEntry> = Manifest::read(&path.join("MANIFEST"))
+        .unwrap()
+        .map(|e| e.unwrap())
+        .collect();
+    assert!(
+        entries
+            .iter()
+            .any(|e| matches!(e, ManifestEntry::RemoveSSTable { .. }))
+    );
+    assert!(
+        entries
+            .iter()
+            .all(|e| !matches!(e, ManifestEntry::Snapshot { .. } | ManifestEntry::Edit(_)))
+    );
+
+    {
+        let db: DB<String, String> = DB::open(path, 1024 * 1024).unwrap();
+        assert_eq!(db.total_sst_count(), 2);
+        assert_v1_db_contents(&db);
+        db.close().unwrap();
+    }
+    assert!(!path.join("MANIFEST").exists());
+    assert!(path.join("CURRENT").exists());
+
+    let db: DB<String, String> = DB::open(path, 1024 * 1024).unwrap();
+    assert_eq!(db.total_sst_count(), 2);
+    assert_v1_db_contents(&db);
+}
+
+#[test]
+fn recovery_ignores_torn_manifest_edit() {
+    let tmp_dir = TempDir::new().unwrap();
+    let path = tmp_dir.path();
+
+    {
+        let db: DB<String, String> = DB::open(path, 10).unwrap();
+        db.put("k1".to_string(), "v1".to_string()).unwrap(); // Triggers flush
+    }
+
+    // Simulate a crash halfway through appending an edit frame
+    let manifest_path = Manifest::current(path).unwrap().unwrap();
+    let mut file = OpenOptions::new()
+        .append(true)
+        .open(&manifest_path)
+        .unwrap();
+    file.write_all(&0xABCDu32.to_le_bytes()).unwrap();
+    file.write_all(&100u64.to_le_bytes()).unwrap();
+    file.write_all(&[1, 2, 3]).unwrap();
+    drop(file);
+
+    let db: DB<String, String> = DB::open(path, 1024).unwrap();
+    assert_eq!(db.get(&"k1".to_string()).unwrap().unwrap().as_str(), "v1");
+    assert_eq!(db.total_sst_count(), 1);
+}
+
+#[test]
+fn recovery_compaction_is_a_single_edit() {
+    let tmp_dir = TempDir::new().unwrap();
+    let path = tmp_dir.path();
+
+    let db: DB<String, String> = DB::open(path, 50).unwrap();
+    for i in 0..20 {
+        db.put(format!("key-{}", i), "val".to_string()).unwrap();
+    }
+    // Compactions start after flushes, which close would otherwise leave to
+    // its final flush, too late to start any.
+    db.flush(true).unwrap();
+    db.close().unwrap();
+
+    let manifest_path = Manifest::current(path).unwrap().unwrap();
+    let entries: Vec<ManifestEntry> = Manifest::read(&manifest_path)
+        .unwrap()
+        .map(|e| e.unwrap())
+        .collect();
+    assert!(matches!(entries[0], ManifestEntry::Snapshot { .. }));
+    assert!(
+        entries[1..]
+            .iter()
+            .all(|e| matches!(e, ManifestEntry::Edit(_)))
+    );
+    assert!(entries.iter().any(|e| matches!(
+        e,
+        ManifestEntry::Edit(edit) if !edit.removed.is_empty() && !edit.added.is_empty()
+    )));
+}
+
+/// Writes `keys`, all of one length, as one WAL record each. Returns the WAL,
+/// the size of its header, and the size of one record.
+fn write_wal_records(path: &std::path::Path, keys: &[&str]) -> (std::path::PathBuf, u64, u64) {
+    let wal_path = path.join("000000.wal");
+    DB::<String, String>::open(path, 1024 * 1024)
+        .unwrap()
+        .close()
+        .unwrap();
+    let header = std::fs::metadata(&wal_path).unwrap().len();
+
+    let db: DB<String, String> = DB::open(path, 1024 * 1024).unwrap();
+    for key in keys {
+        db.put(key.to_string(), "value".to_string()).unwrap();
+    }
+    db.close().unwrap();
+    let len = std::fs::metadata(&wal_path).unwrap().len();
+    (wal_path, header, (len - header) / keys.len() as u64)
+}
+
+fn open_with_mode(
+    path: &std::path::Path,
+    mode: WalRecoveryMode,
+) -> gpdb::Result<DB<String, String>> {
+    DB::open_with_options(path, DBOptions::new().wal_recovery_mode(mode))
+}
+
+#[test]
+fn recovery_tolerates_a_torn_wal_tail() {
+    let tmp_dir = TempDir::new().unwrap();
+    let path = tmp_dir.path();
+    let (wal_path, header, record) = write_wal_records(path, &["k1", "k2"]);
+    let file = OpenOptions::new().write(true).open(&wal_path).unwrap();
+    file.set_len(header + 2 * record - 5).unwrap();
+    drop(file);
+
+    assert!(open_with_mode(path, WalRecoveryMode::AbsoluteConsistency).is_err());
+
+    let db = open_with_mode(path, WalRecoveryMode::TolerateCorruptedTailRecords).unwrap();
+    let report = db.wal_recovery_report().clone();
+    assert_eq!(report.wals_replayed, 1);
+    assert_eq!(report.records_replayed, 1);
+    assert_eq!(report.dropped.len(), 1);
+    assert_eq!(report.dropped[0].wal_id, 0);
+    assert_eq!(report.dropped[0].offset, header + record);
+    assert_eq!(report.dropped_bytes(), record - 5);
+    assert!(db.get(&"k1".to_string()).unwrap().is_some());
+    assert!(db.get(&"k2".to_string()).unwrap().is_none());
+
+    // The tail was cut off, so later writes land behind good records only.
+    db.put("k3".to_string(), "value".to_string()).unwrap();
+    db.close().unwrap();
+    let db: DB<String, String> = DB::open(path, 1024 * 1024).unwrap();
+    assert!(db.wal_recovery_report().is_clean());
+    assert!(db.get(&"k1".to_string()).unwrap().is_some());
+    assert!(db.get(&"k3".to_string()).unwrap().is_some());
+}
+
+#[test]
+fn recovery_leaves_wals_alone_when_the_open_fails() {
+    let tmp_dir = TempDir::new().unwrap();
+    let path = tmp_dir.path();
+    let (wal_path, header, record) = write_wal_records(path, &["k1", "k2"]);
+    let torn = header + 2 * record - 5;
+    let file = OpenOptions::new().write(true).open(&wal_path).unwrap();
+    file.set_len(torn).unwrap();
+    drop(file);
+
+    // A later WAL that cannot be read fails the open after the torn tail of
+    // the first one was already dropped.
+    std::fs::create_dir(path.join("000001.wal")).unwrap();
+    assert!(open_with_mode(path, WalRecoveryMode::TolerateCorruptedTailRecords).is_err());
+    assert_eq!(std::fs::metadata(&wal_path).unwrap().len(), torn);
+
+    std::fs::remove_dir(path.join("000001.wal")).unwrap();
+    let db = open_with_mode(path, WalRecoveryMode::TolerateCorruptedTailRecords).unwrap();
+    assert_eq!(db.wal_recovery_report().dropped_bytes(), record - 5);
+    assert_eq!(std::fs::metadata(&wal_path).unwrap().len(), header + record);
+    db.close().unwrap();
+}
+
+#[test]
+fn recovery_modes_for_a_damaged_wal_record() {
+    let tmp_dir = TempDir::new().unwrap();
+    let path = tmp_dir.path();
+    let (wal_path, header, record) = write_wal_records(path, &["k1", "k2", "k3"]);
+
+    // Damage the payload of the middle record; its length stays intact.
+    let mut file = OpenOptions::new().write(true).open(&wal_path).unwrap();
+    file.seek(SeekFrom::Start(header + record + 14)).unwrap();
+    file.write_all(&[0xFF]).unwrap();
+    drop(file);
+
+    // Damage short of the end is not a torn tail.
+    assert!(open_with_mode(path, WalRecoveryMode::TolerateCorruptedTailRecords).is_err());
+
+    {
+        let db = open_with_mode(path, WalRecoveryMode::SkipAnyCorruptedRecords).unwrap();
+        let report = db.wal_recovery_report();
+        assert_eq!(report.records_replayed, 2);
+        assert_eq!(report.dropped.len(), 1);
+        assert_eq!(
+            (report.dropped[0].offset, report.dropped[0].bytes),
+            (header + record, record)
+        );
+        assert!(db.get(&"k1".to_string()).unwrap().is_some());
+        assert!(db.get(&"k2".to_string()).unwrap().is_none());
+        assert!(db.get(&"k3".to_string()).unwrap().is_some());
+        db.close().unwrap();
+    }
+
+    let db = open_with_mode(path, WalRecoveryMode::PointInTime).unwrap();
+    let report = db.wal_recovery_report();
+    assert_eq!(report.records_replayed, 1);
+    assert_eq!(report.dropped_bytes(), 2 * record);
+    assert!(db.get(&"k1".to_string()).unwrap().is_some());
+    assert!(db.get(&"k3".to_string()).unwrap().is_none());
+    db.close().unwrap();
+    assert_eq!(std::fs::metadata(&wal_path).unwrap().len(), header + record);
+}
+
+/// Copies a database from `tests/fixtures` into a fresh directory.
+fn copy_fixture(name: &str) -> TempDir {
+    let tmp_dir = TempDir::new().unwrap();
+    let fixture = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name);
+    for entry in std::fs::read_dir(fixture).unwrap() {
+        let entry = entry.unwrap();
+        std::fs::copy(entry.path(), tmp_dir.path().join(entry.file_name())).unwrap();
+    }
+    tmp_dir
+}
+
+/// `v1_db` was written by the first release: format version 1 tables in L0
+/// and L1, a single-file manifest, and a headerless WAL of unflushed writes.
+fn assert_v1_db_contents(db: &DB<String, String>) {
+    let get = |key: &str| db.get(&key.to_string()).unwrap().map(|v| v.to_string());
+    assert_eq!(get("key000").as_deref(), Some("v2-0"));
+    assert_eq!(get("key005").as_deref(), Some("v2-5"));
+    assert_eq!(get("key010"), None);
+    assert_eq!(get("key014"), None);
+    assert_eq!(get("key015").as_deref(), Some("v1-15"));
+    assert_eq!(get("key039").as_deref(), Some("v1-39"));
+    assert_eq!(get("key100").as_deref(), Some("v3-100"));
+    assert_eq!(get("key102").as_deref(), Some("v3-102"));
+    assert_eq!(get("key200"), None);
+}
+
+#[test]
+fn recovery_opens_a_version_1_database() {
+    let tmp_dir = copy_fixture("v1_db");
+    let path = tmp_dir.path();
+
+    {
+        let db: DB<String, String> = DB::open(path, 1024 * 1024).unwrap();
+        assert_v1_db_contents(&db);
+        assert_eq!(db.wal_recovery_report().records_replayed, 8);
+        assert_eq!(db.iter().unwrap().count(), 38);
+
+        // New writes go to a new WAL; the old one is kept for replay.
+        db.put("key014".to_string(), "v4".to_string()).unwrap();
+        db.close().unwrap();
+    }
+    assert!(path.join("000005.wal").exists());
+
+    let db: DB<String, String> = DB::open(path, 1024 * 1024).unwrap();
+    assert_eq!(
+        db.get(&"key014".to_string()).unwrap().unwrap().as_str(),
+        "v4"
+    );
+    assert_eq!(
+        db.get(&"key000".to_string()).unwrap().unwrap().as_str(),
+        "v2-0"
+    );
+
+    // Flushing the replayed writes retires the old WAL.
+    db.flush(true).unwrap();
+    assert_eq!(db.get(&"key013".to_string()).unwrap(), None);
+    assert_eq!(
+        db.get(&"key014".to_string()).unwrap().unwrap().as_str(),
+        "v4"
+    );
+    assert_eq!(
+        db.get(&"key100".to_string()).unwrap().unwrap().as_str(),
+        "v3-100"
+    );
+    db.close().unwrap();
+
+    let db: DB<String, String> = DB::open(path, 1024 * 1024).unwrap();
+    assert!(!path.join("000005.wal").exists());
+    assert_eq!(
+        db.get(&"key100".to_string()).unwrap().unwrap().as_str(),
+        "v3-100"
+    );
+}
+
+#[test]
+fn recovery_keeps_or_drops_a_batch_whole() {
+    let tmp_dir = TempDir::new().unwrap();
+    let path = tmp_dir.path();
+    let (wal_path, header, record) = write_wal_records(path, &["k1"]);
+    {
+        let db: DB<String, String> = DB::open(path, 1024 * 1024).unwrap();
+        let mut batch = WriteBatch::new();
+        for key in ["b1", "b2", "b3"] {
+            batch.put(key.to_string(), "value".to_string());
+        }
+        db.write_batch(batch).unwrap();
+        db.close().unwrap();
+    }
+
+    // Damage the last entry of the batch; the entries before it go too.
+    let len = std::fs::metadata(&wal_path).unwrap().len();
+    let mut file = OpenOptions::new().write(true).open(&wal_path).unwrap();
+    file.seek(SeekFrom::Start(len - 2)).unwrap();
+    file.write_all(&[0xFF]).unwrap();
+    drop(file);
+
+    let db = open_with_mode(path, WalRecoveryMode::TolerateCorruptedTailRecords).unwrap();
+    let report = db.wal_recovery_report();
+    assert_eq!(report.records_replayed, 1);
+    assert_eq!(report.dropped[0].offset, header + record);
+    assert!(db.get(&"k1".to_string()).unwrap().is_some());
+    for key in ["b1", "b2", "b3"] {
+        assert!(db.get(&key.to_string()).unwrap().is_none());
+    }
+}
+
+#[test]
+fn recovery_refuses_overlapping_tables_below_l0() {
+    let tmp_dir = TempDir::new().unwrap();
+    let path = tmp_dir.path();
+
+    let mut tables = Vec::new();
+    for (id, keys) in [(1, ["a", "m"]), (2, ["k", "z"])] {
+        let name = format!("L1-{}.sst", id);
+        let entries = keys.map(|key| {
+            Ok(Entry {
+                key: Arc::new(key.to_string()),
+                value: ValueEntry {
+                    value: Some(Arc::new(format!("v{}", id))),
+                    is_tombstone: false,
+                    seq: id,
+                },
+            })
+        });
+        SSTable::<String, String>::write_from_iter(
+            &path.join(&name),
+            entries.into_iter(),
+            SSTableId(id),
+            1,
+            None,
+        )
+        .unwrap();
+        tables.push(PathBuf::from(name));
+    }
+    Manifest::create(
+        path,
+        1,
+        &ManifestEntry::Snapshot {
+            levels: vec![Vec::new(), tables],
+            next_id: SSTableId(3),
+            log_number: 0,
+        },
+    )
+    .unwrap();
+
+    let result = DB::<String, String>::open(path, 1024 * 1024);
+    assert!(
+        matches!(&result, Err(gpdb::Error::Corruption(msg)) if msg.contains("overlap")),
+        "{:?}",
+        result.map(|_| ())
+    );
+}
+
+#[test]
+fn recovery_refuses_tables_without_a_manifest() {
+    let tmp_dir = TempDir::new().unwrap();
+    let path = tmp_dir.path();
+
+    {
+        let db: DB<String, String> = DB::open(path, 10).unwrap();
+        db.put("k1".to_string(), "v1".to_string()).unwrap();
+        db.flush(true).unwrap();
+        db.close().unwrap();
+    }
+    std::fs::remove_file(path.join("CURRENT")).unwrap();
+
+    let result = DB::<String, String>::open(path, 1024);
+    assert!(matches!(result, Err(gpdb::Error::Corruption(_))));
+    let tables = std::fs::read_dir(path)
+        .unwrap()
+        .filter(|e| {
+            e.as_ref()
+                .unwrap()
+                .path()
+                .extension()
+                .is_some_and(|ext| ext == "sst")
+        })
+        .count();
+    assert_eq!(tables, 1);
 }
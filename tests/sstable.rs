@@ -1,4 +1,9 @@
-use gpdb::{Entry, FilterVariant, MemTable, SSTable, SSTableId, ValueEntry};
+use gpdb::{
+    BlockCache, COMPRESSION_LZ4, COMPRESSION_NONE, COMPRESSION_SNAPPY, COMPRESSION_ZSTD,
+    Compression, DeltaBlockBuilder, Entry, FILTER_TYPE_XOR8, FilterVariant, KEY_HASH_XXH3,
+    MAGIC_NUMBER, MemTable, SSTable, SSTableId, TableOptions, ValueEntry, key_hash,
+};
+use std::ops::{Bound, RangeBounds};
 use std::path::PathBuf;
 use std::sync::Arc;
 use tempfile::TempDir;
@@ -26,6 +31,7 @@ fn tiered_xor_filters() {
         value: ValueEntry {
             value: Some(Arc::new("v2".to_string())),
             is_tombstone: false,
+            seq: 0,
         },
     })];
     let sst_l1 =
@@ -91,3 +97,409 @@ fn test_delta_encoding_correctness() {
         ]
     );
 }
+
+fn json_entries(n: usize) -> Vec<gpdb::Result<Entry<String, String>>> {
+    (0..n)
+        .map(|i| {
+            Ok(Entry {
+                key: Arc::new(format!("user:{:05}", i)),
+                value: ValueEntry {
+                    value: Some(Arc::new(format!(
+                        r#"{{"id":{},"name":"user {}","email":"user{}@example.com","active":true}}"#,
+                        i, i, i
+                    ))),
+                    is_tombstone: false,
+                    seq: i as u64,
+                },
+            })
+        })
+        .collect()
+}
+
+#[test]
+fn compressed_blocks_round_trip() {
+    let (tmp_dir, _) = setup();
+    let plain_path = tmp_dir.path().join("plain.sst");
+    let plain = SSTable::write_from_iter(
+        &plain_path,
+        json_entries(2000).into_iter(),
+        SSTableId(1),
+        1,
+        None,
+    )
+    .unwrap();
+    assert_eq!(plain.compression_type(), COMPRESSION_NONE);
+
+    let codecs = [
+        (Compression::zstd(), COMPRESSION_ZSTD),
+        (Compression::Lz4, COMPRESSION_LZ4),
+        (Compression::Snappy, COMPRESSION_SNAPPY),
+    ];
+    for (i, (compression, compression_type)) in codecs.into_iter().enumerate() {
+        let path = tmp_dir.path().join(format!("{:?}.sst", compression_type));
+        let options = TableOptions {
+            compression_per_level: vec![Compression::None, compression],
+            ..TableOptions::default()
+        };
+        let id = SSTableId(i as u64 + 2);
+        let sst: SSTable<String, String> = SSTable::write_from_iter_with_options(
+            &path,
+            json_entries(2000).into_iter(),
+            id,
+            1,
+            None,
+            &options,
+        )
+        .unwrap();
+        assert_eq!(sst.compression_type(), compression_type);
+        assert!(sst.file_size() < plain.file_size());
+
+        let value = sst.get(&"user:01234".to_string()).unwrap().unwrap();
+        assert!(value.value.unwrap().contains("user1234@example.com"));
+        assert_eq!(sst.iter().unwrap().count(), 2000);
+
+        // Reopening reads the compression back from the table itself.
+        let reopened: SSTable<String, String> = SSTable::open(&path, None).unwrap();
+        assert!(reopened.get(&"user:00007".to_string()).unwrap().is_some());
+    }
+}
+
+#[test]
+fn iterator_walks_large_blocks_in_order() {
+    let (tmp_dir, _) = setup();
+    let options = TableOptions {
+        block_size: 256 * 1024,
+        ..TableOptions::default()
+    };
+    let path = tmp_dir.path().join("large_blocks.sst");
+    let sst: SSTable<String, String> = SSTable::write_from_iter_with_options(
+        &path,
+        json_entries(3000).into_iter(),
+        SSTableId(1),
+        1,
+        None,
+        &options,
+    )
+    .unwrap();
+
+    let entries: Vec<_> = sst.iter().unwrap().map(|e| e.unwrap()).collect();
+    assert_eq!(entries.len(), 3000);
+    for (i, entry) in entries.iter().enumerate() {
+        assert_eq!(entry.key.as_str(), format!("user:{:05}", i));
+        assert_eq!(entry.value.seq, i as u64);
+    }
+}
+
+#[test]
+fn range_iterators_seek_from_both_ends() {
+    let (tmp_dir, _) = setup();
+    let options = TableOptions {
+        block_size: 512,
+        restart_interval: 4,
+        ..TableOptions::default()
+    };
+    // Every even key has a second, older version.
+    let mut expected: Vec<(String, u64)> = Vec::new();
+    for i in 0..500u64 {
+        let key = format!("key:{:04}", i * 2);
+        expected.push((key.clone(), 1000 + i));
+        if i % 2 == 0 {
+            expected.push((key, i));
+        }
+    }
+    let entries = expected.iter().map(|(key, seq)| {
+        Ok(Entry {
+            key: Arc::new(key.clone()),
+            value: ValueEntry {
+                value: Some(Arc::new(format!("value-{}", seq))),
+                is_tombstone: false,
+                seq: *seq,
+            },
+        })
+    });
+    let path = tmp_dir.path().join("ranges.sst");
+    let sst: SSTable<String, String> =
+        SSTable::write_from_iter_with_options(&path, entries, SSTableId(1), 1, None, &options)
+            .unwrap();
+
+    fn collect(
+        iter: impl Iterator<Item = gpdb::Result<Entry<String, String>>>,
+    ) -> Vec<(String, u64)> {
+        iter.map(|e| {
+            let e = e.unwrap();
+            (e.key.as_ref().clone(), e.value.seq)
+        })
+        .collect()
+    }
+    let within = |lo: Bound<&str>, hi: Bound<&str>| -> Vec<(String, u64)> {
+        expected
+            .iter()
+            .filter(|(key, _)| (lo, hi).contains(key.as_str()))
+            .cloned()
+            .collect()
+    };
+
+    // Odd keys fall between stored keys; "key:0400" has two versions.
+    for lo in ["key:0000", "key:0399", "key:0400", "key:0998", "key:1000"] {
+        let from = collect(sst.iter_from(&lo.to_string()).unwrap());
+        assert_eq!(
+            from,
+            within(Bound::Included(lo), Bound::Unbounded),
+            "from {}",
+            lo
+        );
+
+        for hi in ["key:0400", "key:0555", "key:0999"] {
+            let inclusive = (
+                Bound::Included(lo.to_string()),
+                Bound::Included(hi.to_string()),
+            );
+            let mut forward = within(Bound::Included(lo), Bound::Included(hi));
+            assert_eq!(collect(sst.range(inclusive.clone()).unwrap()), forward);
+            forward.reverse();
+            assert_eq!(collect(sst.range(inclusive).unwrap().rev()), forward);
+
+            let exclusive = (
+                Bound::Excluded(lo.to_string()),
+                Bound::Excluded(hi.to_string()),
+            );
+            let mut forward = within(Bound::Excluded(lo), Bound::Excluded(hi));
+            assert_eq!(collect(sst.range(exclusive.clone()).unwrap()), forward);
+            forward.reverse();
+            assert_eq!(collect(sst.range(exclusive).unwrap().rev()), forward);
+        }
+    }
+
+    // Both ends meet in the middle without repeating or dropping an entry.
+    let mut iter = sst.iter().unwrap();
+    let mut front = Vec::new();
+    let mut back = Vec::new();
+    while let Some(e) = iter.next() {
+        front.push(e.unwrap().value.seq);
+        match iter.next_back() {
+            Some(e) => back.push(e.unwrap().value.seq),
+            None => break,
+        }
+    }
+    back.reverse();
+    front.extend(back);
+    let all: Vec<u64> = expected.iter().map(|(_, seq)| *seq).collect();
+    assert_eq!(front, all);
+}
+
+#[test]
+fn versions_of_one_key_span_blocks() {
+    let (tmp_dir, _) = setup();
+    let options = TableOptions {
+        block_size: 512,
+        restart_interval: 4,
+        ..TableOptions::default()
+    };
+    let entry = |key: &str, seq: u64| {
+        Ok(Entry {
+            key: Arc::new(key.to_string()),
+            value: ValueEntry {
+                value: Some(Arc::new(format!("value-{:04}", seq))),
+                is_tombstone: false,
+                seq,
+            },
+        })
+    };
+    let entries = std::iter::once(entry("a", 500))
+        .chain((1..=300).rev().map(|seq| entry("hot", seq)))
+        .chain(std::iter::once(entry("z", 400)));
+    let path = tmp_dir.path().join("versions.sst");
+    let cache = Arc::new(BlockCache::new(1024 * 1024));
+    let sst: SSTable<String, String> = SSTable::write_from_iter_with_options(
+        &path,
+        entries,
+        SSTableId(1),
+        1,
+        Some(Arc::clone(&cache)),
+        &options,
+    )
+    .unwrap();
+
+    // Blocks stay near their target size instead of growing to fit the key, so
+    // the oldest version is many blocks past the newest.
+    let hot = "hot".to_string();
+    assert_eq!(sst.get_at(&hot, 1).unwrap().unwrap().seq, 1);
+    assert!(cache.stats().misses >= 10);
+
+    for seq in [300, 299, 150, 2, 1] {
+        let value = sst.get_at(&hot, seq).unwrap().unwrap();
+        assert_eq!(value.seq, seq);
+    }
+    assert!(sst.get_at(&hot, 0).unwrap().is_none());
+    assert_eq!(sst.get(&"a".to_string()).unwrap().unwrap().seq, 500);
+    assert_eq!(sst.get(&"z".to_string()).unwrap().unwrap().seq, 400);
+
+    let seqs: Vec<u64> = sst
+        .range(hot.clone()..=hot.clone())
+        .unwrap()
+        .map(|e| e.unwrap().value.seq)
+        .collect();
+    assert_eq!(seqs, (1..=300).rev().collect::<Vec<_>>());
+    let seqs: Vec<u64> = sst
+        .range((Bound::Excluded("a".to_string()), Bound::Unbounded))
+        .unwrap()
+        .map(|e| e.unwrap().value.seq)
+        .collect();
+    assert_eq!(seqs.len(), 301);
+}
+
+#[test]
+fn zstd_dictionary_is_stored_in_table() {
+    let (tmp_dir, _) = setup();
+    let options = TableOptions {
+        block_size: 512,
+        compression_per_level: vec![Compression::zstd()],
+        zstd_dictionary_size: 4096,
+        ..TableOptions::default()
+    };
+    let path = tmp_dir.path().join("dict.sst");
+    let sst: SSTable<String, String> = SSTable::write_from_iter_with_options(
+        &path,
+        json_entries(5000).into_iter(),
+        SSTableId(1),
+        0,
+        None,
+        &options,
+    )
+    .unwrap();
+    assert!(sst.has_dictionary());
+
+    let reopened: SSTable<String, String> = SSTable::open(&path, None).unwrap();
+    assert!(reopened.has_dictionary());
+    for i in [0, 2500, 4999] {
+        let key = format!("user:{:05}", i);
+        assert!(reopened.get(&key).unwrap().is_some(), "{} is missing", key);
+    }
+    let keys: Vec<String> = reopened
+        .iter()
+        .unwrap()
+        .map(|e| e.unwrap().key.as_ref().clone())
+        .collect();
+    assert_eq!(keys.len(), 5000);
+    assert!(keys.windows(2).all(|w| w[0] < w[1]));
+}
+
+#[test]
+fn legacy_tables_keep_their_key_hash() {
+    use gpdb::io::write_record;
+    use std::collections::BTreeMap;
+    use std::hash::{DefaultHasher, Hash, Hasher};
+    use std::io::Write;
+
+    // The meta block of format version 2, before the key hash was recorded.
+    #[derive(serde::Serialize)]
+    struct LegacyMeta {
+        min_key: String,
+        max_key: String,
+        num_entries: u64,
+        filter_type: u8,
+        compression_type: u8,
+        max_seq: u64,
+    }
+
+    let (_tmp_dir, path) = setup();
+    let keys: Vec<String> = (0..50).map(|i| format!("key-{:02}", i)).collect();
+    let mut builder: DeltaBlockBuilder<String, String> = DeltaBlockBuilder::new(4096);
+    let mut hashes = Vec::new();
+    for key in &keys {
+        let value = ValueEntry {
+            value: Some(Arc::new(format!("v-{}", key))),
+            is_tombstone: false,
+            seq: 1,
+        };
+        builder.add(key, &value);
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        hashes.push(hasher.finish());
+    }
+
+    let mut file = Vec::new();
+    let filter_offset = write_record(&mut file, &builder.finish()).unwrap();
+    let index_offset = filter_offset + write_record(&mut file, &xorf::Xor8::from(&hashes)).unwrap();
+    let index: BTreeMap<String, u64> = [(keys[0].clone(), 0)].into();
+    let meta_offset = index_offset + write_record(&mut file, &index).unwrap();
+    let meta = LegacyMeta {
+        min_key: keys[0].clone(),
+        max_key: keys[49].clone(),
+        num_entries: 50,
+        filter_type: FILTER_TYPE_XOR8,
+        compression_type: COMPRESSION_NONE,
+        max_seq: 1,
+    };
+    write_record(&mut file, &meta).unwrap();
+    for field in [filter_offset, index_offset, meta_offset, 7, MAGIC_NUMBER] {
+        file.write_all(&field.to_le_bytes()).unwrap();
+    }
+    file.write_all(&2u32.to_le_bytes()).unwrap();
+    file.write_all(&[0u8; 20]).unwrap();
+    std::fs::write(&path, file).unwrap();
+
+    let sst: SSTable<String, String> = SSTable::open(&path, None).unwrap();
+    for key in &keys {
+        let value = sst.get(key).unwrap().unwrap();
+        assert_eq!(value.value.unwrap().as_str(), format!("v-{}", key));
+    }
+    assert!(sst.get(&"key-50".to_string()).unwrap().is_none());
+
+    // Tables written now hash keys with xxh3 over their bincode encoding.
+    let rewritten = path.with_file_name("rewritten.sst");
+    let sst =
+        SSTable::write_from_iter(&rewritten, sst.iter().unwrap(), SSTableId(8), 1, None).unwrap();
+    let hash = key_hash(&keys[3], KEY_HASH_XXH3).unwrap();
+    assert!(sst.filter().contains(&hash));
+    assert!(sst.get(&keys[3]).unwrap().is_some());
+}
+
+#[test]
+fn version_1_tables_keep_their_key_hash() {
+    use std::hash::{DefaultHasher, Hash, Hasher};
+
+    // Written by the first release, before sequence numbers and key hashes
+    // were recorded; it holds "key000" to "key039" at "v1-<n>".
+    let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/v1_db/L1-00000000000000000004.sst");
+    let sst: SSTable<String, String> = SSTable::open(&path, None).unwrap();
+    assert_eq!(sst.max_seq(), 0);
+    assert_eq!(sst.num_entries(), 40);
+
+    let key = "key017".to_string();
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    assert!(sst.filter().contains(&hasher.finish()));
+
+    let value = sst.get(&key).unwrap().unwrap();
+    assert_eq!(value.value.unwrap().as_str(), "v1-17");
+    assert_eq!(value.seq, 0);
+    assert!(sst.get(&"key040".to_string()).unwrap().is_none());
+
+    let entries: Vec<Entry<String, String>> = sst.iter().unwrap().map(Result::unwrap).collect();
+    assert_eq!(entries.len(), 40);
+    assert!(entries.iter().all(|e| e.value.seq == 0));
+    assert_eq!(entries[39].value.value.as_deref().unwrap(), "v1-39");
+}
+
+#[test]
+fn concurrent_point_lookups_on_one_table() {
+    let (_tmp_dir, path) = setup();
+    let sst: SSTable<String, String> =
+        SSTable::write_from_iter(&path, json_entries(3000).into_iter(), SSTableId(1), 0, None)
+            .unwrap();
+
+    std::thread::scope(|scope| {
+        for t in 0..8 {
+            let sst = sst.clone();
+            scope.spawn(move || {
+                for i in (t..3000).step_by(8) {
+                    let value = sst.get(&format!("user:{:05}", i)).unwrap().unwrap();
+                    let expected = format!("user{}@example.com", i);
+                    assert!(value.value.unwrap().contains(&expected));
+                }
+            });
+        }
+    });
+}
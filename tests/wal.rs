@@ -1,6 +1,7 @@
 use gpdb::{LogEntry, Wal, WalManager};
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use tempfile::TempDir;
 
 fn setup() -> (TempDir, PathBuf) {
@@ -14,7 +15,7 @@ fn wal_manager_rotation_and_delete() {
     let (_tmp_dir, path) = setup();
     let wm: WalManager<String, String> = WalManager::new(path.clone(), 0).unwrap();
 
-    let entry = LogEntry::Put(Arc::new("k1".to_string()), Arc::new("v1".to_string()));
+    let entry = LogEntry::Put(Arc::new("k1".to_string()), Arc::new("v1".to_string()), 1);
     wm.submit(Arc::new(vec![entry])).unwrap();
 
     assert!(path.join("000000.wal").exists());
@@ -27,6 +28,20 @@ fn wal_manager_rotation_and_delete() {
     assert!(!path.join("000000.wal").exists());
 }
 
+#[test]
+fn wal_manager_reports_the_batch_payload_size() {
+    let (_tmp_dir, path) = setup();
+    let wm: WalManager<String, String> = WalManager::new(path, 0).unwrap();
+
+    let batch = vec![
+        LogEntry::Put(Arc::new("k1".to_string()), Arc::new("v1".to_string()), 1),
+        LogEntry::Delete(Arc::new("k2".to_string()), 2),
+    ];
+    // The same size `disable_wal` writes and recovery charge the MemTable.
+    let expected = bincode::serialized_size(&batch).unwrap();
+    assert_eq!(wm.submit(Arc::new(batch)).unwrap(), expected);
+}
+
 #[test]
 fn wal_recovery_multiple_files() {
     let (_tmp_dir, path) = setup();
@@ -37,6 +52,7 @@ fn wal_recovery_multiple_files() {
         wal0.append_batch(&[LogEntry::Put(
             Arc::new("k1".to_string()),
             Arc::new("v1".to_string()),
+            1,
         )])
         .unwrap();
         wal0.flush().unwrap();
@@ -46,6 +62,7 @@ fn wal_recovery_multiple_files() {
         wal1.append_batch(&[LogEntry::Put(
             Arc::new("k2".to_string()),
             Arc::new("v2".to_string()),
+            2,
         )])
         .unwrap();
         wal1.flush().unwrap();
@@ -58,3 +75,51 @@ fn wal_recovery_multiple_files() {
     assert_eq!(next_id, 1);
     assert!(path.join("000002.wal").exists());
 }
+
+#[test]
+fn wal_manager_unsynced_writes_reach_the_file() {
+    let (_tmp_dir, path) = setup();
+    let wm: WalManager<String, String> =
+        WalManager::with_sync_interval(path.clone(), 0, Some(Duration::from_millis(10))).unwrap();
+
+    for seq in 1..=3 {
+        let entry = LogEntry::Put(
+            Arc::new(format!("k{}", seq)),
+            Arc::new("v".to_string()),
+            seq,
+        );
+        wm.submit_with_sync(Arc::new(vec![entry]), false).unwrap();
+    }
+
+    // Handed to the OS already, so readable before any sync.
+    let wal: Wal<String, String> = Wal::open(&path.join("000000.wal")).unwrap();
+    assert_eq!(wal.iter().unwrap().count(), 3);
+
+    std::thread::sleep(Duration::from_millis(30));
+    wm.sync().unwrap();
+    wm.close().unwrap();
+}
+
+#[test]
+fn wal_manager_periodic_sync_keeps_up_with_steady_writes() {
+    let (_tmp_dir, path) = setup();
+    let wm: WalManager<String, String> =
+        WalManager::with_sync_interval(path, 0, Some(Duration::from_millis(20))).unwrap();
+
+    // Writes arrive faster than the interval, so the worker is never idle long
+    // enough for its wait to time out.
+    let start = std::time::Instant::now();
+    let mut seq = 0;
+    while start.elapsed() < Duration::from_millis(200) {
+        seq += 1;
+        let entry = LogEntry::Put(
+            Arc::new(format!("k{}", seq)),
+            Arc::new("v".to_string()),
+            seq,
+        );
+        wm.submit_with_sync(Arc::new(vec![entry]), false).unwrap();
+        std::thread::sleep(Duration::from_millis(2));
+    }
+    assert!(wm.sync_count() >= 2);
+    wm.close().unwrap();
+}